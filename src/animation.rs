@@ -0,0 +1,122 @@
+//! Keyframed animation
+//!
+//! Things that move while the shutter is open are described by a
+//! list of keyframes. The keyframes are interpolated at the time
+//! stored in each ray, which is what produces motion blur.
+
+use types::*;
+
+use types::na::{Rot3, Rotate};
+
+/// Something that can be linearly interpolated between two values.
+pub trait Interpolate {
+    /// Interpolate between `self` (at `f` = 0) and `other` (at `f`
+    /// = 1).
+    fn interpolate(&self, other: &Self, f: f64) -> Self;
+}
+
+/// A value at a specific point in time.
+pub struct Keyframe<T> {
+    /// The time of the keyframe.
+    pub time: f64,
+    /// The value at that time.
+    pub value: T,
+}
+
+/// Get the value of a list of keyframes at the given time. The
+/// keyframes should be sorted by time. Before the first keyframe
+/// and after the last keyframe, the value is held constant.
+///
+/// Panics if there are no keyframes.
+pub fn sample_keyframes<T: Interpolate + Clone>(keyframes: &[Keyframe<T>], time: f64) -> T {
+    let next = keyframes.iter().position(|k| k.time > time).unwrap_or(keyframes.len());
+    if next == 0 {
+        return keyframes[0].value.clone();
+    }
+    let prev = &keyframes[next - 1];
+    if next == keyframes.len() {
+        return prev.value.clone();
+    }
+    let next = &keyframes[next];
+    let f = (time - prev.time) / (next.time - prev.time);
+    prev.value.interpolate(&next.value, f)
+}
+
+impl Interpolate for f64 {
+    fn interpolate(&self, other: &f64, f: f64) -> f64 {
+        *self + (*other - *self) * f
+    }
+}
+
+impl Interpolate for Vec3 {
+    fn interpolate(&self, other: &Vec3, f: f64) -> Vec3 {
+        *self + (*other - *self) * f
+    }
+}
+
+impl Interpolate for Pnt3 {
+    fn interpolate(&self, other: &Pnt3, f: f64) -> Pnt3 {
+        *self + (*other - *self) * f
+    }
+}
+
+/// A transformation consisting of a uniform scale, followed by a
+/// rotation, followed by a translation.
+#[derive(Clone)]
+pub struct Transform {
+    /// The translation.
+    pub translation: Vec3,
+    /// The rotation as an axis multiplied by an angle in radians.
+    /// Rotations are interpolated component-wise, so keyframes
+    /// should not be more than a fraction of a turn apart.
+    pub rotation: Vec3,
+    /// The uniform scale.
+    pub scale: f64,
+}
+
+impl Interpolate for Transform {
+    fn interpolate(&self, other: &Transform, f: f64) -> Transform {
+        Transform {
+            translation: self.translation.interpolate(&other.translation, f),
+            rotation: self.rotation.interpolate(&other.rotation, f),
+            scale: self.scale.interpolate(&other.scale, f),
+        }
+    }
+}
+
+impl Transform {
+    /// The identity transformation.
+    pub fn identity() -> Transform {
+        Transform { translation: Vec3::new(0.0, 0.0, 0.0), rotation: Vec3::new(0.0, 0.0, 0.0), scale: 1.0 }
+    }
+
+    /// Transform a point from object space into world space.
+    pub fn apply_pnt(&self, p: &Pnt3) -> Pnt3 {
+        let v = Vec3::new(p.x, p.y, p.z) * self.scale;
+        let v = Rot3::new(self.rotation).rotate(&v) + self.translation;
+        Pnt3::new(v.x, v.y, v.z)
+    }
+
+    /// Transform a vector from object space into world space.
+    pub fn apply_vec(&self, v: &Vec3) -> Vec3 {
+        Rot3::new(self.rotation).rotate(&(*v * self.scale))
+    }
+
+    /// Transform a point from world space into object space.
+    pub fn invert_pnt(&self, p: &Pnt3) -> Pnt3 {
+        let v = Vec3::new(p.x, p.y, p.z) - self.translation;
+        let v = Rot3::new(self.rotation).inv_rotate(&v) / self.scale;
+        Pnt3::new(v.x, v.y, v.z)
+    }
+
+    /// Transform a vector from world space into object space.
+    pub fn invert_vec(&self, v: &Vec3) -> Vec3 {
+        Rot3::new(self.rotation).inv_rotate(v) / self.scale
+    }
+
+    /// Transform a normal from object space into world space. Since
+    /// the scale is uniform, this only rotates the normal.
+    pub fn apply_normal(&self, n: &Vec3) -> Vec3 {
+        Rot3::new(self.rotation).rotate(n)
+    }
+}
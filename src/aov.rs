@@ -0,0 +1,245 @@
+//! Arbitrary output variables
+//!
+//! Besides the beauty pass, the renderer can output images of what
+//! the camera rays see where they first hit a surface, for use in
+//! compositing. Each output is written as a separate HDR image.
+//!
+//! Positions, normals, albedos and lighting passes are filtered like
+//! the beauty pass. Depths and IDs can't be blended between
+//! neighboring surfaces, so they are taken from the sample closest to
+//! the center of each pixel. Pixels where nothing was hit have a
+//! depth of infinity and an ID of 0.
+
+use std::f64;
+use std::io;
+use std::io::prelude::*;
+
+use checkpoint;
+use color;
+use color::Color;
+use film::{Film, Filter};
+use scene::Passes;
+use types::*;
+
+/// An output variable.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Aov {
+    /// The distance from the camera to the surface.
+    Depth,
+    /// The position of the surface in world space.
+    Position,
+    /// The shading normal of the surface in world space.
+    Normal,
+    /// The color of the surface without lighting.
+    Albedo,
+    /// The index of the object in the scene, counting from 1.
+    ObjectId,
+    /// The material ID of the object.
+    MaterialId,
+    /// Light from light sources that is reflected diffusely.
+    DiffuseDirect,
+    /// Light from other surfaces that is reflected diffusely.
+    DiffuseIndirect,
+    /// Highlights from light sources.
+    SpecularDirect,
+    /// Reflections and refractions of other surfaces.
+    SpecularIndirect,
+    /// The number of samples taken for the pixel.
+    Samples,
+}
+
+const ALL: [Aov; 11] = [
+    Aov::Depth, Aov::Position, Aov::Normal, Aov::Albedo, Aov::ObjectId, Aov::MaterialId,
+    Aov::DiffuseDirect, Aov::DiffuseIndirect, Aov::SpecularDirect, Aov::SpecularIndirect, Aov::Samples,
+];
+
+impl Aov {
+    /// The name of the output, which is used in scene files and in
+    /// the names of the images.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Aov::Depth => "depth",
+            Aov::Position => "position",
+            Aov::Normal => "normal",
+            Aov::Albedo => "albedo",
+            Aov::ObjectId => "object_id",
+            Aov::MaterialId => "material_id",
+            Aov::DiffuseDirect => "diffuse_direct",
+            Aov::DiffuseIndirect => "diffuse_indirect",
+            Aov::SpecularDirect => "specular_direct",
+            Aov::SpecularIndirect => "specular_indirect",
+            Aov::Samples => "samples",
+        }
+    }
+
+    /// Find an output by its name.
+    pub fn from_name(name: &str) -> Option<Aov> {
+        ALL.iter().find(|a| a.name() == name).cloned()
+    }
+
+    /// Whether the output is filtered like the beauty pass, instead
+    /// of taken from the sample closest to the center of the pixel.
+    fn filtered(&self) -> bool {
+        match *self {
+            Aov::Depth | Aov::ObjectId | Aov::MaterialId | Aov::Samples => false,
+            _ => true,
+        }
+    }
+
+    /// Whether the values of the output can be negative.
+    fn signed(&self) -> bool {
+        match *self {
+            Aov::Position | Aov::Normal => true,
+            _ => false,
+        }
+    }
+
+    /// The value of the output for a sample. The sample count is set
+    /// separately.
+    fn value(&self, surface: &Option<SurfaceSample>) -> Color {
+        let s = match *surface {
+            Some(ref s) => s,
+            None => return if *self == Aov::Depth { gray(f64::INFINITY) } else { color::BLACK },
+        };
+        match *self {
+            Aov::Depth => gray(s.depth),
+            Aov::Position => Color::from_rgb(s.position.x, s.position.y, s.position.z),
+            Aov::Normal => Color::from_rgb(s.normal.x, s.normal.y, s.normal.z),
+            Aov::Albedo => s.albedo,
+            Aov::ObjectId => gray(s.object_id as f64),
+            Aov::MaterialId => gray(s.material_id as f64),
+            Aov::DiffuseDirect => s.passes.diffuse_direct,
+            Aov::DiffuseIndirect => s.passes.diffuse_indirect,
+            Aov::SpecularDirect => s.passes.specular_direct,
+            Aov::SpecularIndirect => s.passes.specular_indirect,
+            Aov::Samples => color::BLACK,
+        }
+    }
+}
+
+#[inline]
+fn gray(v: f64) -> Color {
+    Color::from_rgb(v, v, v)
+}
+
+/// What a camera ray sees where it first hits a surface.
+pub struct SurfaceSample {
+    /// The distance from the origin of the ray to the surface.
+    pub depth: f64,
+    /// The position of the surface.
+    pub position: Pnt3,
+    /// The shading normal of the surface.
+    pub normal: Vec3,
+    /// The color of the surface without lighting.
+    pub albedo: Color,
+    /// The index of the object in the scene, counting from 1.
+    pub object_id: u32,
+    /// The material ID of the object.
+    pub material_id: u32,
+    /// The light reflected by the surface, split into passes.
+    pub passes: Passes,
+}
+
+/// Films for a set of output variables.
+pub struct AovFilm {
+    layers: Vec<(Aov, Film)>,
+    width: u32,
+    // the squared distance to the pixel center of the sample that set
+    // the unfiltered outputs of each pixel
+    nearest: Vec<f64>,
+}
+
+impl AovFilm {
+    /// Create empty films for the outputs.
+    pub fn new(aovs: &[Aov], width: u32, height: u32) -> AovFilm {
+        AovFilm {
+            layers: aovs.iter().map(|&a| {
+                let mut film = Film::new(width, height);
+                film.set_signed(a.signed());
+                (a, film)
+            }).collect(),
+            width: width,
+            nearest: vec![f64::INFINITY; (width * height) as usize],
+        }
+    }
+
+    /// Whether there are any outputs.
+    pub fn is_empty(&self) -> bool {
+        self.layers.is_empty()
+    }
+
+    /// Add a sample at a position in pixels, which was taken for the
+    /// pixel at `(px, py)`.
+    pub fn add_sample(&mut self, filter: &Filter, x: f64, y: f64, px: u32, py: u32, surface: &Option<SurfaceSample>) {
+        let (dx, dy) = (x - (px as f64 + 0.5), y - (py as f64 + 0.5));
+        let d2 = dx * dx + dy * dy;
+        let i = (px + self.width * py) as usize;
+        let nearest = d2 < self.nearest[i];
+        if nearest {
+            self.nearest[i] = d2;
+        }
+        for &mut (aov, ref mut film) in &mut self.layers {
+            if aov.filtered() {
+                film.add_sample(filter, x, y, aov.value(surface));
+            } else if nearest && aov != Aov::Samples {
+                film.set(px, py, aov.value(surface));
+            }
+        }
+    }
+
+    /// Set the number of samples taken for a pixel.
+    pub fn set_samples(&mut self, px: u32, py: u32, count: u32) {
+        for &mut (aov, ref mut film) in &mut self.layers {
+            if aov == Aov::Samples {
+                film.set(px, py, gray(count as f64));
+            }
+        }
+    }
+
+    /// The film of an output, if it was rendered.
+    pub fn layer(&self, aov: Aov) -> Option<&Film> {
+        self.layers.iter().find(|&&(a, _)| a == aov).map(|&(_, ref film)| film)
+    }
+
+    /// Save the films to a checkpoint.
+    pub fn write_state<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        try!(checkpoint::write_u32(w, self.layers.len() as u32));
+        for &(aov, ref film) in &self.layers {
+            let name = aov.name().as_bytes();
+            try!(checkpoint::write_u32(w, name.len() as u32));
+            try!(w.write_all(name));
+            try!(film.write_state(w));
+        }
+        for &d in &self.nearest {
+            try!(checkpoint::write_f64(w, d));
+        }
+        Ok(())
+    }
+
+    /// Load films for the outputs from a checkpoint, which must have
+    /// the same outputs in the same order.
+    pub fn read_state<R: Read>(r: &mut R, aovs: &[Aov], width: u32, height: u32) -> io::Result<AovFilm> {
+        let mut res = AovFilm::new(&[], width, height);
+        if try!(checkpoint::read_u32(r)) as usize != aovs.len() {
+            return checkpoint::invalid("the checkpoint has different output variables");
+        }
+        for &aov in aovs {
+            let expected = aov.name().as_bytes();
+            if try!(checkpoint::read_u32(r)) as usize != expected.len() {
+                return checkpoint::invalid("the checkpoint has different output variables");
+            }
+            let mut name = vec![0; expected.len()];
+            try!(r.read_exact(&mut name));
+            if &name[..] != expected {
+                return checkpoint::invalid("the checkpoint has different output variables");
+            }
+            let mut film = try!(Film::read_state(r, width, height));
+            film.set_signed(aov.signed());
+            res.layers.push((aov, film));
+        }
+        for d in &mut res.nearest {
+            *d = try!(checkpoint::read_f64(r));
+        }
+        Ok(res)
+    }
+}
@@ -1,11 +1,15 @@
 //! Quick BMP file module
 //!
-//! This module contains functions to write BMP files.
+//! This module contains functions to write BMP files, and to read
+//! the ones it writes.
 
 use std::io;
 use std::io::prelude::*;
 use std::fs::File;
 
+use color::Color;
+use film::Film;
+
 /// Write BMP file header, returning the length of a row in bytes
 pub fn write_header(f: &mut File, width: u32, height: u32) -> io::Result<u32> {
     let bytewidth = (3 * width + 3) & 0xFFFFFFFC;
@@ -59,3 +63,32 @@ pub fn write_header(f: &mut File, width: u32, height: u32) -> io::Result<u32> {
     ]));
     Ok(bytewidth)
 }
+
+/// Read a BMP file with 24 bits per pixel and rows from bottom to
+/// top, like the ones this module writes.
+pub fn read(f: &mut File) -> io::Result<Film> {
+    let mut data = Vec::new();
+    try!(f.read_to_end(&mut data));
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "unsupported BMP file");
+    if data.len() < 54 || &data[0..2] != b"BM" {
+        return Err(invalid());
+    }
+    let u32_at = |i: usize| (data[i] as u32) | (data[i + 1] as u32) << 8 | (data[i + 2] as u32) << 16 | (data[i + 3] as u32) << 24;
+    let (offset, width, height) = (u32_at(10) as usize, u32_at(18), u32_at(22));
+    // 24 bpp without compression; a negative height would be top to bottom
+    if data[28] != 0x18 || data[29] != 0 || u32_at(30) != 0 || height >= 0x80000000 {
+        return Err(invalid());
+    }
+    let bytewidth = ((3 * width + 3) & 0xFFFFFFFC) as usize;
+    if data.len() < offset + bytewidth * height as usize {
+        return Err(invalid());
+    }
+    let mut film = Film::new(width, height);
+    for y in 0..height {
+        let row = &data[offset + bytewidth * y as usize..];
+        for x in 0..width as usize {
+            film.set(x as u32, y, Color::from_srgb(row[3 * x + 2], row[3 * x + 1], row[3 * x]));
+        }
+    }
+    Ok(film)
+}
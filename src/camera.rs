@@ -7,8 +7,36 @@ use std::f64;
 
 use types::*;
 use shapes::*;
+use scene::{Object, intersect_objects};
+use animation::{Interpolate, Keyframe, sample_keyframes};
 
-use types::na::{cross, Norm};
+use types::na::{cross, dot, Norm};
+
+/// The interval of time during which the shutter is open. Each ray
+/// is cast at a random time within the interval.
+pub struct Shutter {
+    /// The time at which the shutter opens.
+    pub open: f64,
+    /// The time at which the shutter closes.
+    pub close: f64,
+}
+
+impl Shutter {
+    /// A shutter that is open for an instant at time 0, which
+    /// disables motion blur.
+    pub fn instant() -> Shutter {
+        Shutter { open: 0.0, close: 0.0 }
+    }
+
+    /// Pick a random time while the shutter is open.
+    pub fn sample(&self, rng: &mut RngT) -> f64 {
+        if self.close > self.open {
+            self.open + (self.close - self.open) * rng.gen::<f64>()
+        } else {
+            self.open
+        }
+    }
+}
 
 /// Trait for cameras.
 ///
@@ -19,21 +47,60 @@ use types::na::{cross, Norm};
 pub trait Camera {
     /// Projects a point onto the scene, and returns the ray.
     ///
-    /// `position` is normalized image coordinates, where (-1, -1)
-    /// to (1, 1) is the largest centered square in the image.
-    fn project(&self, position: &Pnt2, rng: &mut RngT) -> Ray;
+    /// `position` is normalized image coordinates, where the film
+    /// gate of the camera is centered on (0, 0). By default, the
+    /// gate is the square from (-1, -1) to (1, 1), and it is the
+    /// largest centered square in the image.
+    /// The time of the ray is sampled from `shutter`.
+    /// Returns `None` if the position does not map to any direction
+    /// (e.g. outside of the image circle of a fisheye lens).
+    fn project(&self, position: &Pnt2, shutter: &Shutter, rng: &mut RngT) -> Option<Ray>;
+    /// Projects a point onto the scene like `project`, and also
+    /// computes the differentials of the ray. `pixel` is the size of
+    /// a pixel in normalized image coordinates. The offset rays are
+    /// projected with the same random numbers as the main ray, so
+    /// they share the same lens position and time.
+    fn project_differential(&self, position: &Pnt2, pixel: &Vec2, shutter: &Shutter, rng: &mut RngT) -> Option<Ray> {
+        let saved = rng.clone();
+        let mut ray = match self.project(position, shutter, rng) {
+            Some(r) => r,
+            None => return None,
+        };
+        let rx = self.project(&Pnt2::new(position.x + pixel.x, position.y), shutter, &mut saved.clone());
+        let ry = self.project(&Pnt2::new(position.x, position.y + pixel.y), shutter, &mut saved.clone());
+        if let (Some(rx), Some(ry)) = (rx, ry) {
+            ray.differential = Some(RayDifferential {
+                rx_origin: rx.origin,
+                rx_direction: rx.direction,
+                ry_origin: ry.origin,
+                ry_direction: ry.direction,
+            });
+        }
+        Some(ray)
+    }
     /// Get the number of samples per pixel.
     fn samples(&self) -> u32 {1}
+    /// Get half of the size of the film gate in normalized image
+    /// coordinates, which is the part of the image plane that the
+    /// image is meant to show. How it is fitted to the image is set
+    /// by the options.
+    fn gate(&self) -> Vec2 {Vec2::new(1.0, 1.0)}
+    /// Compute anything that depends on the objects in the scene,
+    /// such as the focus distance. Called once the scene is loaded.
+    fn autofocus(&mut self, _objects: &[Object]) -> Result<(), String> {Ok(())}
 }
 
 /// A very simple perspective camera that projects the given position
 /// from its location onto an image plane.
+#[derive(Clone)]
 pub struct SimplePerspectiveCamera {
     /// The position of the camera.
     pub position: Pnt3,
     /// A matrix that transforms a homogeneous vector (x, y, 1)
     /// into the direction in world-space.
     pub matrix: Mat3,
+    /// Half of the size of the film gate.
+    pub gate: Vec2,
 }
 
 impl SimplePerspectiveCamera {
@@ -49,18 +116,47 @@ impl SimplePerspectiveCamera {
     /// For best results, `look` and `up` should be normalized, but
     /// this is not necessary since the resulting rays are normalized.
     pub fn new(position: &Pnt3, look: &Vec3, up: &Vec3, im_dist: f64) -> SimplePerspectiveCamera {
+        SimplePerspectiveCamera::from_frame(position, look, up, &Vec3::new(0.0, 0.0, im_dist), &Vec2::new(1.0, 1.0))
+    }
+    /// Create a camera from its look and up vectors, and the center
+    /// of the image plane in the axes of the camera (right, up and
+    /// look).
+    fn from_frame(position: &Pnt3, look: &Vec3, up: &Vec3, center: &Vec3, gate: &Vec2) -> SimplePerspectiveCamera {
         let u = cross(look, up).normalize();
         let v = cross(&u, look).normalize();
-        let w = look.normalize() * im_dist;
+        let w = u * center.x + v * center.y + look.normalize() * center.z;
         SimplePerspectiveCamera {
             position: position.clone(),
             matrix: Mat3::new(
                 u.x, v.x, w.x,
                 u.y, v.y, w.y,
                 u.z, v.z, w.z,
-            )
+            ),
+            gate: *gate,
         }
     }
+    /// Create a new `SimplePerspectiveCamera` like a physical camera,
+    /// from its position, look vector, up vector, the focal length
+    /// of the lens and the width and height of the sensor, both in
+    /// millimetres. The film gate is the sensor, and the image plane
+    /// is at a distance of 1, so the field of view is the same as for
+    /// the real camera.
+    ///
+    /// `shift` moves the lens parallel to the sensor, as a fraction
+    /// of the width and height of the sensor, which moves the image
+    /// without changing the perspective (e.g. to keep vertical lines
+    /// parallel in architectural renders).
+    pub fn physical(position: &Pnt3, look: &Vec3, up: &Vec3, focal_length: f64, sensor: &Vec2, shift: &Vec2) -> SimplePerspectiveCamera {
+        let center = Vec3::new(shift.x * sensor.x / focal_length, shift.y * sensor.y / focal_length, 1.0);
+        SimplePerspectiveCamera::from_frame(position, look, up, &center, &(*sensor / (2.0 * focal_length)))
+    }
+    /// The viewing direction, which is perpendicular to the image
+    /// plane even if the lens is shifted.
+    fn view_direction(&self) -> Vec3 {
+        let u = self.matrix * Vec3::new(1.0, 0.0, 0.0);
+        let v = self.matrix * Vec3::new(0.0, 1.0, 0.0);
+        cross(&v, &u).normalize()
+    }
     /// Create a new `SimplePerspectiveCamera` from the given focus
     /// point, viewing direction, and up vector, POV angle, and half
     /// the focus height.
@@ -74,50 +170,342 @@ impl SimplePerspectiveCamera {
 }
 
 impl Camera for SimplePerspectiveCamera {
-    fn project(&self, position: &Pnt2, _: &mut RngT) -> Ray {
-        Ray { origin: self.position, direction: (self.matrix * Vec3::new(position.x, position.y, 1.0)).normalize() }
+    fn project(&self, position: &Pnt2, shutter: &Shutter, rng: &mut RngT) -> Option<Ray> {
+        Some(Ray { origin: self.position, direction: (self.matrix * Vec3::new(position.x, position.y, 1.0)).normalize(), time: shutter.sample(rng), differential: None })
     }
+    fn gate(&self) -> Vec2 {self.gate}
+}
+
+impl SimplePerspectiveCamera {
+    /// Split the matrix into the look and up vectors, and the center
+    /// of the image plane in the axes of the camera.
+    fn frame(&self) -> (Vec3, Vec3, Vec3) {
+        let u = self.matrix * Vec3::new(1.0, 0.0, 0.0);
+        let v = self.matrix * Vec3::new(0.0, 1.0, 0.0);
+        let w = self.matrix * Vec3::new(0.0, 0.0, 1.0);
+        let look = self.view_direction();
+        (look, v, Vec3::new(dot(&w, &u), dot(&w, &v), dot(&w, &look)))
+    }
+}
+
+/// The orientation is interpolated through the look and up vectors,
+/// so that the axes stay perpendicular and of unit length. Like
+/// rotations of shapes, keyframes should not be more than a fraction
+/// of a turn apart.
+impl Interpolate for SimplePerspectiveCamera {
+    fn interpolate(&self, other: &SimplePerspectiveCamera, f: f64) -> SimplePerspectiveCamera {
+        let (look0, up0, center0) = self.frame();
+        let (look1, up1, center1) = other.frame();
+        SimplePerspectiveCamera::from_frame(
+            &self.position.interpolate(&other.position, f),
+            &look0.interpolate(&look1, f),
+            &up0.interpolate(&up1, f),
+            &center0.interpolate(&center1, f),
+            &(self.gate + (other.gate - self.gate) * f),
+        )
+    }
+}
+
+/// A perspective camera that moves while the shutter is open. The
+/// position and orientation are interpolated between keyframes at
+/// the time of each ray.
+pub struct AnimatedCamera {
+    /// The camera over time, sorted by time.
+    pub keyframes: Vec<Keyframe<SimplePerspectiveCamera>>,
+}
+
+impl Camera for AnimatedCamera {
+    fn project(&self, position: &Pnt2, shutter: &Shutter, rng: &mut RngT) -> Option<Ray> {
+        let time = shutter.sample(rng);
+        let camera = sample_keyframes(&self.keyframes, time);
+        Some(Ray { origin: camera.position, direction: (camera.matrix * Vec3::new(position.x, position.y, 1.0)).normalize(), time: time, differential: None })
+    }
+    fn gate(&self) -> Vec2 {self.keyframes[0].value.gate}
+}
+
+/// The shape of the aperture of a lens, which determines the shape
+/// of out-of-focus highlights (bokeh).
+pub struct Aperture {
+    /// The radius of the aperture. For polygonal apertures, this is
+    /// the distance from the center to a corner.
+    pub radius: f64,
+    /// The number of blades. If less than 3, the aperture is a circle.
+    pub blades: u32,
+    /// The rotation of the blades in radians.
+    pub rotation: f64,
+    /// Amount of cat's-eye vignetting. The aperture is clipped by a
+    /// circle of the same radius that is shifted towards the image
+    /// center by this fraction of the radius per unit of image
+    /// position. 0 disables the effect.
+    pub cats_eye: f64,
+}
+
+impl Aperture {
+    /// A circular aperture with the given radius.
+    pub fn circle(radius: f64) -> Aperture {
+        Aperture { radius: radius, blades: 0, rotation: 0.0, cats_eye: 0.0 }
+    }
+
+    /// The aperture of a lens with the given focal length and f-number.
+    /// The focal length is in scene units.
+    pub fn from_f_stop(focal_length: f64, f_stop: f64) -> Aperture {
+        Aperture::circle(focal_length / f_stop / 2.0)
+    }
+
+    /// Sample a point on the aperture, relative to its center, as
+    /// seen from the given image position. Returns `None` if the
+    /// sample was blocked by cat's-eye vignetting.
+    pub fn sample(&self, position: &Pnt2, rng: &mut RngT) -> Option<(f64, f64)> {
+        let (x, y) = if self.blades < 3 {
+            // generate a random angle
+            let theta = rng.gen::<f64>() * (2.0 * f64::consts::PI);
+            // taking the square root of the radius yields a uniform distribution
+            let rand::Closed01(r2) = rng.gen::<rand::Closed01<f64>>();
+            let r = r2.sqrt();
+            (theta.cos() * r, theta.sin() * r)
+        } else {
+            // pick a triangle between the center and one of the edges
+            let blade_angle = 2.0 * f64::consts::PI / self.blades as f64;
+            let blade = ((rng.gen::<f64>() * self.blades as f64) as u32).min(self.blades - 1);
+            let a0 = self.rotation + blade as f64 * blade_angle;
+            let a1 = a0 + blade_angle;
+            // uniform point in the triangle
+            let (mut u, mut v) = (rng.gen::<f64>(), rng.gen::<f64>());
+            if u + v > 1.0 {
+                u = 1.0 - u;
+                v = 1.0 - v;
+            }
+            (a0.cos() * u + a1.cos() * v, a0.sin() * u + a1.sin() * v)
+        };
+        if self.cats_eye > 0.0 {
+            let cx = x + position.x * self.cats_eye;
+            let cy = y + position.y * self.cats_eye;
+            if cx * cx + cy * cy > 1.0 { return None }
+        }
+        Some((x * self.radius, y * self.radius))
+    }
+}
+
+/// What a `DepthOfFieldCamera` focuses on.
+pub enum Focus {
+    /// A distance from the camera along the viewing direction.
+    Distance(f64),
+    /// A point in the scene.
+    Point(Pnt3),
+    /// The object with the given name. The focus distance is found
+    /// by casting rays from the camera when the scene is loaded.
+    Object(String),
 }
 
 /// A perspective camera that has a depth of field effect.
 pub struct DepthOfFieldCamera {
     /// The base camera.
     pub camera: SimplePerspectiveCamera,
-    /// The distance to the focal plane (from the camera).
+    /// The distance to the focal plane (from the camera). This is
+    /// computed from `autofocus` when the scene is loaded.
     pub focus: f64,
-    /// The aperture radius. The aperture is a circle.
-    pub aperture: f64,
+    /// What to focus on, if the focus distance should be computed.
+    pub autofocus: Option<Focus>,
+    /// The aperture of the lens.
+    pub aperture: Aperture,
     /// The number of samples.
     pub samples: u32,
     im_dist: f64,
 }
 
 impl DepthOfFieldCamera {
-    /// Create a new `DepthOfFieldCamera`.
+    /// Create a new `DepthOfFieldCamera` with a circular aperture.
     pub fn new(camera: SimplePerspectiveCamera, focus: f64, aperture: f64, samples: u32) -> DepthOfFieldCamera {
-        let im_dist = (camera.matrix * Vec3::new(0.0, 0.0, 1.0)).norm();
+        DepthOfFieldCamera::with_aperture(camera, Focus::Distance(focus), Aperture::circle(aperture), samples)
+    }
+
+    /// Create a new `DepthOfFieldCamera` with the given focus and
+    /// aperture. Unless the focus is a distance, `autofocus` must be
+    /// called before rendering.
+    pub fn with_aperture(camera: SimplePerspectiveCamera, focus: Focus, aperture: Aperture, samples: u32) -> DepthOfFieldCamera {
+        let im_dist = dot(&(camera.matrix * Vec3::new(0.0, 0.0, 1.0)), &camera.view_direction());
+        let (distance, autofocus) = match focus {
+            Focus::Distance(d) => (d, None),
+            f => (im_dist, Some(f)),
+        };
         DepthOfFieldCamera {
             camera: camera,
-            focus: focus,
+            focus: distance,
+            autofocus: autofocus,
             aperture: aperture,
             samples: samples,
             im_dist: im_dist,
         }
     }
+
+    /// The distance from the camera to the plane through a point
+    /// that is parallel to the image plane.
+    fn depth_of(&self, pt: &Pnt3) -> f64 {
+        dot(&(*pt - self.camera.position), &self.camera.view_direction())
+    }
 }
 
 impl Camera for DepthOfFieldCamera {
-    fn project(&self, position: &Pnt2, rng: &mut RngT) -> Ray {
+    fn project(&self, position: &Pnt2, shutter: &Shutter, rng: &mut RngT) -> Option<Ray> {
         let dir = self.camera.matrix * Vec3::new(position.x, position.y, 1.0); // not normalized
         let ip = self.camera.position + dir; // point on image plane
         let fp = self.camera.position + dir * (self.focus / self.im_dist); // focal point
-        // generate a random angle
-        let theta = rng.gen::<f64>() * (2.0 * f64::consts::PI);
-        // taking the square root of the radius yields a uniform distribution
-        let rand::Closed01(r2) = rng.gen::<rand::Closed01<f64>>();
-        let r = r2.sqrt() * self.aperture;
-        let orig = ip + self.camera.matrix * Vec3::new(theta.cos() * r, theta.sin() * r, 0.0);
-        Ray { origin: orig, direction: (fp - orig).normalize() }
+        let (x, y) = match self.aperture.sample(position, rng) {
+            Some(p) => p,
+            None => return None,
+        };
+        let orig = ip + self.camera.matrix * Vec3::new(x, y, 0.0);
+        Some(Ray { origin: orig, direction: (fp - orig).normalize(), time: shutter.sample(rng), differential: None })
     }
     fn samples(&self) -> u32 {self.samples}
+    fn gate(&self) -> Vec2 {self.camera.gate()}
+    fn autofocus(&mut self, objects: &[Object]) -> Result<(), String> {
+        let focus = match self.autofocus {
+            None => return Ok(()),
+            Some(Focus::Distance(d)) => d,
+            Some(Focus::Point(ref p)) => self.depth_of(p),
+            Some(Focus::Object(ref name)) => {
+                // search image positions from the center outwards for
+                // the first ray that hits the object
+                const STEPS: i32 = 32;
+                let mut positions = Vec::new();
+                for y in -STEPS..STEPS + 1 {
+                    for x in -STEPS..STEPS + 1 {
+                        positions.push(Pnt2::new(x as f64 / STEPS as f64, y as f64 / STEPS as f64));
+                    }
+                }
+                positions.sort_by(|a, b| (a.x * a.x + a.y * a.y).partial_cmp(&(b.x * b.x + b.y * b.y)).unwrap());
+                let mut found = None;
+                for pos in positions {
+                    let direction = (self.camera.matrix * Vec3::new(pos.x, pos.y, 1.0)).normalize();
+                    let ray = Ray { origin: self.camera.position, direction: direction, time: 0.0, differential: None };
+                    if let Some(hit) = intersect_objects(objects, &ray) {
+                        if hit.object.name.as_ref() == Some(name) {
+                            found = Some(self.depth_of(&ray.cast(hit.result.t)));
+                            break;
+                        }
+                    }
+                }
+                match found {
+                    Some(d) => d,
+                    None => return Err(format!("object \"{}\" is not visible from the camera", name)),
+                }
+            },
+        };
+        if focus <= 0.0 {
+            return Err("focus point is behind the camera".to_string());
+        }
+        self.focus = focus;
+        self.autofocus = None;
+        Ok(())
+    }
+}
+
+/// Compute an orthonormal basis from a look vector and an up vector.
+/// The columns of the result are the right, up, and look directions.
+fn orthonormal_basis(look: &Vec3, up: &Vec3) -> Mat3 {
+    let u = cross(look, up).normalize();
+    let v = cross(&u, look).normalize();
+    let w = look.normalize();
+    Mat3::new(
+        u.x, v.x, w.x,
+        u.y, v.y, w.y,
+        u.z, v.z, w.z,
+    )
+}
+
+/// A panoramic camera using the equirectangular (latitude-longitude)
+/// projection. The whole sphere is mapped onto a rectangle with an
+/// aspect ratio of 2:1, so x ranges from -2 to 2 (longitude -180°
+/// to 180°) and y ranges from -1 to 1 (latitude -90° to 90°) in
+/// normalized image coordinates. The rectangle is the film gate, so
+/// the whole sphere is fitted to the image, and positions outside of
+/// it are not projected.
+pub struct EquirectangularCamera {
+    /// The position of the camera.
+    pub position: Pnt3,
+    /// A matrix whose columns are the right, up, and forward
+    /// directions of the camera in world-space.
+    pub matrix: Mat3,
+}
+
+impl EquirectangularCamera {
+    /// Create a new `EquirectangularCamera` from the given position,
+    /// look vector, and up vector. The center of the image looks in
+    /// the direction of `look`.
+    pub fn new(position: &Pnt3, look: &Vec3, up: &Vec3) -> EquirectangularCamera {
+        EquirectangularCamera {
+            position: position.clone(),
+            matrix: orthonormal_basis(look, up),
+        }
+    }
+}
+
+impl Camera for EquirectangularCamera {
+    fn project(&self, position: &Pnt2, shutter: &Shutter, rng: &mut RngT) -> Option<Ray> {
+        if position.x.abs() > 2.0 || position.y.abs() > 1.0 { return None }
+        let phi = position.x * f64::consts::FRAC_PI_2;
+        let theta = position.y * f64::consts::FRAC_PI_2;
+        let cos_theta = theta.cos();
+        let dir = Vec3::new(phi.sin() * cos_theta, theta.sin(), phi.cos() * cos_theta);
+        Some(Ray { origin: self.position, direction: (self.matrix * dir).normalize(), time: shutter.sample(rng), differential: None })
+    }
+    fn gate(&self) -> Vec2 {Vec2::new(2.0, 1.0)}
+}
+
+/// The mapping function of a fisheye lens, which relates the angle
+/// from the optical axis to the distance from the image center.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FisheyeMapping {
+    /// The distance from the center is proportional to the angle.
+    Equidistant,
+    /// The distance from the center is proportional to the sine of
+    /// half of the angle, which preserves solid angles (areas).
+    Equisolid,
+}
+
+/// A fisheye camera. The image circle is the unit circle in normalized
+/// image coordinates; positions outside of it are not projected.
+pub struct FisheyeCamera {
+    /// The position of the camera.
+    pub position: Pnt3,
+    /// A matrix whose columns are the right, up, and forward
+    /// directions of the camera in world-space.
+    pub matrix: Mat3,
+    /// The field of view across the diameter of the image circle,
+    /// in radians. A hemisphere is a field of view of pi.
+    pub fov: f64,
+    /// The mapping function of the lens.
+    pub mapping: FisheyeMapping,
+}
+
+impl FisheyeCamera {
+    /// Create a new `FisheyeCamera` from the given position, look
+    /// vector, up vector, field of view, and mapping function.
+    pub fn new(position: &Pnt3, look: &Vec3, up: &Vec3, fov: f64, mapping: FisheyeMapping) -> FisheyeCamera {
+        FisheyeCamera {
+            position: position.clone(),
+            matrix: orthonormal_basis(look, up),
+            fov: fov,
+            mapping: mapping,
+        }
+    }
+}
+
+impl Camera for FisheyeCamera {
+    fn project(&self, position: &Pnt2, shutter: &Shutter, rng: &mut RngT) -> Option<Ray> {
+        let r = (position.x * position.x + position.y * position.y).sqrt();
+        if r > 1.0 { return None }
+        let half_fov = self.fov / 2.0;
+        let theta = match self.mapping {
+            FisheyeMapping::Equidistant => r * half_fov,
+            FisheyeMapping::Equisolid => 2.0 * (r * (half_fov / 2.0).sin()).asin(),
+        };
+        if theta > f64::consts::PI { return None }
+        let (sin_theta, cos_theta) = theta.sin_cos();
+        // direction of the position around the optical axis
+        let (cos_phi, sin_phi) = if r > 0.0 { (position.x / r, position.y / r) } else { (1.0, 0.0) };
+        let dir = Vec3::new(cos_phi * sin_theta, sin_phi * sin_theta, cos_theta);
+        Some(Ray { origin: self.position, direction: (self.matrix * dir).normalize(), time: shutter.sample(rng), differential: None })
+    }
 }
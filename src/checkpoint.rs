@@ -0,0 +1,101 @@
+//! Checkpoints of progressive renders
+//!
+//! A checkpoint saves everything that a progressive render has
+//! accumulated: the weighted sums of the samples of the beauty pass
+//! and the output variables, the sample counts and statistics of each
+//! pixel, and the state of the sampler. A later run can load it and
+//! continue to add samples to the image, as long as the scene is the
+//! same, which is checked with a hash of the scene file.
+//!
+//! Checkpoints are binary files, with numbers stored little-endian.
+
+use std::io;
+use std::io::prelude::*;
+
+use render::RenderState;
+use sampler::Sampler;
+use scene::Scene;
+
+/// The first bytes of a checkpoint, with the version of the format.
+const MAGIC: &'static [u8] = b"RTSTATE2";
+
+/// Hash the source of a scene, with the 64-bit FNV-1a hash.
+pub fn scene_hash(source: &str) -> u64 {
+    source.bytes().fold(0xcbf29ce484222325, |h, b| (h ^ b as u64).wrapping_mul(0x100000001b3))
+}
+
+/// An error for a checkpoint that can't be loaded.
+pub fn invalid<T>(message: &str) -> io::Result<T> {
+    Err(io::Error::new(io::ErrorKind::InvalidData, message))
+}
+
+/// Write a `u32` to a checkpoint.
+pub fn write_u32<W: Write>(w: &mut W, v: u32) -> io::Result<()> {
+    w.write_all(&[v as u8, (v >> 8) as u8, (v >> 16) as u8, (v >> 24) as u8])
+}
+
+/// Write a `u64` to a checkpoint.
+pub fn write_u64<W: Write>(w: &mut W, v: u64) -> io::Result<()> {
+    try!(write_u32(w, v as u32));
+    write_u32(w, (v >> 32) as u32)
+}
+
+/// Write an `f32` to a checkpoint.
+pub fn write_f32<W: Write>(w: &mut W, v: f32) -> io::Result<()> {
+    write_u32(w, v.to_bits())
+}
+
+/// Write an `f64` to a checkpoint.
+pub fn write_f64<W: Write>(w: &mut W, v: f64) -> io::Result<()> {
+    write_u64(w, v.to_bits())
+}
+
+/// Read a `u32` from a checkpoint.
+pub fn read_u32<R: Read>(r: &mut R) -> io::Result<u32> {
+    let mut b = [0; 4];
+    try!(r.read_exact(&mut b));
+    Ok((b[0] as u32) | (b[1] as u32) << 8 | (b[2] as u32) << 16 | (b[3] as u32) << 24)
+}
+
+/// Read a `u64` from a checkpoint.
+pub fn read_u64<R: Read>(r: &mut R) -> io::Result<u64> {
+    let lo = try!(read_u32(r)) as u64;
+    let hi = try!(read_u32(r)) as u64;
+    Ok(lo | hi << 32)
+}
+
+/// Read an `f32` from a checkpoint.
+pub fn read_f32<R: Read>(r: &mut R) -> io::Result<f32> {
+    read_u32(r).map(f32::from_bits)
+}
+
+/// Read an `f64` from a checkpoint.
+pub fn read_f64<R: Read>(r: &mut R) -> io::Result<f64> {
+    read_u64(r).map(f64::from_bits)
+}
+
+/// Save a checkpoint of a render. The sampler is reseeded, so that
+/// a render that is resumed from the checkpoint continues with the
+/// same random numbers as this one.
+pub fn save<W: Write>(w: &mut W, hash: u64, state: &RenderState, sampler: &mut Sampler) -> io::Result<()> {
+    try!(w.write_all(MAGIC));
+    try!(write_u64(w, hash));
+    try!(sampler.write_state(w));
+    state.write_state(w)
+}
+
+/// Load a checkpoint of a render of a scene with the given hash,
+/// returning the render and its sampler.
+pub fn load<R: Read>(r: &mut R, hash: u64, scene: &Scene) -> io::Result<(RenderState, Sampler)> {
+    let mut magic = [0; 8];
+    try!(r.read_exact(&mut magic));
+    if &magic[..] != MAGIC {
+        return invalid("not a checkpoint");
+    }
+    if try!(read_u64(r)) != hash {
+        return invalid("the checkpoint is for a different scene");
+    }
+    let sampler = try!(Sampler::read_state(r, scene.options.sampler));
+    let state = try!(RenderState::read_state(r, scene));
+    Ok((state, sampler))
+}
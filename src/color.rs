@@ -618,6 +618,11 @@ impl Color {
         [clamp_color_val(self.r), clamp_color_val(self.g), clamp_color_val(self.b)]
     }
 
+    /// Get the RGB components as 3 sRGB-encoded bytes.
+    pub fn srgb(&self) -> [u8; 3] {
+        [to_srgb(self.r), to_srgb(self.g), to_srgb(self.b)]
+    }
+
     /// Get the components of the image in BGR as 3 bytes, useful
     /// for writing an image.
     pub fn bgr(&self) -> [u8; 3] {
@@ -631,6 +636,11 @@ impl Color {
         buf[i * 3 + 2] = to_srgb(self.r);
     }
 
+    /// The luminance of the color, using the Rec. 709 primaries.
+    pub fn luminance(&self) -> f64 {
+        0.2126 * self.r + 0.7152 * self.g + 0.0722 * self.b
+    }
+
     /// Some indication of significance; if 0, unsignificant; if
     /// greater than 0, significant. Used to disable shading when
     /// unnecessary.
@@ -0,0 +1,131 @@
+//! Denoising
+//!
+//! The denoiser smooths the noise of the rendered image after it has
+//! been rendered, using a joint bilateral filter guided by the albedo
+//! and normal output variables. Each pixel becomes a weighted average
+//! of the pixels around it, where pixels are weighted down when they
+//! are far away, when their surroundings have a different color, or
+//! when they see a surface with a different albedo or normal. The
+//! feature buffers have little noise, so edges and textures are kept
+//! while the noise of the lighting is averaged away.
+//!
+//! Colors are compared in small patches, like in non-local means
+//! filtering, after compressing their range so that bright outliers
+//! don't stop them from being smoothed.
+
+use color;
+use color::Color;
+use film::Film;
+
+/// Settings for the denoiser. The standard deviation and the
+/// differences must be greater than 0; smaller values keep more detail.
+pub struct Denoiser {
+    /// The radius of the filter, in pixels.
+    pub radius: u32,
+    /// The standard deviation of the spatial weight, in pixels.
+    pub spatial: f64,
+    /// How different the colors of two pixels can be. Larger values
+    /// smooth more.
+    pub color: f64,
+    /// How different the albedos of two pixels can be.
+    pub albedo: f64,
+    /// How different the normals of two pixels can be.
+    pub normal: f64,
+}
+
+impl Default for Denoiser {
+    fn default() -> Denoiser {
+        Denoiser { radius: 6, spatial: 3.0, color: 0.3, albedo: 0.1, normal: 0.3 }
+    }
+}
+
+/// The radius of the patches whose colors are compared.
+const PATCH_RADIUS: i64 = 1;
+
+/// The pixels of a film, with a helper to compare them.
+struct Buffer {
+    width: i64,
+    height: i64,
+    pixels: Vec<Color>,
+}
+
+impl Buffer {
+    fn new<F>(film: &Film, f: F) -> Buffer where F: Fn(Color) -> Color {
+        let (w, h) = (film.width(), film.height());
+        let pixels = (0..w * h).map(|i| f(film.pixel(i % w, i / w))).collect();
+        Buffer { width: w as i64, height: h as i64, pixels: pixels }
+    }
+
+    /// Get a pixel, clamping the coordinates to the edges.
+    #[inline]
+    fn at(&self, x: i64, y: i64) -> Color {
+        let x = x.max(0).min(self.width - 1);
+        let y = y.max(0).min(self.height - 1);
+        self.pixels[(x + self.width * y) as usize]
+    }
+
+    /// The squared distance between two pixels.
+    #[inline]
+    fn distance(&self, x0: i64, y0: i64, x1: i64, y1: i64) -> f64 {
+        let d = self.at(x0, y0) - self.at(x1, y1);
+        d.r * d.r + d.g * d.g + d.b * d.b
+    }
+
+    /// The mean squared distance between the patches around two
+    /// pixels.
+    fn patch_distance(&self, x0: i64, y0: i64, x1: i64, y1: i64) -> f64 {
+        let mut sum = 0.0;
+        for dy in -PATCH_RADIUS..PATCH_RADIUS + 1 {
+            for dx in -PATCH_RADIUS..PATCH_RADIUS + 1 {
+                sum += self.distance(x0 + dx, y0 + dy, x1 + dx, y1 + dy);
+            }
+        }
+        let n = 2 * PATCH_RADIUS + 1;
+        sum / (n * n) as f64
+    }
+}
+
+/// Compress the range of a color to [0, 1).
+#[inline]
+fn compress(c: Color) -> Color {
+    Color::from_rgb(c.r / (1.0 + c.r), c.g / (1.0 + c.g), c.b / (1.0 + c.b))
+}
+
+impl Denoiser {
+    /// Denoise a color film, guided by films of the albedo and
+    /// normal, which must have the same size.
+    pub fn denoise(&self, color: &Film, albedo: &Film, normal: &Film) -> Film {
+        let (width, height) = (color.width(), color.height());
+        let raw = Buffer::new(color, |c| c);
+        let compressed = Buffer::new(color, compress);
+        let albedo = Buffer::new(albedo, |c| c);
+        let normal = Buffer::new(normal, |c| c);
+        let r = self.radius as i64;
+        let spatial = 1.0 / (2.0 * self.spatial * self.spatial);
+        let color_scale = 1.0 / (self.color * self.color);
+        let albedo_scale = 1.0 / (self.albedo * self.albedo);
+        let normal_scale = 1.0 / (self.normal * self.normal);
+        let mut out = Film::new(width, height);
+        for y in 0..height as i64 {
+            for x in 0..width as i64 {
+                let mut sum = color::BLACK;
+                let mut total = 0.0;
+                for ny in (y - r).max(0)..(y + r + 1).min(height as i64) {
+                    for nx in (x - r).max(0)..(x + r + 1).min(width as i64) {
+                        let (dx, dy) = ((nx - x) as f64, (ny - y) as f64);
+                        let exponent = (dx * dx + dy * dy) * spatial
+                            + compressed.patch_distance(x, y, nx, ny) * color_scale
+                            + albedo.distance(x, y, nx, ny) * albedo_scale
+                            + normal.distance(x, y, nx, ny) * normal_scale;
+                        let w = (-exponent).exp();
+                        sum = sum + raw.at(nx, ny) * w;
+                        total += w;
+                    }
+                }
+                // the pixel itself always has a weight of 1
+                out.set(x as u32, y as u32, sum / total);
+            }
+        }
+        out
+    }
+}
@@ -0,0 +1,382 @@
+//! The film and reconstruction filters
+//!
+//! The film collects the samples of an image. Each sample is added
+//! to every pixel whose center is within the radius of the filter,
+//! weighted by the filter, and each pixel is the weighted average of
+//! its samples. Wider filters let samples contribute to neighboring
+//! pixels, which reduces aliasing on high-contrast edges.
+
+use std::f64;
+use std::io;
+use std::io::prelude::*;
+
+use color;
+use color::Color;
+use checkpoint;
+
+/// A reconstruction filter, which weights a sample by its offset from
+/// the center of a pixel, in pixels.
+pub trait Filter {
+    /// The radius of the filter, in pixels. Samples further away
+    /// than this along either axis have no weight.
+    fn radius(&self) -> f64;
+    /// The weight of a sample at an offset from the center of a
+    /// pixel. The weight can be negative, for filters that sharpen
+    /// the image. With few samples, the negative weights of a pixel
+    /// can cancel most of the positive ones; see `Film::pixel` for
+    /// how such pixels are resolved.
+    fn weight(&self, dx: f64, dy: f64) -> f64;
+}
+
+/// Weights all samples within the radius equally. A radius of 0.5
+/// averages the samples inside each pixel.
+pub struct BoxFilter {
+    /// The radius of the filter.
+    pub radius: f64,
+}
+
+impl Filter for BoxFilter {
+    fn radius(&self) -> f64 { self.radius }
+
+    fn weight(&self, dx: f64, dy: f64) -> f64 {
+        if dx.abs() < self.radius && dy.abs() < self.radius { 1.0 } else { 0.0 }
+    }
+}
+
+/// Weights samples linearly down to 0 at the radius.
+pub struct TriangleFilter {
+    /// The radius of the filter.
+    pub radius: f64,
+}
+
+impl Filter for TriangleFilter {
+    fn radius(&self) -> f64 { self.radius }
+
+    fn weight(&self, dx: f64, dy: f64) -> f64 {
+        (self.radius - dx.abs()).max(0.0) * (self.radius - dy.abs()).max(0.0)
+    }
+}
+
+/// A Gaussian, shifted down so that it is 0 at the radius.
+pub struct GaussianFilter {
+    /// The radius of the filter.
+    pub radius: f64,
+    /// How quickly the weight falls off. Larger values are sharper.
+    pub alpha: f64,
+}
+
+impl GaussianFilter {
+    fn gaussian(&self, d: f64) -> f64 {
+        ((-self.alpha * d * d).exp() - (-self.alpha * self.radius * self.radius).exp()).max(0.0)
+    }
+}
+
+impl Filter for GaussianFilter {
+    fn radius(&self) -> f64 { self.radius }
+
+    fn weight(&self, dx: f64, dy: f64) -> f64 {
+        self.gaussian(dx) * self.gaussian(dy)
+    }
+}
+
+/// The Mitchell-Netravali filter, a cubic that trades blurring
+/// against ringing with its `b` and `c` parameters. `b = c = 1/3` is
+/// recommended.
+pub struct MitchellFilter {
+    /// The radius of the filter.
+    pub radius: f64,
+    /// The B parameter. Larger values blur more.
+    pub b: f64,
+    /// The C parameter. Larger values ring more.
+    pub c: f64,
+}
+
+impl MitchellFilter {
+    /// The filter in one dimension, where `x` is scaled to [-1, 1].
+    fn mitchell(&self, x: f64) -> f64 {
+        let x = (2.0 * x).abs();
+        let (b, c) = (self.b, self.c);
+        let v = if x > 1.0 {
+            (-b - 6.0 * c) * x * x * x + (6.0 * b + 30.0 * c) * x * x + (-12.0 * b - 48.0 * c) * x + (8.0 * b + 24.0 * c)
+        } else {
+            (12.0 - 9.0 * b - 6.0 * c) * x * x * x + (-18.0 + 12.0 * b + 6.0 * c) * x * x + (6.0 - 2.0 * b)
+        };
+        if x < 2.0 { v / 6.0 } else { 0.0 }
+    }
+}
+
+impl Filter for MitchellFilter {
+    fn radius(&self) -> f64 { self.radius }
+
+    fn weight(&self, dx: f64, dy: f64) -> f64 {
+        self.mitchell(dx / self.radius) * self.mitchell(dy / self.radius)
+    }
+}
+
+/// A windowed sinc filter, which is sharp but rings around edges.
+pub struct LanczosFilter {
+    /// The radius of the filter.
+    pub radius: f64,
+    /// The number of lobes of the sinc within the radius.
+    pub tau: f64,
+}
+
+impl LanczosFilter {
+    fn lanczos(&self, d: f64) -> f64 {
+        let x = d.abs() / self.radius;
+        if x >= 1.0 {
+            return 0.0;
+        }
+        let sinc = |x: f64| if x < 1e-5 { 1.0 } else { (f64::consts::PI * x).sin() / (f64::consts::PI * x) };
+        sinc(x * self.tau) * sinc(x)
+    }
+}
+
+impl Filter for LanczosFilter {
+    fn radius(&self) -> f64 { self.radius }
+
+    fn weight(&self, dx: f64, dy: f64) -> f64 {
+        self.lanczos(dx) * self.lanczos(dy)
+    }
+}
+
+/// A film that accumulates weighted samples for each pixel.
+pub struct Film {
+    width: u32,
+    height: u32,
+    colors: Vec<Color>,
+    weights: Vec<f64>,
+    // the sums of the absolute values of the weights
+    abs_weights: Vec<f64>,
+    signed: bool,
+}
+
+impl Film {
+    /// Create an empty film for colors, which can't be negative.
+    pub fn new(width: u32, height: u32) -> Film {
+        let n = (width * height) as usize;
+        Film { width: width, height: height, colors: vec![color::BLACK; n], weights: vec![0.0; n], abs_weights: vec![0.0; n], signed: false }
+    }
+
+    /// Set whether the pixels can be negative, for data such as
+    /// positions and normals. Otherwise they are clamped to 0.
+    pub fn set_signed(&mut self, signed: bool) {
+        self.signed = signed;
+    }
+
+    /// The width of the film in pixels.
+    pub fn width(&self) -> u32 { self.width }
+
+    /// The height of the film in pixels.
+    pub fn height(&self) -> u32 { self.height }
+
+    /// Add a sample at a position in pixels, where pixel `(x, y)`
+    /// spans from `x` to `x + 1` and `y` to `y + 1`.
+    pub fn add_sample(&mut self, filter: &Filter, x: f64, y: f64, c: Color) {
+        let r = filter.radius();
+        // the pixels whose centers are within the radius
+        let (x0, x1) = ((x - 0.5 - r).ceil().max(0.0), (x - 0.5 + r).floor().min(self.width as f64 - 1.0));
+        let (y0, y1) = ((y - 0.5 - r).ceil().max(0.0), (y - 0.5 + r).floor().min(self.height as f64 - 1.0));
+        if x0 > x1 || y0 > y1 {
+            return;
+        }
+        for py in y0 as u32..y1 as u32 + 1 {
+            for px in x0 as u32..x1 as u32 + 1 {
+                let w = filter.weight(x - (px as f64 + 0.5), y - (py as f64 + 0.5));
+                if w != 0.0 {
+                    let i = (px + self.width * py) as usize;
+                    self.colors[i] = self.colors[i] + c * w;
+                    self.weights[i] += w;
+                    self.abs_weights[i] += w.abs();
+                }
+            }
+        }
+    }
+
+    /// Replace the samples of a pixel with a single color.
+    pub fn set(&mut self, x: u32, y: u32, c: Color) {
+        let i = (x + self.width * y) as usize;
+        self.colors[i] = c;
+        self.weights[i] = 1.0;
+        self.abs_weights[i] = 1.0;
+    }
+
+    /// Copy a rectangle of the film, with its lower left pixel at
+    /// `(x, y)`.
+    pub fn crop(&self, x: u32, y: u32, width: u32, height: u32) -> Film {
+        let mut res = Film::new(width, height);
+        res.signed = self.signed;
+        for py in 0..height {
+            for px in 0..width {
+                let (i, j) = ((x + px + self.width * (y + py)) as usize, (px + width * py) as usize);
+                res.colors[j] = self.colors[i];
+                res.weights[j] = self.weights[i];
+                res.abs_weights[j] = self.abs_weights[i];
+            }
+        }
+        res
+    }
+
+    /// Replace a rectangle of the film with another film, with its
+    /// lower left pixel at `(x, y)`.
+    pub fn paste(&mut self, other: &Film, x: u32, y: u32) {
+        for py in 0..other.height {
+            for px in 0..other.width {
+                let (i, j) = ((x + px + self.width * (y + py)) as usize, (px + other.width * py) as usize);
+                self.colors[i] = other.colors[j];
+                self.weights[i] = other.weights[j];
+                self.abs_weights[i] = other.abs_weights[j];
+            }
+        }
+    }
+
+    /// Get the color of a pixel. Pixels without any weight are black.
+    ///
+    /// The sum of the samples is divided by the sum of their weights,
+    /// but by no less than half of the sum of the absolute weights.
+    /// With few samples, negative weights can cancel most of the sum,
+    /// and dividing by what is left would make bright specks (or
+    /// black pixels if nothing is left). The colors of films that
+    /// are not signed are clamped to 0, since negative weights can
+    /// also make them negative.
+    pub fn pixel(&self, x: u32, y: u32) -> Color {
+        let i = (x + self.width * y) as usize;
+        if !(self.abs_weights[i] > 0.0) {
+            return color::BLACK;
+        }
+        let c = self.colors[i] / self.weights[i].max(0.5 * self.abs_weights[i]);
+        if self.signed { c } else { Color::from_rgb(c.r.max(0.0), c.g.max(0.0), c.b.max(0.0)) }
+    }
+
+    /// Write the film as a PFM image, which stores the colors as
+    /// 32-bit floats without clamping them.
+    pub fn write_pfm<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        try!(write!(w, "PF\n{} {}\n-1.0\n", self.width, self.height));
+        // PFM rows go from the bottom to the top, like the film
+        let mut row = Vec::with_capacity(12 * self.width as usize);
+        for y in 0..self.height {
+            row.clear();
+            for x in 0..self.width {
+                let c = self.pixel(x, y);
+                for &v in &[c.r, c.g, c.b] {
+                    let bits = (v as f32).to_bits();
+                    row.extend_from_slice(&[bits as u8, (bits >> 8) as u8, (bits >> 16) as u8, (bits >> 24) as u8]);
+                }
+            }
+            try!(w.write_all(&row));
+        }
+        Ok(())
+    }
+
+    /// Read a PFM image in the format that `write_pfm` writes. The
+    /// film is signed, so that the pixels are read as they are.
+    pub fn read_pfm<R: Read>(r: &mut R) -> io::Result<Film> {
+        let mut data = Vec::new();
+        try!(r.read_to_end(&mut data));
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "not a PFM image written by the renderer");
+        // the header is three lines of text
+        let mut lines = data.splitn(4, |&b| b == b'\n');
+        let header: Vec<String> = lines.by_ref().take(3).map(|l| String::from_utf8_lossy(l).into_owned()).collect();
+        let pixels = try!(lines.next().ok_or_else(&invalid));
+        if header[0] != "PF" || header[2] != "-1.0" {
+            return Err(invalid());
+        }
+        let size: Vec<u32> = header[1].split(' ').filter_map(|v| v.parse().ok()).collect();
+        if size.len() != 2 || pixels.len() != 12 * (size[0] * size[1]) as usize {
+            return Err(invalid());
+        }
+        let mut film = Film::new(size[0], size[1]);
+        film.signed = true;
+        let read = |i: usize| {
+            let b = &pixels[4 * i..4 * i + 4];
+            f32::from_bits((b[0] as u32) | (b[1] as u32) << 8 | (b[2] as u32) << 16 | (b[3] as u32) << 24) as f64
+        };
+        for i in 0..film.colors.len() {
+            film.colors[i] = Color::from_rgb(read(3 * i), read(3 * i + 1), read(3 * i + 2));
+            film.weights[i] = 1.0;
+            film.abs_weights[i] = 1.0;
+        }
+        Ok(film)
+    }
+
+    /// Save the samples of the film to a checkpoint.
+    pub fn write_state<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        for i in 0..self.colors.len() {
+            let c = self.colors[i];
+            for &v in &[c.r, c.g, c.b, self.weights[i], self.abs_weights[i]] {
+                try!(checkpoint::write_f64(w, v));
+            }
+        }
+        Ok(())
+    }
+
+    /// Load the samples of a film from a checkpoint.
+    pub fn read_state<R: Read>(r: &mut R, width: u32, height: u32) -> io::Result<Film> {
+        let mut film = Film::new(width, height);
+        for i in 0..film.colors.len() {
+            let (red, green, blue) = (try!(checkpoint::read_f64(r)), try!(checkpoint::read_f64(r)), try!(checkpoint::read_f64(r)));
+            film.colors[i] = Color::from_rgb(red, green, blue);
+            film.weights[i] = try!(checkpoint::read_f64(r));
+            film.abs_weights[i] = try!(checkpoint::read_f64(r));
+        }
+        Ok(film)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Film, Filter};
+    use color::Color;
+
+    /// A filter with a weight of 1 left of the pixel center and
+    /// `right` right of it.
+    struct Split {
+        right: f64,
+    }
+
+    impl Filter for Split {
+        fn radius(&self) -> f64 { 0.5 }
+
+        fn weight(&self, dx: f64, _: f64) -> f64 {
+            if dx < 0.0 { 1.0 } else { self.right }
+        }
+    }
+
+    fn gray(v: f64) -> Color {
+        Color::from_rgb(v, v, v)
+    }
+
+    #[test]
+    fn positive_weights() {
+        let mut film = Film::new(1, 1);
+        let filter = Split { right: 0.5 };
+        film.add_sample(&filter, 0.25, 0.5, gray(1.0));
+        film.add_sample(&filter, 0.75, 0.5, gray(4.0));
+        assert!((film.pixel(0, 0).r - 2.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn canceled_weights() {
+        // the weights almost cancel, which would divide by 0.01
+        let mut film = Film::new(1, 1);
+        let filter = Split { right: -0.99 };
+        film.add_sample(&filter, 0.25, 0.5, gray(1.0));
+        film.add_sample(&filter, 0.75, 0.5, gray(0.0));
+        let c = film.pixel(0, 0).r;
+        assert!(c > 0.5 && c < 2.0, "{}", c);
+    }
+
+    #[test]
+    fn negative_colors() {
+        let filter = Split { right: -0.5 };
+        let mut film = Film::new(1, 1);
+        film.add_sample(&filter, 0.25, 0.5, gray(0.0));
+        film.add_sample(&filter, 0.75, 0.5, gray(1.0));
+        assert_eq!(film.pixel(0, 0).r, 0.0);
+        // signed films keep negative values
+        film.set_signed(true);
+        assert!(film.pixel(0, 0).r < 0.0);
+        film.set(0, 0, gray(-1.0));
+        assert_eq!(film.pixel(0, 0).r, -1.0);
+    }
+}
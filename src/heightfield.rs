@@ -0,0 +1,209 @@
+//! Heightfields
+//!
+//! A heightfield is a terrain surface defined by a grid of heights,
+//! usually loaded from a grayscale image. Each cell of the grid is
+//! split into two triangles, and rays walk through the grid cell by
+//! cell, so large terrains are fast to intersect. The normals are
+//! interpolated between the vertices to make the surface look smooth.
+
+use std::f64;
+
+use types::*;
+use types::na::{Norm, Dot, cross};
+use shapes::{Ray, IntersectionResult, Interval, Shape, AxisAlignedBox, BoundingBox};
+#[cfg(feature = "textures")]
+use texture::Texture;
+
+/// A surface defined by a grid of heights. The grid spans `size.x`
+/// along the x axis and `size.z` along the z axis from `min`, and a
+/// height of 1 is `size.y` above `min`. The texture coordinates go
+/// from 0 to 1 along the x and z axes. It does not enclose a volume.
+pub struct Heightfield {
+    nx: usize,
+    nz: usize,
+    heights: Vec<f64>,
+    normals: Vec<Vec3>,
+    min: Pnt3,
+    size: Vec3,
+    bounds: BoundingBox,
+}
+
+impl Heightfield {
+    /// Create a heightfield from a grid of `nx` by `nz` heights, with
+    /// x varying fastest. The grid must be at least 2 by 2.
+    pub fn new(nx: usize, nz: usize, heights: Vec<f64>, min: Pnt3, size: Vec3) -> Heightfield {
+        assert!(nx >= 2 && nz >= 2 && heights.len() == nx * nz);
+        let (lo, hi) = heights.iter().fold((f64::INFINITY, -f64::INFINITY), |(lo, hi), &h| (lo.min(h), hi.max(h)));
+        // the height scale can be negative, so the corners are sorted
+        let bounds = BoundingBox::from_points(&[
+            Pnt3::new(min.x, min.y + size.y * lo, min.z),
+            Pnt3::new(min.x + size.x, min.y + size.y * hi, min.z + size.z),
+        ]);
+        let mut field = Heightfield {
+            nx: nx,
+            nz: nz,
+            heights: heights,
+            normals: Vec::new(),
+            min: min,
+            size: size,
+            bounds: bounds,
+        };
+        field.normals = (0..nx * nz).map(|k| field.vertex_normal(k % nx, k / nx)).collect();
+        field
+    }
+
+    /// Create a heightfield from the brightness of an image. The top
+    /// of the image is at the lowest z. Images with 8 bits per channel
+    /// should be loaded as non-color data, so that the heights are not
+    /// decoded from sRGB, which is the default in scene files.
+    #[cfg(feature = "textures")]
+    pub fn from_texture(texture: &Texture, min: Pnt3, size: Vec3) -> Heightfield {
+        let (nx, nz) = (texture.width() as usize, texture.height() as usize);
+        let heights = (0..nx * nz).map(|k| {
+            let c = texture.at((k % nx) as i64, (k / nx) as i64);
+            (c.r + c.g + c.b) / 3.0
+        }).collect();
+        Heightfield::new(nx, nz, heights, min, size)
+    }
+
+    #[inline]
+    fn height(&self, i: usize, j: usize) -> f64 {
+        self.heights[i + self.nx * j]
+    }
+
+    /// The position of a vertex of the grid.
+    #[inline]
+    fn vertex(&self, i: usize, j: usize) -> Pnt3 {
+        Pnt3::new(
+            self.min.x + self.size.x * i as f64 / (self.nx - 1) as f64,
+            self.min.y + self.size.y * self.height(i, j),
+            self.min.z + self.size.z * j as f64 / (self.nz - 1) as f64,
+        )
+    }
+
+    /// Estimate the normal at a vertex from the slope to its
+    /// neighbors.
+    fn vertex_normal(&self, i: usize, j: usize) -> Vec3 {
+        let (i0, i1) = (if i > 0 { i - 1 } else { i }, if i + 1 < self.nx { i + 1 } else { i });
+        let (j0, j1) = (if j > 0 { j - 1 } else { j }, if j + 1 < self.nz { j + 1 } else { j });
+        let dx = self.vertex(i1, j) - self.vertex(i0, j);
+        let dz = self.vertex(i, j1) - self.vertex(i, j0);
+        let n = cross(&dz, &dx);
+        if n.sqnorm() > 0.0 { n.normalize() } else { Vec3::new(0.0, 1.0, 0.0) }
+    }
+
+    /// Intersect a ray with one triangle of a cell. `a` is the corner
+    /// vertex, and `b` and `c` are one step along x and z from it.
+    /// `flip` is true for the triangle whose corner is the highest
+    /// vertex of the cell.
+    fn intersect_triangle(&self, ray: &Ray, a: (usize, usize), b: (usize, usize), c: (usize, usize), flip: bool) -> Option<IntersectionResult> {
+        let p0 = self.vertex(a.0, a.1);
+        let (e1, e2) = (self.vertex(b.0, b.1) - p0, self.vertex(c.0, c.1) - p0);
+        let h = cross(&ray.direction, &e2);
+        let det = e1.dot(&h);
+        if det == 0.0 {
+            return None;
+        }
+        let s = ray.origin - p0;
+        let u = s.dot(&h) / det;
+        if u < 0.0 || u > 1.0 {
+            return None;
+        }
+        let q = cross(&s, &e1);
+        let v = ray.direction.dot(&q) / det;
+        if v < 0.0 || u + v > 1.0 {
+            return None;
+        }
+        let t = e2.dot(&q) / det;
+        if !(t > 0.0) {
+            return None;
+        }
+        let normal = (self.normals[a.0 + self.nx * a.1] * (1.0 - u - v)
+            + self.normals[b.0 + self.nx * b.1] * u
+            + self.normals[c.0 + self.nx * c.1] * v).normalize();
+        let (sx, sz) = ((self.nx - 1) as f64, (self.nz - 1) as f64);
+        let sign = if flip { -1.0 } else { 1.0 };
+        let uv = Pnt2::new((a.0 as f64 + sign * u) / sx, (a.1 as f64 + sign * v) / sz);
+        Some(IntersectionResult::new(t, normal, uv, e1 * (sign * sx), e2 * (sign * sz)))
+    }
+
+    /// Intersect a ray with the two triangles of the cell at `(i, j)`.
+    fn intersect_cell(&self, ray: &Ray, i: usize, j: usize) -> Option<IntersectionResult> {
+        let first = self.intersect_triangle(ray, (i, j), (i + 1, j), (i, j + 1), false);
+        let second = self.intersect_triangle(ray, (i + 1, j + 1), (i, j + 1), (i + 1, j), true);
+        match (first, second) {
+            (Some(a), Some(b)) => Some(if a.t < b.t { a } else { b }),
+            (a, None) => a,
+            (None, b) => b,
+        }
+    }
+}
+
+impl Shape for Heightfield {
+    fn intersect(&self, ray: &Ray) -> Option<IntersectionResult> {
+        let bounds = AxisAlignedBox { min: self.bounds.min, max: self.bounds.max };
+        let span = match bounds.intervals(ray).pop() {
+            Some(span) => span,
+            None => return None,
+        };
+        let (t0, t1) = (span.enter.t.max(0.0), span.exit.t);
+        if t0 > t1 {
+            return None;
+        }
+        // walk through the cells in grid coordinates, where cells are
+        // one unit wide
+        let (sx, sz) = ((self.nx - 1) as f64, (self.nz - 1) as f64);
+        let p = ray.cast(t0);
+        let (gx, gz) = ((p.x - self.min.x) / self.size.x * sx, (p.z - self.min.z) / self.size.z * sz);
+        let (dx, dz) = (ray.direction.x / self.size.x * sx, ray.direction.z / self.size.z * sz);
+        let clamp = |g: f64, n: usize| if g < 0.0 { 0 } else if g as usize >= n - 1 { n - 2 } else { g as usize };
+        let (mut i, mut j) = (clamp(gx, self.nx) as i64, clamp(gz, self.nz) as i64);
+        let (step_i, delta_x, mut next_x) = if dx > 0.0 {
+            (1, 1.0 / dx, t0 + (i as f64 + 1.0 - gx) / dx)
+        } else if dx < 0.0 {
+            (-1, -1.0 / dx, t0 + (i as f64 - gx) / dx)
+        } else {
+            (0, f64::INFINITY, f64::INFINITY)
+        };
+        let (step_j, delta_z, mut next_z) = if dz > 0.0 {
+            (1, 1.0 / dz, t0 + (j as f64 + 1.0 - gz) / dz)
+        } else if dz < 0.0 {
+            (-1, -1.0 / dz, t0 + (j as f64 - gz) / dz)
+        } else {
+            (0, f64::INFINITY, f64::INFINITY)
+        };
+        let mut t = t0;
+        while i >= 0 && j >= 0 && (i as usize) < self.nx - 1 && (j as usize) < self.nz - 1 && t <= t1 {
+            let (ci, cj) = (i as usize, j as usize);
+            let t_exit = next_x.min(next_z).min(t1);
+            // skip cells whose heights the ray is above or below
+            let (y0, y1) = (ray.cast(t).y, ray.cast(t_exit).y);
+            let (ray_lo, ray_hi) = (y0.min(y1), y0.max(y1));
+            let ys = [self.vertex(ci, cj).y, self.vertex(ci + 1, cj).y, self.vertex(ci, cj + 1).y, self.vertex(ci + 1, cj + 1).y];
+            let (cell_lo, cell_hi) = (ys[0].min(ys[1]).min(ys[2]).min(ys[3]), ys[0].max(ys[1]).max(ys[2]).max(ys[3]));
+            if ray_hi >= cell_lo && ray_lo <= cell_hi {
+                if let Some(result) = self.intersect_cell(ray, ci, cj) {
+                    return Some(result);
+                }
+            }
+            if next_x < next_z {
+                t = next_x;
+                next_x += delta_x;
+                i += step_i;
+            } else {
+                t = next_z;
+                next_z += delta_z;
+                j += step_j;
+            }
+        }
+        None
+    }
+
+    fn intervals(&self, _: &Ray) -> Vec<Interval> {
+        Vec::new()
+    }
+
+    fn bounds(&self) -> Option<BoundingBox> {
+        Some(self.bounds)
+    }
+}
@@ -6,8 +6,20 @@ pub mod types;
 pub mod color;
 pub mod scene;
 pub mod camera;
+pub mod animation;
 pub mod raytrace;
+pub mod render;
+pub mod checkpoint;
+pub mod film;
+pub mod aov;
+pub mod denoise;
+pub mod sampler;
 pub mod bmp;
 pub mod serialize;
-#[cfg(feature = "skybox")]
+pub mod param;
+pub mod procedural;
+pub mod voxel;
+pub mod sdf;
+pub mod heightfield;
+#[cfg(feature = "textures")]
 pub mod texture;
@@ -1,18 +1,24 @@
 extern crate libraytrace;
 
-use std::io::BufReader;
+use std::io::{BufReader, BufWriter};
 use std::io::prelude::*;
+use std::fs;
 use std::fs::File;
+use std::time::Instant;
 
 use libraytrace::types::*;
-use libraytrace::color;
-use libraytrace::raytrace;
+use libraytrace::render;
+use libraytrace::checkpoint;
+use libraytrace::scene::Scene;
+use libraytrace::sampler::Sampler;
+use libraytrace::film::Film;
+use libraytrace::denoise::Denoiser;
 use libraytrace::bmp;
 use libraytrace::serialize;
 
 fn main() {
     // read a file
-    let scene = {
+    let (mut scene, hash) = {
         let file = match File::open("test_scene.txt") {
             Ok(f) => f,
             Err(e) => { println!("error: {}", e); return },
@@ -24,37 +30,148 @@ fn main() {
             Err(e) => { println!("error: {}", e); return },
         }
         match serialize::deserialize(&contents) {
-            Ok(s) => s,
+            Ok(s) => (s, checkpoint::scene_hash(&contents)),
             Err(e) => { println!("error: {}", e); return },
         }
     };
-    let width = scene.options.width;
-    let height = scene.options.height;
-    // write BMP
-    let mut file_handle = File::create("out.bmp")
-                          .ok().expect("error creating out.bmp");
-    let bytewidth = bmp::write_header(&mut file_handle, width, height)
-                    .ok().expect("error writing BMP header");
+    // command line flags override the denoising and sampling options
+    let mut resume = false;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_ref() {
+            "--denoise" => if scene.options.denoise.is_none() { scene.options.denoise = Some(Denoiser::default()) },
+            "--no-denoise" => scene.options.denoise = None,
+            "--resume" => resume = true,
+            "--samples" => match args.next().and_then(|n| n.parse().ok()) {
+                Some(n) => scene.options.antialias = n,
+                None => { println!("error: --samples needs a number"); return },
+            },
+            _ => { println!("error: unknown argument {}", arg); return },
+        }
+    }
     // render image
-    let halfwidth  = (width  as f64) / 2.0;
-    let halfheight = (height as f64) / 2.0;
-    let scale = (1.0 / halfwidth).max(1.0 / halfheight);
-    let mut row: Vec<u8> = vec![0; bytewidth as usize];
-    let mut rng = rand::weak_rng();
-    let aasamples = scene.options.antialias;
-    for y in 0..height {
-        for x in 0..width {
-            let mut res = color::BLACK;
-            for _ in 0..aasamples {
-                // transform to (-1, 1)
-                let pos = Pnt2::new(
-                    ((x as f64 + rng.gen::<f64>()) - halfwidth)  * scale,
-                    ((y as f64 + rng.gen::<f64>()) - halfheight) * scale,
-                );
-                res = res + raytrace::raytrace(&scene, &pos, 1.0, &mut rng);
+    let mut rng = Sampler::new(scene.options.sampler, rand::weak_rng());
+    let state = match scene.options.progressive {
+        Some(ref progressive) => {
+            let mut state = if resume {
+                let loaded = File::open("out.state").and_then(|f| checkpoint::load(&mut BufReader::new(f), hash, &scene));
+                match loaded {
+                    Ok((state, sampler)) => {
+                        rng = sampler;
+                        state
+                    },
+                    Err(e) => { println!("error: can't resume from out.state: {}", e); return },
+                }
+            } else {
+                render::RenderState::new(&scene)
+            };
+            let mut last_write = Instant::now();
+            while state.render_pass(&scene, progressive.samples, &mut rng) {
+                let elapsed = last_write.elapsed();
+                let seconds = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 * 1e-9;
+                if (progressive.checkpoint_passes > 0 && state.passes % progressive.checkpoint_passes == 0)
+                    || (progressive.checkpoint_interval > 0.0 && seconds >= progressive.checkpoint_interval) {
+                    println!("pass {}: {} samples", state.passes, state.samples());
+                    write_images(&scene, &state);
+                    write_checkpoint(hash, &state, &mut rng);
+                    last_write = Instant::now();
+                }
+            }
+            write_checkpoint(hash, &state, &mut rng);
+            state
+        },
+        None => {
+            if resume {
+                println!("error: only progressive renders can be resumed");
+                return;
             }
-            (res / aasamples as f64).write_bgr(&mut row, x as usize);
+            render::render(&scene, &mut rng)
+        },
+    };
+    write_images(&scene, &state);
+}
+
+/// Write the rendered image, and the output variables of the options.
+fn write_images(scene: &Scene, state: &render::RenderState) {
+    let (x, y, width, height) = scene.options.region();
+    let raw = state.film.crop(x, y, width, height);
+    // when denoising, the raw image is kept next to the denoised one
+    let denoised = render::denoise(scene, &state.film, &state.aovs);
+    let film = match denoised {
+        Some(ref denoised) => {
+            write_region(scene, "out_raw.bmp", &raw);
+            denoised
+        },
+        None => &raw,
+    };
+    write_region(scene, "out.bmp", film);
+    // write the output variables, along with the beauty pass in HDR
+    if !scene.options.aovs.is_empty() {
+        write_region(scene, "out.pfm", film);
+        for &aov in &scene.options.aovs {
+            write_region(scene, &format!("out_{}.pfm", aov.name()), &state.aovs.layer(aov).unwrap().crop(x, y, width, height));
+        }
+    }
+}
+
+/// Write the rendered region of an image to a BMP or PFM file. When
+/// the crop rectangle is merged, it is pasted into the image that is
+/// already in the file, or into a black image if there is none of
+/// the right size.
+fn write_region(scene: &Scene, name: &str, region: &Film) {
+    let pfm = name.ends_with(".pfm");
+    let merge = scene.options.crop.as_ref().map_or(false, |crop| crop.merge);
+    if !merge {
+        return if pfm { write_pfm(name, region) } else { write_bmp(name, region) };
+    }
+    let (width, height) = (scene.options.width, scene.options.height);
+    let existing = File::open(name).and_then(|mut f| if pfm { Film::read_pfm(&mut f) } else { bmp::read(&mut f) });
+    let mut film = match existing {
+        Ok(film) => if film.width() == width && film.height() == height {
+            film
+        } else {
+            println!("warning: {} has a different size, so it is replaced", name);
+            Film::new(width, height)
+        },
+        Err(_) => Film::new(width, height),
+    };
+    let (x, y, _, _) = scene.options.region();
+    film.paste(region, x, y);
+    if pfm { write_pfm(name, &film) } else { write_bmp(name, &film) }
+}
+
+/// Save a checkpoint of a progressive render to `out.state`. It is
+/// written to a temporary file first, so that the last checkpoint is
+/// kept if the render is killed while saving.
+fn write_checkpoint(hash: u64, state: &render::RenderState, rng: &mut Sampler) {
+    {
+        let file_handle = File::create("out.state.tmp")
+                          .ok().expect("error creating checkpoint");
+        let mut writer = BufWriter::new(file_handle);
+        checkpoint::save(&mut writer, hash, state, rng).and_then(|_| writer.flush())
+                  .ok().expect("error writing checkpoint");
+    }
+    fs::rename("out.state.tmp", "out.state").ok().expect("error writing checkpoint");
+}
+
+/// Write a film to a BMP file.
+fn write_bmp(name: &str, film: &Film) {
+    let mut file_handle = File::create(name)
+                          .ok().expect("error creating BMP file");
+    let bytewidth = bmp::write_header(&mut file_handle, film.width(), film.height())
+                    .ok().expect("error writing BMP header");
+    let mut row: Vec<u8> = vec![0; bytewidth as usize];
+    for y in 0..film.height() {
+        for x in 0..film.width() {
+            film.pixel(x, y).write_bgr(&mut row, x as usize);
         }
         file_handle.write_all(&row[..]).ok().expect("error writing row");
     }
 }
+
+/// Write a film to a PFM file.
+fn write_pfm(name: &str, film: &Film) {
+    let mut file_handle = File::create(name)
+                          .ok().expect("error creating PFM file");
+    film.write_pfm(&mut file_handle).ok().expect("error writing PFM file");
+}
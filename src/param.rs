@@ -0,0 +1,99 @@
+//! Material parameters
+//!
+//! A material parameter is either a constant or a value that
+//! varies across the surface of an object, such as a lookup in a
+//! texture using the texture coordinates of the intersection or a
+//! procedural pattern.
+
+use types::*;
+use shapes::IntersectionResult;
+use color::Color;
+use procedural::{ColorPattern, ScalarPattern};
+#[cfg(feature = "textures")]
+use texture::Texture;
+
+/// A texture that is mapped onto a surface using its texture
+/// coordinates.
+#[cfg(feature = "textures")]
+pub struct ImageTexture {
+    /// The texture.
+    pub texture: Texture,
+    /// Scale applied to the texture coordinates before sampling.
+    pub scale: f64,
+    /// Multiplier applied to the sampled values.
+    pub multiplier: f64,
+    /// Whether to use the alpha channel of the texture instead of
+    /// its color. The alpha is used for all three components.
+    pub alpha: bool,
+}
+
+#[cfg(feature = "textures")]
+impl ImageTexture {
+    /// Sample the texture at the texture coordinates of an
+    /// intersection. The v coordinate points up in the image. The
+    /// texture is filtered over the footprint of the pixel.
+    pub fn sample(&self, result: &IntersectionResult) -> Color {
+        let u = result.uv.x * self.scale;
+        let v = result.uv.y * self.scale;
+        let width = result.duvdx.x.abs().max(result.duvdx.y.abs())
+                    .max(result.duvdy.x.abs()).max(result.duvdy.y.abs()) * self.scale;
+        let (c, a) = self.texture.sample_rgba_filtered(u, 1.0 - v, width);
+        if self.alpha {
+            Color::from_rgb(a, a, a) * self.multiplier
+        } else {
+            c * self.multiplier
+        }
+    }
+}
+
+/// A color parameter of a material.
+pub enum ColorParam {
+    /// The same color everywhere.
+    Constant(Color),
+    /// A color looked up in a texture.
+    #[cfg(feature = "textures")]
+    Texture(ImageTexture),
+    /// A color computed by a procedural pattern.
+    Pattern(ColorPattern),
+}
+
+impl ColorParam {
+    /// Get the color at an intersection, where `pt` is the point of
+    /// intersection.
+    pub fn at(&self, result: &IntersectionResult, pt: &Pnt3) -> Color {
+        match *self {
+            ColorParam::Constant(c) => c,
+            #[cfg(feature = "textures")]
+            ColorParam::Texture(ref t) => t.sample(result),
+            ColorParam::Pattern(ref p) => p.ramp.color(p.pattern.value(pt, &result.uv)),
+        }
+    }
+}
+
+/// A scalar parameter of a material.
+pub enum ScalarParam {
+    /// The same value everywhere.
+    Constant(f64),
+    /// A value looked up in a texture. The value is the average of
+    /// the color components.
+    #[cfg(feature = "textures")]
+    Texture(ImageTexture),
+    /// A value computed by a procedural pattern.
+    Pattern(ScalarPattern),
+}
+
+impl ScalarParam {
+    /// Get the value at an intersection, where `pt` is the point of
+    /// intersection.
+    pub fn at(&self, result: &IntersectionResult, pt: &Pnt3) -> f64 {
+        match *self {
+            ScalarParam::Constant(x) => x,
+            #[cfg(feature = "textures")]
+            ScalarParam::Texture(ref t) => {
+                let c = t.sample(result);
+                (c.r + c.g + c.b) / 3.0
+            },
+            ScalarParam::Pattern(ref p) => p.low + (p.high - p.low) * p.pattern.value(pt, &result.uv),
+        }
+    }
+}
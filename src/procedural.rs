@@ -0,0 +1,301 @@
+//! Procedural textures
+//!
+//! Procedural textures are computed from the point of intersection
+//! instead of being looked up in an image. A pattern computes a
+//! value in [0, 1], which can then be mapped onto colors using a
+//! `ColorRamp`.
+
+use std::f64;
+
+use types::*;
+use color::Color;
+
+use types::rand::{SeedableRng, XorShiftRng};
+
+/// The coordinates that a pattern is evaluated in.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PatternSpace {
+    /// The texture coordinates of the surface, with z = 0.
+    Uv,
+    /// The point of intersection in world space.
+    World,
+}
+
+/// A pattern that produces a value in [0, 1] for each point on a
+/// surface.
+pub trait Pattern {
+    /// Compute the value of the pattern. `pt` is the point of
+    /// intersection and `uv` are its texture coordinates.
+    fn value(&self, pt: &Pnt3, uv: &Pnt2) -> f64;
+}
+
+/// Where to evaluate a pattern, and how much to scale it.
+pub struct PatternCoords {
+    /// The space the pattern is evaluated in.
+    pub space: PatternSpace,
+    /// Scale applied to the coordinates. Larger values produce
+    /// smaller features.
+    pub scale: f64,
+}
+
+impl PatternCoords {
+    /// Get the scaled coordinates of a point.
+    pub fn get(&self, pt: &Pnt3, uv: &Pnt2) -> Pnt3 {
+        match self.space {
+            PatternSpace::Uv => Pnt3::new(uv.x * self.scale, uv.y * self.scale, 0.0),
+            PatternSpace::World => Pnt3::new(pt.x * self.scale, pt.y * self.scale, pt.z * self.scale),
+        }
+    }
+}
+
+/// Gradient noise as described by Ken Perlin in "Improving Noise".
+pub struct Perlin {
+    perm: [u8; 512],
+}
+
+impl Perlin {
+    /// Create the noise function with a permutation generated from
+    /// the given seed.
+    pub fn new(seed: u32) -> Perlin {
+        let mut rng = XorShiftRng::from_seed([seed ^ 0x193a6754, 0xa8a7d469, 0x97830e05, 0x113ba7bb]);
+        let mut p: Vec<u8> = (0..256).map(|i| i as u8).collect();
+        rng.shuffle(&mut p);
+        let mut perm = [0u8; 512];
+        for i in 0..512 {
+            perm[i] = p[i & 255];
+        }
+        Perlin { perm: perm }
+    }
+
+    /// Evaluate the noise at a point. The result is in [-1, 1].
+    pub fn noise(&self, p: &Pnt3) -> f64 {
+        let (xf, yf, zf) = (p.x.floor(), p.y.floor(), p.z.floor());
+        let xi = (xf as i64 & 255) as usize;
+        let yi = (yf as i64 & 255) as usize;
+        let zi = (zf as i64 & 255) as usize;
+        let (x, y, z) = (p.x - xf, p.y - yf, p.z - zf);
+        let (u, v, w) = (fade(x), fade(y), fade(z));
+        let perm = &self.perm;
+        let a = perm[xi] as usize + yi;
+        let aa = perm[a] as usize + zi;
+        let ab = perm[a + 1] as usize + zi;
+        let b = perm[xi + 1] as usize + yi;
+        let ba = perm[b] as usize + zi;
+        let bb = perm[b + 1] as usize + zi;
+        lerp(w, lerp(v, lerp(u, grad(perm[aa], x, y, z),
+                                grad(perm[ba], x - 1.0, y, z)),
+                        lerp(u, grad(perm[ab], x, y - 1.0, z),
+                                grad(perm[bb], x - 1.0, y - 1.0, z))),
+                lerp(v, lerp(u, grad(perm[aa + 1], x, y, z - 1.0),
+                                grad(perm[ba + 1], x - 1.0, y, z - 1.0)),
+                        lerp(u, grad(perm[ab + 1], x, y - 1.0, z - 1.0),
+                                grad(perm[bb + 1], x - 1.0, y - 1.0, z - 1.0))))
+    }
+
+    /// Fractional Brownian motion: a sum of octaves of noise with
+    /// increasing frequency and decreasing amplitude. The result is
+    /// roughly in [-1, 1].
+    pub fn fbm(&self, p: &Pnt3, octaves: u32, lacunarity: f64, gain: f64) -> f64 {
+        let (mut sum, mut amp, mut freq, mut norm) = (0.0, 1.0, 1.0, 0.0);
+        for _ in 0..octaves {
+            sum = sum + amp * self.noise(&Pnt3::new(p.x * freq, p.y * freq, p.z * freq));
+            norm = norm + amp;
+            amp = amp * gain;
+            freq = freq * lacunarity;
+        }
+        if norm > 0.0 { sum / norm } else { 0.0 }
+    }
+
+    /// Turbulence: like `fbm`, but using the absolute value of each
+    /// octave, which creates creases. The result is in [0, 1].
+    pub fn turbulence(&self, p: &Pnt3, octaves: u32, lacunarity: f64, gain: f64) -> f64 {
+        let (mut sum, mut amp, mut freq, mut norm) = (0.0, 1.0, 1.0, 0.0);
+        for _ in 0..octaves {
+            sum = sum + amp * self.noise(&Pnt3::new(p.x * freq, p.y * freq, p.z * freq)).abs();
+            norm = norm + amp;
+            amp = amp * gain;
+            freq = freq * lacunarity;
+        }
+        if norm > 0.0 { sum / norm } else { 0.0 }
+    }
+}
+
+#[inline]
+fn fade(t: f64) -> f64 {
+    t * t * t * (t * (t * 6.0 - 15.0) + 10.0)
+}
+
+#[inline]
+fn lerp(t: f64, a: f64, b: f64) -> f64 {
+    a + t * (b - a)
+}
+
+#[inline]
+fn grad(hash: u8, x: f64, y: f64, z: f64) -> f64 {
+    let h = hash & 15;
+    let u = if h < 8 { x } else { y };
+    let v = if h < 4 { y } else if h == 12 || h == 14 { x } else { z };
+    (if h & 1 == 0 { u } else { -u }) + (if h & 2 == 0 { v } else { -v })
+}
+
+#[inline]
+fn clamp01(x: f64) -> f64 {
+    if x < 0.0 { 0.0 } else if x > 1.0 { 1.0 } else { x }
+}
+
+/// A checkerboard of cubes (or squares in texture space) of size 1,
+/// alternating between 0 and 1.
+pub struct Checker {
+    /// The coordinates of the pattern.
+    pub coords: PatternCoords,
+}
+
+impl Pattern for Checker {
+    fn value(&self, pt: &Pnt3, uv: &Pnt2) -> f64 {
+        let p = self.coords.get(pt, uv);
+        let sum = p.x.floor() as i64 + p.y.floor() as i64 + p.z.floor() as i64;
+        if sum & 1 == 0 { 0.0 } else { 1.0 }
+    }
+}
+
+/// Perlin noise, or fractional Brownian motion with more than one
+/// octave.
+pub struct Noise {
+    /// The coordinates of the pattern.
+    pub coords: PatternCoords,
+    /// The number of octaves.
+    pub octaves: u32,
+    /// The frequency multiplier between octaves.
+    pub lacunarity: f64,
+    /// The amplitude multiplier between octaves.
+    pub gain: f64,
+    /// The noise function.
+    pub perlin: Perlin,
+}
+
+impl Pattern for Noise {
+    fn value(&self, pt: &Pnt3, uv: &Pnt2) -> f64 {
+        let p = self.coords.get(pt, uv);
+        clamp01(0.5 + 0.5 * self.perlin.fbm(&p, self.octaves, self.lacunarity, self.gain))
+    }
+}
+
+/// Turbulence, which is the sum of the absolute values of octaves
+/// of noise.
+pub struct Turbulence {
+    /// The coordinates of the pattern.
+    pub coords: PatternCoords,
+    /// The number of octaves.
+    pub octaves: u32,
+    /// The frequency multiplier between octaves.
+    pub lacunarity: f64,
+    /// The amplitude multiplier between octaves.
+    pub gain: f64,
+    /// The noise function.
+    pub perlin: Perlin,
+}
+
+impl Pattern for Turbulence {
+    fn value(&self, pt: &Pnt3, uv: &Pnt2) -> f64 {
+        let p = self.coords.get(pt, uv);
+        clamp01(self.perlin.turbulence(&p, self.octaves, self.lacunarity, self.gain))
+    }
+}
+
+/// Marble: veins along the x axis that are distorted by turbulence.
+pub struct Marble {
+    /// The coordinates of the pattern.
+    pub coords: PatternCoords,
+    /// The number of veins per unit.
+    pub frequency: f64,
+    /// How strongly the veins are distorted.
+    pub distortion: f64,
+    /// The number of octaves of turbulence.
+    pub octaves: u32,
+    /// The noise function.
+    pub perlin: Perlin,
+}
+
+impl Pattern for Marble {
+    fn value(&self, pt: &Pnt3, uv: &Pnt2) -> f64 {
+        let p = self.coords.get(pt, uv);
+        let turb = self.perlin.turbulence(&p, self.octaves, 2.0, 0.5);
+        0.5 + 0.5 * (p.x * self.frequency * 2.0 * f64::consts::PI + self.distortion * turb).sin()
+    }
+}
+
+/// Wood: concentric rings around the y axis that are distorted by
+/// turbulence. The value is a sawtooth from the inside to the outside
+/// of each ring.
+pub struct Wood {
+    /// The coordinates of the pattern.
+    pub coords: PatternCoords,
+    /// The number of rings per unit.
+    pub frequency: f64,
+    /// How strongly the rings are distorted.
+    pub distortion: f64,
+    /// The number of octaves of turbulence.
+    pub octaves: u32,
+    /// The noise function.
+    pub perlin: Perlin,
+}
+
+impl Pattern for Wood {
+    fn value(&self, pt: &Pnt3, uv: &Pnt2) -> f64 {
+        let p = self.coords.get(pt, uv);
+        let turb = self.perlin.turbulence(&p, self.octaves, 2.0, 0.5);
+        let r = (p.x * p.x + p.z * p.z).sqrt() * self.frequency + self.distortion * turb;
+        r - r.floor()
+    }
+}
+
+/// A point on a color ramp.
+pub struct ColorStop {
+    /// The position of the stop in [0, 1].
+    pub at: f64,
+    /// The color at the stop.
+    pub color: Color,
+}
+
+/// A gradient that maps values in [0, 1] to colors by linearly
+/// interpolating between stops.
+pub struct ColorRamp {
+    /// The stops of the ramp, sorted by position.
+    pub stops: Vec<ColorStop>,
+}
+
+impl ColorRamp {
+    /// Get the color at the given value. Values outside of the first
+    /// and last stops get the color of that stop.
+    pub fn color(&self, x: f64) -> Color {
+        let next = self.stops.iter().position(|s| s.at > x).unwrap_or(self.stops.len());
+        if next == 0 {
+            return self.stops[0].color;
+        }
+        let prev = &self.stops[next - 1];
+        if next == self.stops.len() {
+            return prev.color;
+        }
+        let next = &self.stops[next];
+        let f = (x - prev.at) / (next.at - prev.at);
+        prev.color * (1.0 - f) + next.color * f
+    }
+}
+
+/// A pattern that is mapped to colors using a ramp.
+pub struct ColorPattern {
+    /// The pattern.
+    pub pattern: Box<Pattern>,
+    /// The colors that the values of the pattern are mapped to.
+    pub ramp: ColorRamp,
+}
+
+/// A pattern that is mapped linearly onto a range of values.
+pub struct ScalarPattern {
+    /// The pattern.
+    pub pattern: Box<Pattern>,
+    /// The value where the pattern is 0.
+    pub low: f64,
+    /// The value where the pattern is 1.
+    pub high: f64,
+}
@@ -6,13 +6,14 @@
 use std::f64;
 
 use types::*;
-use shapes::{Shape, Ray, IntersectionResult};
+use shapes::{Shape, Ray, RayDifferential, IntersectionResult};
 use camera::Camera;
 use color;
 use color::Color;
 use scene::*;
+use aov::SurfaceSample;
 
-use types::na::{dot, Norm};
+use types::na::{cross, dot, Norm};
 
 const MIN_SIGNIFICANCE: f64 = 1.0f64 / 256.0 / 2.0;
 const MAX_DEPTH: u32 = 4;
@@ -27,52 +28,175 @@ fn clamp_one(x: f64) -> f64 {
     if x > 1.0 { 1.0 } else { x }
 }
 
+/// Move the origin of an offset ray to the tangent plane at `pt`.
+#[inline]
+fn offset_hit(origin: &Pnt3, direction: &Vec3, pt: &Pnt3, normal: &Vec3) -> Pnt3 {
+    *origin + *direction * (dot(normal, &(*pt - *origin)) / dot(normal, direction))
+}
+
+/// Reflect the differentials of a ray about the normal at `pt`. The
+/// surface is treated as flat around the point of intersection.
+fn reflect_differential(ray: &Ray, pt: &Pnt3, normal: &Vec3) -> Option<RayDifferential> {
+    ray.differential.as_ref().map(|d| {
+        let reflect = |v: &Vec3| *v - *normal * (2.0 * dot(v, normal));
+        RayDifferential {
+            rx_origin: offset_hit(&d.rx_origin, &d.rx_direction, pt, normal),
+            rx_direction: reflect(&d.rx_direction),
+            ry_origin: offset_hit(&d.ry_origin, &d.ry_direction, pt, normal),
+            ry_direction: reflect(&d.ry_direction),
+        }
+    })
+}
+
+/// Refract the differentials of a ray at `pt`, where `normal` faces
+/// the incoming ray and `n` is the ratio of the indices of refraction.
+/// Returns `None` if either offset ray is totally internally reflected.
+fn refract_differential(ray: &Ray, pt: &Pnt3, normal: &Vec3, n: f64) -> Option<RayDifferential> {
+    let refract = |v: &Vec3| {
+        let nd = dot(v, normal);
+        let sin2 = n * n * (1.0 - nd * nd);
+        if sin2 < 1.0 {
+            Some((*v * n - *normal * (n * nd + (1.0 - sin2).sqrt())).normalize())
+        } else {
+            None
+        }
+    };
+    match ray.differential {
+        Some(ref d) => match (refract(&d.rx_direction), refract(&d.ry_direction)) {
+            (Some(rx), Some(ry)) => Some(RayDifferential {
+                rx_origin: offset_hit(&d.rx_origin, &d.rx_direction, pt, normal),
+                rx_direction: rx,
+                ry_origin: offset_hit(&d.ry_origin, &d.ry_direction, pt, normal),
+                ry_direction: ry,
+            }),
+            _ => None,
+        },
+        None => None,
+    }
+}
+
+/// Check whether a light in direction `ldir` (with the squared
+/// range `sqrange`) lights the point `pt`. Returns the fraction of
+/// the light that arrives, which is black if the point is in shadow
+/// and is reduced by any media in between.
+fn light_visibility(scene: &Scene, pt: &Pnt3, ldir: &Vec3, sqrange: Option<f64>, time: f64, rng: &mut RngT) -> Color {
+    let shadow = Ray { origin: *pt + *ldir * 0.00001, direction: *ldir, time: time, differential: None };
+    if let Some(intersection) = scene.intersect(&shadow) {
+        if match sqrange {
+            Some(r2) => intersection.result.t * intersection.result.t < r2,
+            None => true,
+        } {
+            return color::BLACK;
+        }
+    }
+    if !scene.has_media() {
+        return Color::from_rgb(1.0, 1.0, 1.0);
+    }
+    transmittance(scene, &shadow, sqrange.map_or(f64::INFINITY, |r2| r2.sqrt()), rng)
+}
+
+/// Sample a direction in the hemisphere around `normal`, with a
+/// probability proportional to the cosine of the angle to the normal.
+fn sample_cosine_hemisphere(normal: &Vec3, rng: &mut RngT) -> Vec3 {
+    let (r1, r2) = (rng.gen::<f64>(), rng.gen::<f64>());
+    let (r, phi) = (r1.sqrt(), 2.0 * f64::consts::PI * r2);
+    let a = if normal.x.abs() > 0.9 { Vec3::new(0.0, 1.0, 0.0) } else { Vec3::new(1.0, 0.0, 0.0) };
+    let u = cross(&a, normal).normalize();
+    let v = cross(normal, &u);
+    u * (r * phi.cos()) + v * (r * phi.sin()) + *normal * (1.0 - r1).sqrt()
+}
+
+/// Compute the ambient occlusion at `pt`: the cosine weighted
+/// fraction of the hemisphere around `normal` from which no object
+/// is closer than the occlusion distance. 1 is fully unoccluded.
+pub fn ambient_occlusion(scene: &Scene, pt: &Pnt3, normal: &Vec3, time: f64, rng: &mut RngT) -> f64 {
+    let settings = &scene.options.occlusion;
+    if settings.samples == 0 {
+        return 1.0;
+    }
+    let mut open = 0;
+    for _ in 0..settings.samples {
+        let dir = sample_cosine_hemisphere(normal, rng);
+        let ray = Ray { origin: *pt + dir * 0.00001, direction: dir, time: time, differential: None };
+        match scene.intersect(&ray) {
+            Some(hit) if hit.result.t < settings.distance => {},
+            _ => open += 1,
+        }
+    }
+    open as f64 / settings.samples as f64
+}
+
+/// Get the ambient light of a material at a point, darkened by
+/// ambient occlusion if the scene asks for it.
+fn ambient_light(scene: &Scene, ambient: Color, result: &IntersectionResult, ray: &Ray, pt: &Pnt3, rng: &mut RngT) -> Color {
+    if !scene.options.occlude_ambient || ambient.significance() <= 0.0 {
+        return ambient;
+    }
+    let normal = if dot(&result.normal, &ray.direction) > 0.0 { -result.normal } else { result.normal };
+    ambient * ambient_occlusion(scene, pt, &normal, ray.time, rng)
+}
+
 impl Material for PhongMaterial {
     fn color(&self, scene: &Scene, result: &IntersectionResult, ray: &Ray, significance: f64, depth: u32, rng: &mut RngT) -> Color {
-        let mut res = self.ambient;
-        if depth > MAX_DEPTH {return res}
+        self.passes(scene, result, ray, significance, depth, rng).total()
+    }
+
+    fn passes(&self, scene: &Scene, result: &IntersectionResult, ray: &Ray, significance: f64, depth: u32, rng: &mut RngT) -> Passes {
         let pt = ray.cast(result.t);
-        let diffuse = self.diffuse.significance() * significance > MIN_SIGNIFICANCE;
-        let specular = self.specular.significance() * significance > MIN_SIGNIFICANCE;
+        let mut res = Passes { diffuse_indirect: ambient_light(scene, self.ambient.at(result, &pt), result, ray, &pt, rng), ..Passes::default() };
+        if depth > MAX_DEPTH {return res}
+        let diffuse_color = self.diffuse.at(result, &pt);
+        let specular_color = self.specular.at(result, &pt);
+        let exponent = self.exponent.at(result, &pt);
+        let diffuse = diffuse_color.significance() * significance > MIN_SIGNIFICANCE;
+        let specular = specular_color.significance() * significance > MIN_SIGNIFICANCE;
         // normal should face the viewer; if not, flip it
         let normal = if dot(&result.normal, &ray.direction) > 0.0 { -result.normal } else { result.normal };
         for light in &scene.lights {
             if diffuse || specular {
                 let (ldir, sqrange) = light.model.light_dir_and_sq_range_for(&pt, rng);
                 // check if in shadow
-                if let Some(intersection) = scene.intersect(&Ray { origin: pt + ldir * 0.00001, direction: ldir }) {
-                    if match sqrange {
-                        Some(r2) => intersection.result.t * intersection.result.t < r2,
-                        None => true,
-                    } {
-                        continue;
-                    }
+                let visibility = light_visibility(scene, &pt, &ldir, sqrange, ray.time, rng);
+                if visibility.significance() <= 0.0 {
+                    continue;
                 }
+                let light_color = light.color * visibility;
                 if diffuse {
-                    res = res + self.diffuse * light.color * clamp_zero(dot(&ldir, &normal)) * f64::consts::FRAC_1_PI;
+                    res.diffuse_direct = res.diffuse_direct + diffuse_color * light_color * clamp_zero(dot(&ldir, &normal)) * f64::consts::FRAC_1_PI;
                 }
                 if specular {
-                    res = res + self.specular * light.color * clamp_zero(dot(&normal, &((ldir - ray.direction).normalize()))).powf(self.exponent);
+                    res.specular_direct = res.specular_direct + specular_color * light_color * clamp_zero(dot(&normal, &((ldir - ray.direction).normalize()))).powf(exponent);
                 }
             }
         }
         if specular {
             let d = ray.direction;
             let rd = d - normal * (2.0 * dot(&d, &normal));
-            let reflect = Ray { origin: pt + rd * 0.00001, direction: rd };
-            res = res + self.specular * ray_color(scene, &reflect, significance * self.specular.significance(), depth + 1, rng);
+            let reflect = Ray { origin: pt + rd * 0.00001, direction: rd, time: ray.time, differential: reflect_differential(ray, &pt, &normal) };
+            res.specular_indirect = res.specular_indirect + specular_color * ray_color(scene, &reflect, significance * specular_color.significance(), depth + 1, rng);
         }
         res
     }
+
+    fn albedo(&self, result: &IntersectionResult, pt: &Pnt3) -> Color {
+        self.diffuse.at(result, pt)
+    }
 }
 
 impl Material for IndirectPhongMaterial {
     fn color(&self, scene: &Scene, result: &IntersectionResult, ray: &Ray, significance: f64, depth: u32, rng: &mut RngT) -> Color {
-        let mut res = self.ambient;
-        if depth > MAX_DEPTH {return res}
+        self.passes(scene, result, ray, significance, depth, rng).total()
+    }
+
+    fn passes(&self, scene: &Scene, result: &IntersectionResult, ray: &Ray, significance: f64, depth: u32, rng: &mut RngT) -> Passes {
         let pt = ray.cast(result.t);
-        let diffuse = self.diffuse.significance() * significance > MIN_SIGNIFICANCE;
-        let specular = self.specular.significance() * significance > MIN_SIGNIFICANCE;
+        let mut res = Passes { diffuse_indirect: ambient_light(scene, self.ambient.at(result, &pt), result, ray, &pt, rng), ..Passes::default() };
+        if depth > MAX_DEPTH {return res}
+        let diffuse_color = self.diffuse.at(result, &pt);
+        let specular_color = self.specular.at(result, &pt);
+        let exponent = self.exponent.at(result, &pt);
+        let diffuse = diffuse_color.significance() * significance > MIN_SIGNIFICANCE;
+        let specular = specular_color.significance() * significance > MIN_SIGNIFICANCE;
         // normal should face the viewer; if not, flip it
         let normal = if dot(&result.normal, &ray.direction) > 0.0 { -result.normal } else { result.normal };
         if diffuse || specular {
@@ -80,19 +204,16 @@ impl Material for IndirectPhongMaterial {
             for light in &scene.lights {
                 let (ldir, sqrange) = light.model.light_dir_and_sq_range_for(&pt, rng);
                 // check if in shadow
-                if let Some(intersection) = scene.intersect(&Ray { origin: pt + ldir * 0.00001, direction: ldir }) {
-                    if match sqrange {
-                        Some(r2) => intersection.result.t * intersection.result.t < r2,
-                        None => true,
-                    } {
-                        continue;
-                    }
+                let visibility = light_visibility(scene, &pt, &ldir, sqrange, ray.time, rng);
+                if visibility.significance() <= 0.0 {
+                    continue;
                 }
+                let light_color = light.color * visibility;
                 if diffuse {
-                    res = res + self.diffuse * light.color * clamp_zero(dot(&ldir, &normal)) * f64::consts::FRAC_1_PI;
+                    res.diffuse_direct = res.diffuse_direct + diffuse_color * light_color * clamp_zero(dot(&ldir, &normal)) * f64::consts::FRAC_1_PI;
                 }
                 if specular {
-                    res = res + self.specular * light.color * clamp_zero(dot(&normal, &((ldir - ray.direction).normalize()))).powf(self.exponent);
+                    res.specular_direct = res.specular_direct + specular_color * light_color * clamp_zero(dot(&normal, &((ldir - ray.direction).normalize()))).powf(exponent);
                 }
             }
             // indirect lighting
@@ -105,26 +226,37 @@ impl Material for IndirectPhongMaterial {
                 let x = sin_theta * phi.cos();
                 let z = sin_theta * phi.sin();
                 let dir = { let d = Vec3::new(x, r1, z); if dot(&d, &normal) >= 0.0 {d} else {-d} };
-                let ray = Ray { origin: pt + dir * 0.00001, direction: dir };
+                let ray = Ray { origin: pt + dir * 0.00001, direction: dir, time: ray.time, differential: None };
                 let color = ray_color(scene, &ray, significance, depth + 1, rng);
                 let fac = self.samples as f64 * 0.5;
                 if diffuse {
-                    res = res + self.diffuse * color * dot(&normal, &dir) / fac;
+                    res.diffuse_indirect = res.diffuse_indirect + diffuse_color * color * dot(&normal, &dir) / fac;
                 }
                 if specular {
-                    res = res + self.specular * color * clamp_zero(dot(&normal, &((dir - ray.direction).normalize()))).powf(self.exponent) / fac;
+                    res.specular_indirect = res.specular_indirect + specular_color * color * clamp_zero(dot(&normal, &((dir - ray.direction).normalize()))).powf(exponent) / fac;
                 }
             }
         }
         res
     }
+
+    fn albedo(&self, result: &IntersectionResult, pt: &Pnt3) -> Color {
+        self.diffuse.at(result, pt)
+    }
 }
 
 impl Material for FresnelMaterial {
     fn color(&self, scene: &Scene, result: &IntersectionResult, ray: &Ray, significance: f64, depth: u32, rng: &mut RngT) -> Color {
-        let mut res = self.ambient;
-        if depth > MAX_DEPTH {return res}
+        self.passes(scene, result, ray, significance, depth, rng).total()
+    }
+
+    fn passes(&self, scene: &Scene, result: &IntersectionResult, ray: &Ray, significance: f64, depth: u32, rng: &mut RngT) -> Passes {
         let pt = ray.cast(result.t);
+        let mut res = Passes { diffuse_indirect: ambient_light(scene, self.ambient.at(result, &pt), result, ray, &pt, rng), ..Passes::default() };
+        if depth > MAX_DEPTH {return res}
+        let diffuse_color = self.diffuse.at(result, &pt);
+        let specular_color = self.specular.at(result, &pt);
+        let exponent = self.exponent.at(result, &pt);
         let nd = dot(&result.normal, &ray.direction);
         // normal should face the viewer; if not, flip it
         let normal = if nd > 0.0 { -result.normal } else { result.normal };
@@ -134,43 +266,50 @@ impl Material for FresnelMaterial {
         let omcos = 1.0 - nd.abs();
         let omcos2 = omcos * omcos;
         let fresnel = clamp_one(r0 + (1.0 - r0) * omcos2 * omcos2 * omcos);
-        let diffuse = self.diffuse.significance() * significance > MIN_SIGNIFICANCE;
-        let specular = self.specular.significance() * fresnel * significance > MIN_SIGNIFICANCE;
+        let diffuse = diffuse_color.significance() * significance > MIN_SIGNIFICANCE;
+        let specular = specular_color.significance() * fresnel * significance > MIN_SIGNIFICANCE;
         for light in &scene.lights {
             if diffuse || specular {
                 let (ldir, sqrange) = light.model.light_dir_and_sq_range_for(&pt, rng);
                 // check if in shadow
-                if let Some(intersection) = scene.intersect(&Ray { origin: pt + ldir * 0.00001, direction: ldir }) {
-                    if match sqrange {
-                        Some(r2) => intersection.result.t * intersection.result.t < r2,
-                        None => true,
-                    } {
-                        continue;
-                    }
+                let visibility = light_visibility(scene, &pt, &ldir, sqrange, ray.time, rng);
+                if visibility.significance() <= 0.0 {
+                    continue;
                 }
+                let light_color = light.color * visibility;
                 if diffuse {
-                    res = res + self.diffuse * light.color * clamp_zero(dot(&ldir, &normal)) * f64::consts::FRAC_1_PI;
+                    res.diffuse_direct = res.diffuse_direct + diffuse_color * light_color * clamp_zero(dot(&ldir, &normal)) * f64::consts::FRAC_1_PI;
                 }
                 if specular {
-                    res = res + self.specular * light.color * fresnel * clamp_zero(dot(&normal, &((ldir - ray.direction).normalize()))).powf(self.exponent);
+                    res.specular_direct = res.specular_direct + specular_color * light_color * fresnel * clamp_zero(dot(&normal, &((ldir - ray.direction).normalize()))).powf(exponent);
                 }
             }
         }
         if specular {
             let d = ray.direction;
             let rd = d - normal * (2.0 * dot(&d, &normal));
-            let reflect = Ray { origin: pt + rd * 0.00001, direction: rd };
-            res = res + self.specular * ray_color(scene, &reflect, fresnel * significance * self.specular.significance(), depth + 1, rng) * fresnel;
+            let reflect = Ray { origin: pt + rd * 0.00001, direction: rd, time: ray.time, differential: reflect_differential(ray, &pt, &normal) };
+            res.specular_indirect = res.specular_indirect + specular_color * ray_color(scene, &reflect, fresnel * significance * specular_color.significance(), depth + 1, rng) * fresnel;
         }
         res
     }
+
+    fn albedo(&self, result: &IntersectionResult, pt: &Pnt3) -> Color {
+        self.diffuse.at(result, pt)
+    }
 }
 
 impl Material for TransparentMaterial {
     fn color(&self, scene: &Scene, result: &IntersectionResult, ray: &Ray, significance: f64, depth: u32, rng: &mut RngT) -> Color {
-        let mut res = color::BLACK;
+        self.passes(scene, result, ray, significance, depth, rng).total()
+    }
+
+    fn passes(&self, scene: &Scene, result: &IntersectionResult, ray: &Ray, significance: f64, depth: u32, rng: &mut RngT) -> Passes {
+        let mut res = Passes::default();
         if depth > MAX_DEPTH {return res}
         let pt = ray.cast(result.t);
+        let specular_color = self.specular.at(result, &pt);
+        let exponent = self.exponent.at(result, &pt);
         let nd = dot(&result.normal, &ray.direction);
         // normal should face the viewer; if not, flip it
         let normal = if nd > 0.0 { -result.normal } else { result.normal };
@@ -190,26 +329,23 @@ impl Material for TransparentMaterial {
         let omcos = if nd > 0.0 { if let Some(r) = refract { 1.0 - dot(&normal, &r) } else { 0.0 } } else { 1.0 - nd.abs() };
         let omcos2 = omcos * omcos;
         let fresnel = if refract.is_some() { clamp_one(r0 + (1.0 - r0) * omcos2 * omcos2 * omcos) } else { 1.0 };
-        let specular = self.specular.significance() * fresnel * significance > MIN_SIGNIFICANCE;
+        let specular = specular_color.significance() * fresnel * significance > MIN_SIGNIFICANCE;
         for light in &scene.lights {
             if specular {
                 let (ldir, sqrange) = light.model.light_dir_and_sq_range_for(&pt, rng);
                 // check if in shadow
-                if let Some(intersection) = scene.intersect(&Ray { origin: pt + ldir * 0.00001, direction: ldir }) {
-                    if match sqrange {
-                        Some(r2) => intersection.result.t * intersection.result.t < r2,
-                        None => true,
-                    } {
-                        continue;
-                    }
+                let visibility = light_visibility(scene, &pt, &ldir, sqrange, ray.time, rng);
+                if visibility.significance() <= 0.0 {
+                    continue;
                 }
-                res = res + self.specular * light.color * fresnel * clamp_zero(dot(&normal, &((ldir - ray.direction).normalize()))).powf(self.exponent);
+                let light_color = light.color * visibility;
+                res.specular_direct = res.specular_direct + specular_color * light_color * fresnel * clamp_zero(dot(&normal, &((ldir - ray.direction).normalize()))).powf(exponent);
             }
         }
         if specular {
             let rd = ray.direction - normal * (2.0 * ndv);
-            let reflect = Ray { origin: pt + rd * 0.00001, direction: rd };
-            res = res + self.specular * ray_color(scene, &reflect, fresnel * significance * self.specular.significance(), depth + 1, rng) * fresnel;
+            let reflect = Ray { origin: pt + rd * 0.00001, direction: rd, time: ray.time, differential: reflect_differential(ray, &pt, &normal) };
+            res.specular_indirect = res.specular_indirect + specular_color * ray_color(scene, &reflect, fresnel * significance * specular_color.significance(), depth + 1, rng) * fresnel;
         }
         if fresnel < 1.0 {
             match refract {
@@ -217,12 +353,121 @@ impl Material for TransparentMaterial {
                 Some(refract) => {
                     let omf = clamp_one(1.0 - fresnel);
                     let refract = refract.normalize();
-                    res = res + ray_color(scene, &Ray { origin: pt + refract * 0.00001, direction: refract }, omf * significance, depth + 1, rng) * omf;
+                    let differential = refract_differential(ray, &pt, &normal, n);
+                    res.specular_indirect = res.specular_indirect + ray_color(scene, &Ray { origin: pt + refract * 0.00001, direction: refract, time: ray.time, differential: differential }, omf * significance, depth + 1, rng) * omf;
                 }
             }
         }
         res
     }
+
+    fn albedo(&self, result: &IntersectionResult, pt: &Pnt3) -> Color {
+        self.specular.at(result, pt)
+    }
+}
+
+/// Step in texture coordinates used to differentiate height maps.
+const BUMP_DELTA: f64 = 0.001;
+
+impl BumpMappedMaterial {
+    /// Perturb the normal of an intersection result with the height
+    /// map.
+    fn bumped_result(&self, result: &IntersectionResult, pt: &Pnt3) -> IntersectionResult {
+        let pt = *pt;
+        // evaluate the height at points shifted along u and v
+        let shifted = |du: f64, dv: f64| {
+            let r = IntersectionResult {
+                t: result.t,
+                normal: result.normal,
+                uv: Pnt2::new(result.uv.x + du, result.uv.y + dv),
+                dpdu: result.dpdu,
+                dpdv: result.dpdv,
+                duvdx: result.duvdx,
+                duvdy: result.duvdy,
+            };
+            self.height.at(&r, &(pt + result.dpdu * du + result.dpdv * dv)) * self.strength
+        };
+        let h = shifted(0.0, 0.0);
+        let dhdu = (shifted(BUMP_DELTA, 0.0) - h) / BUMP_DELTA;
+        let dhdv = (shifted(0.0, BUMP_DELTA) - h) / BUMP_DELTA;
+        let n = result.normal.normalize();
+        let dpdu = result.dpdu + n * dhdu;
+        let dpdv = result.dpdv + n * dhdv;
+        let bumped = cross(&dpdu, &dpdv).normalize();
+        // keep the normal on the same side as the geometric normal
+        let bumped = if dot(&bumped, &n) < 0.0 { -bumped } else { bumped };
+        IntersectionResult {
+            t: result.t,
+            normal: if bumped.x.is_nan() { n } else { bumped },
+            uv: result.uv,
+            dpdu: dpdu,
+            dpdv: dpdv,
+            duvdx: result.duvdx,
+            duvdy: result.duvdy,
+        }
+    }
+}
+
+impl Material for BumpMappedMaterial {
+    fn color(&self, scene: &Scene, result: &IntersectionResult, ray: &Ray, significance: f64, depth: u32, rng: &mut RngT) -> Color {
+        self.material.color(scene, &self.bumped_result(result, &ray.cast(result.t)), ray, significance, depth, rng)
+    }
+
+    fn passes(&self, scene: &Scene, result: &IntersectionResult, ray: &Ray, significance: f64, depth: u32, rng: &mut RngT) -> Passes {
+        self.material.passes(scene, &self.bumped_result(result, &ray.cast(result.t)), ray, significance, depth, rng)
+    }
+
+    fn albedo(&self, result: &IntersectionResult, pt: &Pnt3) -> Color {
+        self.material.albedo(&self.bumped_result(result, pt), pt)
+    }
+
+    fn shading_normal(&self, result: &IntersectionResult, pt: &Pnt3) -> Vec3 {
+        self.material.shading_normal(&self.bumped_result(result, pt), pt)
+    }
+}
+
+#[cfg(feature = "textures")]
+impl NormalMappedMaterial {
+    /// Replace the normal of an intersection result with the one from
+    /// the normal map.
+    fn mapped_result(&self, result: &IntersectionResult) -> IntersectionResult {
+        let c = self.normal_map.sample(result);
+        let local = Vec3::new((c.r * 2.0 - 1.0) * self.strength, (c.g * 2.0 - 1.0) * self.strength, c.b * 2.0 - 1.0);
+        // orthonormal tangent frame around the geometric normal
+        let n = result.normal.normalize();
+        let t = (result.dpdu - n * dot(&result.dpdu, &n)).normalize();
+        let b = cross(&n, &t);
+        let b = if dot(&b, &result.dpdv) < 0.0 { -b } else { b };
+        let mapped = (t * local.x + b * local.y + n * local.z).normalize();
+        IntersectionResult {
+            t: result.t,
+            normal: if mapped.x.is_nan() { n } else { mapped },
+            uv: result.uv,
+            dpdu: result.dpdu,
+            dpdv: result.dpdv,
+            duvdx: result.duvdx,
+            duvdy: result.duvdy,
+        }
+    }
+}
+
+#[cfg(feature = "textures")]
+impl Material for NormalMappedMaterial {
+    fn color(&self, scene: &Scene, result: &IntersectionResult, ray: &Ray, significance: f64, depth: u32, rng: &mut RngT) -> Color {
+        self.material.color(scene, &self.mapped_result(result), ray, significance, depth, rng)
+    }
+
+    fn passes(&self, scene: &Scene, result: &IntersectionResult, ray: &Ray, significance: f64, depth: u32, rng: &mut RngT) -> Passes {
+        self.material.passes(scene, &self.mapped_result(result), ray, significance, depth, rng)
+    }
+
+    fn albedo(&self, result: &IntersectionResult, pt: &Pnt3) -> Color {
+        self.material.albedo(&self.mapped_result(result), pt)
+    }
+
+    fn shading_normal(&self, result: &IntersectionResult, pt: &Pnt3) -> Vec3 {
+        self.material.shading_normal(&self.mapped_result(result), pt)
+    }
 }
 
 impl Background for SolidColorBackground {
@@ -231,46 +476,384 @@ impl Background for SolidColorBackground {
     }
 }
 
+/// Find the face of a skybox that a direction points at. Returns
+/// the major axis (0 for x, 1 for y, 2 for z).
 #[cfg(feature = "skybox")]
-macro_rules! skybox_axis {
-    ($self_:ident, $rayd:expr, $namep:ident, $namen:ident, $dir:ident, $otherx:ident, $othery:ident, $px:expr, $py:expr) => {
-        if $rayd.$dir.abs() > $rayd.$otherx.abs() && $rayd.$dir.abs() > $rayd.$othery.abs() {
-            if $rayd.$dir > 0.0 {
-                return $self_.$namep.sample($px * 0.5 + 0.5, $py * 0.5 + 0.5);
-            } else {
-                return $self_.$namen.sample($px * 0.5 + 0.5, $py * 0.5 + 0.5);
-            }
-        }
-    };
+fn skybox_axis(d: &Vec3) -> usize {
+    let (x, y, z) = (d.x.abs(), d.y.abs(), d.z.abs());
+    if x >= y && x >= z { 0 } else if y >= z { 1 } else { 2 }
+}
+
+/// Get the position of a direction on the face of the skybox along
+/// the given axis, in the range [-1, 1].
+#[cfg(feature = "skybox")]
+fn skybox_coords(d: &Vec3, axis: usize) -> (f64, f64) {
+    match axis {
+        0 => (-d.z / d.x, -d.y / d.x.abs()),
+        1 => (d.x / d.y.abs(), d.z / d.y),
+        _ => (d.x / d.z, -d.y / d.z.abs()),
+    }
 }
 
 #[cfg(feature = "skybox")]
 impl Background for SkyboxBackground {
     fn color(&self, ray: &Ray, _: &mut RngT) -> Color {
         let d = ray.direction;
-        skybox_axis!(self, d, px, nx, x, z, y, -d.z / d.x, -d.y / d.x.abs());
-        skybox_axis!(self, d, py, ny, y, x, z, d.x / d.y.abs(), d.z / d.y);
-        skybox_axis!(self, d, pz, nz, z, x, y, d.x / d.z, -d.y / d.z.abs());
-        color::BLACK
+        let axis = skybox_axis(&d);
+        let face = match (axis, [d.x, d.y, d.z][axis] > 0.0) {
+            (0, true) => &self.px,
+            (0, false) => &self.nx,
+            (1, true) => &self.py,
+            (1, false) => &self.ny,
+            (_, true) => &self.pz,
+            (_, false) => &self.nz,
+        };
+        let (px, py) = skybox_coords(&d, axis);
+        // the footprint is the distance to the neighboring rays on the
+        // same face; the face coordinates are halved when sampling
+        let width = match ray.differential {
+            Some(ref diff) => {
+                let (qx, qy) = skybox_coords(&diff.rx_direction, axis);
+                let (rx, ry) = skybox_coords(&diff.ry_direction, axis);
+                let w = (qx - px).abs().max((qy - py).abs()).max((rx - px).abs()).max((ry - py).abs()) * 0.5;
+                if w.is_finite() { w } else { 0.0 }
+            },
+            None => 0.0,
+        };
+        face.sample_filtered(px * 0.5 + 0.5, py * 0.5 + 0.5, width)
+    }
+}
+
+#[inline]
+fn color_exp(c: Color) -> Color {
+    Color::from_rgb(c.r.exp(), c.g.exp(), c.b.exp())
+}
+
+/// Multiply the coefficients of a medium by the length of a segment.
+/// Channels with a coefficient of 0 stay 0 on an infinite segment,
+/// which would otherwise give NaN.
+fn optical_depth(coefficient: Color, length: f64) -> Color {
+    let depth = |c: f64| if c == 0.0 { 0.0 } else { c * length };
+    Color::from_rgb(depth(coefficient.r), depth(coefficient.g), depth(coefficient.b))
+}
+
+/// Find the intervals of `t` in [0, `tmax`] where a ray is inside a
+/// volume.
+fn volume_segments(volume: &Volume, ray: &Ray, tmax: f64) -> Vec<(f64, f64)> {
+    match volume.bounds {
+        Some(ref shape) => shape.intervals(ray).into_iter()
+            .map(|i| (i.enter.t.max(0.0), i.exit.t.min(tmax)))
+            .filter(|&(a, b)| a < b)
+            .collect(),
+        None => vec![(0.0, tmax)],
+    }
+}
+
+/// Find the interval of `t` in [0, `tmax`] where a ray is inside the
+/// box of a grid volume.
+fn grid_interval(volume: &GridVolume, ray: &Ray, tmax: f64) -> Option<(f64, f64)> {
+    let (mut t0, mut t1) = (0.0, tmax);
+    let o = [ray.origin.x, ray.origin.y, ray.origin.z];
+    let d = [ray.direction.x, ray.direction.y, ray.direction.z];
+    let lo = [volume.min.x, volume.min.y, volume.min.z];
+    let hi = [volume.max.x, volume.max.y, volume.max.z];
+    for i in 0..3 {
+        let inv = 1.0 / d[i];
+        let (a, b) = ((lo[i] - o[i]) * inv, (hi[i] - o[i]) * inv);
+        let (a, b) = if a < b { (a, b) } else { (b, a) };
+        // a NaN (parallel ray on the slab boundary) leaves the interval
+        if a > t0 { t0 = a; }
+        if b < t1 { t1 = b; }
+        if t0 > t1 {
+            return None;
+        }
+    }
+    Some((t0, t1))
+}
+
+/// Get the density of a grid volume at a point in world space.
+#[inline]
+fn grid_density(volume: &GridVolume, pt: &Pnt3) -> f64 {
+    let size = volume.max - volume.min;
+    let local = *pt - volume.min;
+    volume.grid.density(&Pnt3::new(local.x / size.x, local.y / size.y, local.z / size.z)) * volume.density_scale
+}
+
+/// Sample the tentative collisions of a ray with a grid volume in
+/// [0, `tmax`], using the largest density of the grid as the
+/// majorant. `f` is called with each collision and the ratio of the
+/// density there to the majorant, and returns whether to continue.
+fn track_grid<F>(volume: &GridVolume, ray: &Ray, tmax: f64, rng: &mut RngT, mut f: F) where F: FnMut(f64, f64, &mut RngT) -> bool {
+    let majorant = volume.grid.max() * volume.density_scale;
+    if !(majorant > 0.0) {
+        return;
+    }
+    if let Some((a, b)) = grid_interval(volume, ray, tmax) {
+        let rate = majorant * ray.direction.norm();
+        let mut t = a;
+        loop {
+            t = t - (1.0 - rng.gen::<f64>()).ln() / rate;
+            if t >= b || !f(t, grid_density(volume, &ray.cast(t)) / majorant, rng) {
+                return;
+            }
+        }
+    }
+}
+
+/// Get the fraction of light that passes through the media along a
+/// ray, up to `tmax`. Homogeneous media are computed exactly, and
+/// grid volumes are estimated with ratio tracking.
+fn transmittance(scene: &Scene, ray: &Ray, tmax: f64, rng: &mut RngT) -> Color {
+    let scale = ray.direction.norm();
+    let mut depth = color::BLACK;
+    for volume in &scene.volumes {
+        let extinction = volume.medium.absorption + volume.medium.scattering;
+        for (a, b) in volume_segments(volume, ray, tmax) {
+            depth = depth + optical_depth(extinction, (b - a) * scale);
+        }
+    }
+    let mut ratio = 1.0;
+    for volume in &scene.grid_volumes {
+        track_grid(volume, ray, tmax, rng, |_, density, _| {
+            ratio = ratio * (1.0 - density);
+            ratio > 0.0
+        });
+    }
+    color_exp(depth * -1.0) * ratio
+}
+
+/// The result of sampling the media along a ray.
+enum MediumSample {
+    /// The ray passes through the media, and the light coming from
+    /// the end of the ray is weighted.
+    Pass(Color),
+    /// The ray is scattered at `t` by a medium with the given phase
+    /// function asymmetry, and the light scattered there is weighted.
+    Scatter(f64, f64, Color),
+}
+
+/// Sample the distance that a ray travels through the media before
+/// it is scattered (free-flight sampling), up to `tmax`. Each
+/// homogeneous volume is sampled with the average of its extinction,
+/// and the estimate is weighted to account for the different colors.
+/// Grid volumes are sampled with delta tracking.
+fn sample_media(scene: &Scene, ray: &Ray, tmax: f64, rng: &mut RngT) -> MediumSample {
+    let scale = ray.direction.norm();
+    let segments: Vec<_> = scene.volumes.iter().map(|v| volume_segments(v, ray, tmax)).collect();
+    // find the closest collision with any of the volumes, along with
+    // the asymmetry and the weight of the light scattered there
+    let mut collision: Option<(f64, f64, Color)> = None;
+    for (i, volume) in scene.volumes.iter().enumerate() {
+        let extinction = volume.medium.absorption + volume.medium.scattering;
+        let rate = extinction.significance() / 3.0;
+        if rate <= 0.0 {
+            continue;
+        }
+        let mut distance = -(1.0 - rng.gen::<f64>()).ln() / rate;
+        for &(a, b) in &segments[i] {
+            let length = (b - a) * scale;
+            if distance < length {
+                let t = a + distance / scale;
+                if collision.map_or(true, |(ct, _, _)| t < ct) {
+                    collision = Some((t, volume.medium.asymmetry, volume.medium.scattering / rate));
+                }
+                break;
+            }
+            distance = distance - length;
+        }
+    }
+    for volume in &scene.grid_volumes {
+        let limit = collision.map_or(tmax, |(t, _, _)| t);
+        track_grid(volume, ray, limit, rng, |t, density, rng| {
+            if rng.gen::<f64>() < density {
+                collision = Some((t, volume.asymmetry, volume.albedo));
+                false
+            } else {
+                true
+            }
+        });
+    }
+    // weight by the ratio of the transmittance to the probability
+    // of travelling to the end point; for grid volumes they are equal
+    let end = collision.map_or(tmax, |(t, _, _)| t);
+    let mut weight = Color::from_rgb(1.0, 1.0, 1.0);
+    for (i, volume) in scene.volumes.iter().enumerate() {
+        let extinction = volume.medium.absorption + volume.medium.scattering;
+        let rate = extinction.significance() / 3.0;
+        if rate <= 0.0 {
+            continue;
+        }
+        for &(a, b) in &segments[i] {
+            if a >= end {
+                break;
+            }
+            let length = (b.min(end) - a) * scale;
+            weight = weight * color_exp(optical_depth(extinction - Color::from_rgb(rate, rate, rate), length) * -1.0);
+        }
+    }
+    match collision {
+        Some((t, asymmetry, scatter)) => MediumSample::Scatter(t, asymmetry, weight * scatter),
+        None => MediumSample::Pass(weight),
     }
 }
 
+/// The Henyey-Greenstein phase function, where `cos` is the cosine
+/// of the angle between the incoming and outgoing directions.
+#[inline]
+fn henyey_greenstein(g: f64, cos: f64) -> f64 {
+    let denom = 1.0 + g * g - 2.0 * g * cos;
+    (1.0 - g * g) / (4.0 * f64::consts::PI * denom * denom.sqrt())
+}
+
+/// Sample a direction scattered from `d` according to the
+/// Henyey-Greenstein phase function.
+fn sample_henyey_greenstein(g: f64, d: &Vec3, rng: &mut RngT) -> Vec3 {
+    let (r1, r2) = (rng.gen::<f64>(), rng.gen::<f64>());
+    let cos = if g.abs() < 1e-3 {
+        1.0 - 2.0 * r1
+    } else {
+        let sq = (1.0 - g * g) / (1.0 - g + 2.0 * g * r1);
+        (1.0 + g * g - sq * sq) / (2.0 * g)
+    };
+    let sin = clamp_zero(1.0 - cos * cos).sqrt();
+    let phi = 2.0 * f64::consts::PI * r2;
+    let w = d.normalize();
+    let a = if w.x.abs() > 0.9 { Vec3::new(0.0, 1.0, 0.0) } else { Vec3::new(1.0, 0.0, 0.0) };
+    let u = cross(&a, &w).normalize();
+    let v = cross(&w, &u);
+    u * (sin * phi.cos()) + v * (sin * phi.sin()) + w * cos
+}
+
+/// Compute the light scattered towards the origin of a ray by a
+/// medium at `pt`.
+fn medium_color(scene: &Scene, asymmetry: f64, ray: &Ray, pt: &Pnt3, significance: f64, depth: u32, rng: &mut RngT) -> Color {
+    let mut res = color::BLACK;
+    let d = ray.direction.normalize();
+    for light in &scene.lights {
+        let (ldir, sqrange) = light.model.light_dir_and_sq_range_for(pt, rng);
+        let visibility = light_visibility(scene, pt, &ldir, sqrange, ray.time, rng);
+        if visibility.significance() > 0.0 {
+            res = res + light.color * visibility * henyey_greenstein(asymmetry, dot(&ldir, &d));
+        }
+    }
+    if scene.options.multiple_scattering && depth <= MAX_DEPTH && significance > MIN_SIGNIFICANCE {
+        // the phase function is sampled exactly, so the weight is 1
+        let dir = sample_henyey_greenstein(asymmetry, &d, rng);
+        let scattered = Ray { origin: *pt, direction: dir, time: ray.time, differential: None };
+        res = res + ray_color(scene, &scattered, significance, depth + 1, rng);
+    }
+    res
+}
+
 /// Trace a ray to an object or nothing and return the result of
 /// color computation. Significance is a float that is decreased
 /// when a ray is generated recursively.
 pub fn ray_color(scene: &Scene, ray: &Ray, significance: f64, depth: u32, rng: &mut RngT) -> Color {
-    // find the object that the ray hits and compute the color
-    match scene.intersect(ray) {
-        Some(result) => result.object.material.color(scene, &result.result, ray, significance, depth, rng),
-        None => scene.background.color(ray, rng),
+    trace(scene, ray, significance, depth, false, rng).0
+}
+
+/// Trace a ray like `ray_color`. If `want_surface` is true, also
+/// return what the ray sees at the surface it hits, unless it is
+/// scattered by a medium before it gets there.
+fn trace(scene: &Scene, ray: &Ray, significance: f64, depth: u32, want_surface: bool, rng: &mut RngT) -> (Color, Option<SurfaceSample>) {
+    // find the object that the ray hits
+    let hit = scene.intersect(ray);
+    // the ray may be scattered by media before it reaches the object
+    let weight = if !scene.has_media() {
+        Color::from_rgb(1.0, 1.0, 1.0)
+    } else {
+        let tmax = hit.as_ref().map_or(f64::INFINITY, |h| h.result.t);
+        match sample_media(scene, ray, tmax, rng) {
+            MediumSample::Scatter(t, asymmetry, weight) => {
+                let pt = ray.cast(t);
+                return (medium_color(scene, asymmetry, ray, &pt, significance * weight.significance() / 3.0, depth, rng) * weight, None);
+            },
+            MediumSample::Pass(weight) => weight,
+        }
+    };
+    // compute the color
+    match hit {
+        Some(mut hit) => {
+            hit.result.compute_uv_differentials(ray);
+            let material = &hit.object.material;
+            if !want_surface {
+                return (material.color(scene, &hit.result, ray, significance, depth, rng) * weight, None);
+            }
+            let passes = material.passes(scene, &hit.result, ray, significance, depth, rng) * weight;
+            let pt = ray.cast(hit.result.t);
+            let surface = SurfaceSample {
+                depth: hit.result.t * ray.direction.norm(),
+                position: pt,
+                normal: material.shading_normal(&hit.result, &pt).normalize(),
+                albedo: material.albedo(&hit.result, &pt),
+                object_id: hit.index as u32 + 1,
+                material_id: hit.object.material_id,
+                passes: passes,
+            };
+            (passes.total(), Some(surface))
+        },
+        None => (scene.background.color(ray, rng) * weight, None),
     }
 }
 
+/// Trace a ray and return the ambient occlusion where it hits, as a
+/// gray color. Rays that hit nothing are white.
+fn occlusion_color(scene: &Scene, ray: &Ray, rng: &mut RngT) -> Color {
+    let ao = match scene.intersect(ray) {
+        Some(hit) => {
+            let pt = ray.cast(hit.result.t);
+            let n = hit.result.normal;
+            let normal = if dot(&n, &ray.direction) > 0.0 { -n } else { n };
+            ambient_occlusion(scene, &pt, &normal, ray.time, rng)
+        },
+        None => 1.0,
+    };
+    Color::from_rgb(ao, ao, ao)
+}
+
 /// Project the position onto the scene and trace the ray.
 pub fn raytrace(scene: &Scene, pos: &Pnt2, significance: f64, rng: &mut RngT) -> Color {
     let mut res = color::BLACK;
+    let pixel = scene.pixel_size();
     for _ in 0..scene.camera.samples() {
-        res = res + ray_color(scene, &scene.camera.project(pos, rng), significance, 0, rng);
+        if let Some(ray) = scene.camera.project_differential(pos, &pixel, &scene.options.shutter, rng) {
+            res = res + match scene.options.mode {
+                RenderMode::Shaded => ray_color(scene, &ray, significance, 0, rng),
+                RenderMode::AmbientOcclusion => occlusion_color(scene, &ray, rng),
+            };
+        }
     }
     res / scene.camera.samples() as f64
 }
+
+/// Project the position onto the scene and trace the ray like
+/// `raytrace`, and also return what it sees at the first surface it
+/// hits. When the camera takes several samples, the lighting passes
+/// are averaged, and the rest is taken from the first sample that
+/// hits a surface. In ambient occlusion mode, there is no surface.
+pub fn raytrace_surface(scene: &Scene, pos: &Pnt2, significance: f64, rng: &mut RngT) -> (Color, Option<SurfaceSample>) {
+    if let RenderMode::AmbientOcclusion = scene.options.mode {
+        return (raytrace(scene, pos, significance, rng), None);
+    }
+    let mut res = color::BLACK;
+    let mut passes = Passes::default();
+    let mut surface: Option<SurfaceSample> = None;
+    let pixel = scene.pixel_size();
+    let samples = scene.camera.samples();
+    for _ in 0..samples {
+        if let Some(ray) = scene.camera.project_differential(pos, &pixel, &scene.options.shutter, rng) {
+            let (c, s) = trace(scene, &ray, significance, 0, true, rng);
+            res = res + c;
+            if let Some(s) = s {
+                passes = passes + s.passes;
+                if surface.is_none() {
+                    surface = Some(s);
+                }
+            }
+        }
+    }
+    if let Some(ref mut s) = surface {
+        s.passes = passes / samples as f64;
+    }
+    (res / samples as f64, surface)
+}
@@ -0,0 +1,210 @@
+//! Rendering of pixels
+//!
+//! This module decides how many rays are traced for each pixel of
+//! the image, and adds them to the film and to the films of the
+//! output variables. Images can be rendered a pixel at a time, or
+//! progressively in passes that each add a few samples to every
+//! pixel, so that the image can be saved while it is rendered.
+
+use std::io;
+use std::io::prelude::*;
+use std::u32;
+
+use types::*;
+use color::Color;
+use film::Film;
+use aov::{Aov, AovFilm};
+use raytrace;
+use checkpoint;
+use scene::Scene;
+
+/// Running statistics of the samples of a pixel.
+struct PixelStats {
+    count: u32,
+    // sums of the luminance and its square, for the variance
+    lum_sum: f64,
+    lum_sq_sum: f64,
+}
+
+impl PixelStats {
+    fn new() -> PixelStats {
+        PixelStats { count: 0, lum_sum: 0.0, lum_sq_sum: 0.0 }
+    }
+
+    fn add(&mut self, c: Color) {
+        let l = c.luminance();
+        self.count += 1;
+        self.lum_sum += l;
+        self.lum_sq_sum += l * l;
+    }
+
+    /// The standard error of the mean luminance, relative to the mean.
+    /// Dark pixels are compared to the darkest visible level instead,
+    /// so that noise that can't be seen does not need more samples.
+    fn relative_error(&self) -> f64 {
+        let n = self.count as f64;
+        let mean = self.lum_sum / n;
+        let variance = (self.lum_sq_sum / n - mean * mean).max(0.0) * n / (n - 1.0);
+        (variance / n).sqrt() / mean.max(1.0 / 256.0)
+    }
+}
+
+/// Trace one ray through a random position in the pixel at `(x, y)`
+/// and add it to the films.
+fn sample_pixel(scene: &Scene, film: &mut Film, aovs: &mut AovFilm, x: u32, y: u32, rng: &mut RngT) -> Color {
+    let (dx, dy) = rng.get_2d();
+    let (fx, fy) = (x as f64 + dx, y as f64 + dy);
+    let pos = scene.image_position(fx, fy);
+    let filter = &*scene.options.filter;
+    let c = if aovs.is_empty() {
+        raytrace::raytrace(scene, &pos, 1.0, rng)
+    } else {
+        let (c, surface) = raytrace::raytrace_surface(scene, &pos, 1.0, rng);
+        aovs.add_sample(filter, fx, fy, x, y, &surface);
+        c
+    };
+    film.add_sample(filter, fx, fy, c);
+    c
+}
+
+/// Whether a pixel needs more samples.
+fn needs_samples(scene: &Scene, stats: &PixelStats) -> bool {
+    if stats.count < scene.options.antialias {
+        return true;
+    }
+    match scene.options.adaptive {
+        // at least two samples are needed to estimate the variance
+        Some(ref adaptive) => stats.count < adaptive.max_samples && (stats.count < 2 || stats.relative_error() > adaptive.error),
+        None => false,
+    }
+}
+
+/// The output variables that are rendered for a scene: the ones of
+/// the options, and the ones that the denoiser needs. Progressive
+/// renders always have the latter, so that their checkpoints can be
+/// resumed with or without denoising.
+fn outputs(scene: &Scene) -> Vec<Aov> {
+    let mut outputs = scene.options.aovs.clone();
+    if scene.options.denoise.is_some() || scene.options.progressive.is_some() {
+        for &aov in &[Aov::Albedo, Aov::Normal] {
+            if !outputs.contains(&aov) {
+                outputs.push(aov);
+            }
+        }
+    }
+    outputs
+}
+
+/// An image that is being rendered.
+pub struct RenderState {
+    /// The beauty pass.
+    pub film: Film,
+    /// The output variables.
+    pub aovs: AovFilm,
+    /// The number of passes that have been rendered.
+    pub passes: u32,
+    stats: Vec<PixelStats>,
+}
+
+impl RenderState {
+    /// Start rendering a scene.
+    pub fn new(scene: &Scene) -> RenderState {
+        let (width, height) = (scene.options.width, scene.options.height);
+        RenderState {
+            film: Film::new(width, height),
+            aovs: AovFilm::new(&outputs(scene), width, height),
+            passes: 0,
+            stats: (0..width * height).map(|_| PixelStats::new()).collect(),
+        }
+    }
+
+    /// Save the render to a checkpoint.
+    pub fn write_state<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        try!(checkpoint::write_u32(w, self.passes));
+        try!(self.film.write_state(w));
+        try!(self.aovs.write_state(w));
+        for stats in &self.stats {
+            try!(checkpoint::write_u32(w, stats.count));
+            try!(checkpoint::write_f64(w, stats.lum_sum));
+            try!(checkpoint::write_f64(w, stats.lum_sq_sum));
+        }
+        Ok(())
+    }
+
+    /// Load a render of a scene from a checkpoint.
+    pub fn read_state<R: Read>(r: &mut R, scene: &Scene) -> io::Result<RenderState> {
+        let (width, height) = (scene.options.width, scene.options.height);
+        let passes = try!(checkpoint::read_u32(r));
+        let film = try!(Film::read_state(r, width, height));
+        let aovs = try!(AovFilm::read_state(r, &outputs(scene), width, height));
+        let mut stats = Vec::with_capacity((width * height) as usize);
+        for _ in 0..width * height {
+            let count = try!(checkpoint::read_u32(r));
+            let lum_sum = try!(checkpoint::read_f64(r));
+            let lum_sq_sum = try!(checkpoint::read_f64(r));
+            stats.push(PixelStats { count: count, lum_sum: lum_sum, lum_sq_sum: lum_sq_sum });
+        }
+        Ok(RenderState { film: film, aovs: aovs, passes: passes, stats: stats })
+    }
+
+    /// Add up to `max` samples to the pixel at `(x, y)`, where `y`
+    /// counts from the bottom of the image, until it has as many as
+    /// it needs. The samples may contribute to neighboring pixels.
+    /// Returns the number of samples that were taken.
+    pub fn render_pixel(&mut self, scene: &Scene, x: u32, y: u32, max: u32, rng: &mut RngT) -> u32 {
+        let stats = &mut self.stats[(x + scene.options.width * y) as usize];
+        rng.start_pixel(x, y, scene.options.antialias);
+        let mut taken = 0;
+        while taken < max && needs_samples(scene, stats) {
+            rng.start_sample(stats.count);
+            stats.add(sample_pixel(scene, &mut self.film, &mut self.aovs, x, y, rng));
+            taken += 1;
+        }
+        self.aovs.set_samples(x, y, stats.count);
+        taken
+    }
+
+    /// Add up to `samples` samples to every pixel of the rendered
+    /// region that needs more. Returns whether any samples were taken.
+    pub fn render_pass(&mut self, scene: &Scene, samples: u32, rng: &mut RngT) -> bool {
+        let (x0, y0, width, height) = scene.options.region();
+        let mut taken = 0;
+        for y in y0..y0 + height {
+            for x in x0..x0 + width {
+                taken += self.render_pixel(scene, x, y, samples, rng);
+            }
+        }
+        if taken > 0 {
+            self.passes += 1;
+        }
+        taken > 0
+    }
+
+    /// The total number of samples that have been taken.
+    pub fn samples(&self) -> u64 {
+        self.stats.iter().map(|s| s.count as u64).sum()
+    }
+}
+
+/// Render the region of the image a pixel at a time.
+pub fn render(scene: &Scene, rng: &mut RngT) -> RenderState {
+    let (x0, y0, width, height) = scene.options.region();
+    let mut state = RenderState::new(scene);
+    for y in y0..y0 + height {
+        for x in x0..x0 + width {
+            state.render_pixel(scene, x, y, u32::MAX, rng);
+        }
+    }
+    state
+}
+
+/// Denoise the rendered region of an image with the settings of the
+/// options, if denoising is enabled.
+pub fn denoise(scene: &Scene, film: &Film, aovs: &AovFilm) -> Option<Film> {
+    let (x, y, width, height) = scene.options.region();
+    scene.options.denoise.as_ref().map(|denoiser| {
+        let albedo = aovs.layer(Aov::Albedo).expect("albedo was not rendered");
+        let normal = aovs.layer(Aov::Normal).expect("normal was not rendered");
+        denoiser.denoise(&film.crop(x, y, width, height), &albedo.crop(x, y, width, height), &normal.crop(x, y, width, height))
+    })
+}
@@ -0,0 +1,355 @@
+//! Sample generators
+//!
+//! The sampler provides the random numbers used while rendering. Each
+//! number that is drawn for a pixel sample is a new dimension of that
+//! sample: the first two position the sample in the pixel, and the
+//! following ones are used by the camera, lights and materials in the
+//! order they ask for them. Well distributed samples make images
+//! converge faster than independent random numbers.
+//!
+//! The sampler implements `Rng`, and every `f64` it generates comes
+//! from the current dimension. Dimensions are paired, and each pair
+//! is distributed over the samples of a pixel as:
+//!
+//! * `random`: independent random numbers;
+//! * `stratified`: one jittered sample in each cell of a grid, which
+//!   has as many cells as the `antialias` option; further samples are
+//!   random;
+//! * `sobol`: the 2D Sobol sequence, with Owen scrambling and
+//!   shuffling that is different for each pixel and pair;
+//! * `blue_noise`: the same scrambled Sobol points for every pixel,
+//!   rotated by a blue noise mask, so that the error of neighboring
+//!   pixels is uncorrelated and looks like fine grain at low sample
+//!   counts.
+
+use std::io;
+use std::io::prelude::*;
+use std::rc::Rc;
+
+use types::rand::{Rng, SeedableRng, XorShiftRng};
+use checkpoint;
+
+/// The kinds of sample generators.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SamplerKind {
+    /// Independent random numbers.
+    Random,
+    /// Jittered samples in a grid.
+    Stratified,
+    /// The Owen scrambled Sobol sequence.
+    Sobol,
+    /// Sobol points rotated by a blue noise mask.
+    BlueNoise,
+}
+
+/// The size of the blue noise mask.
+const MASK_SIZE: usize = 64;
+
+/// A generator of samples for pixels. Clones continue with the same
+/// samples as the original.
+#[derive(Clone)]
+pub struct Sampler {
+    kind: SamplerKind,
+    rng: XorShiftRng,
+    mask: Rc<Vec<f32>>,
+    x: u32,
+    y: u32,
+    count: u32,
+    index: u32,
+    dimension: u32,
+}
+
+#[inline]
+fn hash(mut x: u32) -> u32 {
+    x ^= x >> 16;
+    x = x.wrapping_mul(0x7feb352d);
+    x ^= x >> 15;
+    x = x.wrapping_mul(0x846ca68b);
+    x ^= x >> 16;
+    x
+}
+
+#[inline]
+fn hash_combine(seed: u32, v: u32) -> u32 {
+    seed ^ (v.wrapping_add(0x9e3779b9).wrapping_add(seed << 6).wrapping_add(seed >> 2))
+}
+
+#[inline]
+fn to_unit(x: u32) -> f64 {
+    x as f64 * (1.0 / 4294967296.0)
+}
+
+/// A permutation of the bits of `x`, where each bit only depends on
+/// the bits below it.
+#[inline]
+fn laine_karras_permutation(mut x: u32, seed: u32) -> u32 {
+    x = x.wrapping_add(seed);
+    x ^= x.wrapping_mul(0x6c50b47c);
+    x ^= x.wrapping_mul(0xb82f1e52);
+    x ^= x.wrapping_mul(0xc7afe638);
+    x ^= x.wrapping_mul(0x8d22f6e6);
+    x
+}
+
+/// Owen scramble a number in [0, 2^32), interpreted as a fraction.
+#[inline]
+fn nested_uniform_scramble(x: u32, seed: u32) -> u32 {
+    laine_karras_permutation(x.reverse_bits(), seed).reverse_bits()
+}
+
+/// The first two dimensions of the Sobol sequence.
+fn sobol_2d(mut index: u32) -> (u32, u32) {
+    let x = index.reverse_bits();
+    let (mut y, mut v) = (0, 1 << 31);
+    while index != 0 {
+        if index & 1 != 0 {
+            y ^= v;
+        }
+        index >>= 1;
+        v ^= v >> 1;
+    }
+    (x, y)
+}
+
+/// A shuffled and scrambled 2D Sobol point. Each seed gives a
+/// differently scrambled copy of the sequence.
+fn scrambled_sobol_2d(index: u32, seed: u32) -> (f64, f64) {
+    let (x, y) = sobol_2d(nested_uniform_scramble(index, seed));
+    (to_unit(nested_uniform_scramble(x, hash_combine(seed, 0))), to_unit(nested_uniform_scramble(y, hash_combine(seed, 1))))
+}
+
+/// A permutation of [0, n), chosen by the seed.
+fn permute(mut i: u32, n: u32, seed: u32) -> u32 {
+    let mut w = n - 1;
+    w |= w >> 1;
+    w |= w >> 2;
+    w |= w >> 4;
+    w |= w >> 8;
+    w |= w >> 16;
+    // walk the cycle of a permutation of the next power of two until
+    // the result is in range
+    loop {
+        i ^= seed;
+        i = i.wrapping_mul(0xe170893d);
+        i ^= seed >> 16;
+        i ^= (i & w) >> 4;
+        i ^= seed >> 8;
+        i = i.wrapping_mul(0x0929eb3f);
+        i ^= seed >> 23;
+        i ^= (i & w) >> 1;
+        i = i.wrapping_mul(1 | seed >> 27);
+        i = i.wrapping_mul(0x6935fa69);
+        i ^= (i & w) >> 11;
+        i = i.wrapping_mul(0x74dcb303);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0x9e501cc3);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0xc860a3df);
+        i &= w;
+        i ^= i >> 5;
+        if i < n {
+            return (i + seed) % n;
+        }
+    }
+}
+
+/// Build a blue noise mask with the void and cluster method: pixels
+/// are ranked by repeatedly picking the one furthest from the pixels
+/// ranked so far, so the first pixels of any rank range are evenly
+/// spread out. Returns the rank of each pixel, scaled to [0, 1).
+fn blue_noise_mask(rng: &mut XorShiftRng) -> Vec<f32> {
+    const N: usize = MASK_SIZE * MASK_SIZE;
+    // the energy that a point contributes at each offset, wrapping
+    // around the edges
+    let sigma2 = 2.0 * 1.5 * 1.5;
+    let kernel: Vec<f64> = (0..N).map(|k| {
+        let (dx, dy) = (k % MASK_SIZE, k / MASK_SIZE);
+        let dx = dx.min(MASK_SIZE - dx) as f64;
+        let dy = dy.min(MASK_SIZE - dy) as f64;
+        (-(dx * dx + dy * dy) / sigma2).exp()
+    }).collect();
+    let mut on = vec![false; N];
+    let mut energy = vec![0.0; N];
+    let toggle = |on: &mut Vec<bool>, energy: &mut Vec<f64>, p: usize| {
+        on[p] = !on[p];
+        let sign = if on[p] { 1.0 } else { -1.0 };
+        let (px, py) = (p % MASK_SIZE, p / MASK_SIZE);
+        for k in 0..N {
+            let (x, y) = (k % MASK_SIZE, k / MASK_SIZE);
+            let d = (x + MASK_SIZE - px) % MASK_SIZE + MASK_SIZE * ((y + MASK_SIZE - py) % MASK_SIZE);
+            energy[k] += sign * kernel[d];
+        }
+    };
+    // the pixel that is on (or off) with the highest (or lowest) energy
+    let extreme = |on: &Vec<bool>, energy: &Vec<f64>, want: bool, highest: bool| {
+        let mut best = None;
+        for k in 0..N {
+            if on[k] == want && best.map_or(true, |b: usize| if highest { energy[k] > energy[b] } else { energy[k] < energy[b] }) {
+                best = Some(k);
+            }
+        }
+        best.unwrap()
+    };
+    // start with random points, then move points from the tightest
+    // cluster to the largest void until that changes nothing
+    let initial = N / 10;
+    while on.iter().filter(|&&b| b).count() < initial {
+        let p = rng.gen_range(0, N);
+        if !on[p] {
+            toggle(&mut on, &mut energy, p);
+        }
+    }
+    for _ in 0..N {
+        let cluster = extreme(&on, &energy, true, true);
+        toggle(&mut on, &mut energy, cluster);
+        let void = extreme(&on, &energy, false, false);
+        if void == cluster {
+            toggle(&mut on, &mut energy, cluster);
+            break;
+        }
+        toggle(&mut on, &mut energy, void);
+    }
+    let mut rank = vec![0; N];
+    // rank the initial points from the last to the first by removing
+    // the tightest clusters
+    let (mut on1, mut energy1) = (on.clone(), energy.clone());
+    for r in (0..initial).rev() {
+        let cluster = extreme(&on1, &energy1, true, true);
+        toggle(&mut on1, &mut energy1, cluster);
+        rank[cluster] = r;
+    }
+    // rank the rest by filling the largest voids
+    for r in initial..N {
+        let void = extreme(&on, &energy, false, false);
+        toggle(&mut on, &mut energy, void);
+        rank[void] = r;
+    }
+    rank.iter().map(|&r| (r as f32 + 0.5) / N as f32).collect()
+}
+
+impl Sampler {
+    /// Create a sampler. `rng` is used for random samples and for
+    /// dimensions that a generator does not cover.
+    pub fn new(kind: SamplerKind, mut rng: XorShiftRng) -> Sampler {
+        let mask = Rc::new(if kind == SamplerKind::BlueNoise { blue_noise_mask(&mut rng) } else { Vec::new() });
+        Sampler { kind: kind, rng: rng, mask: mask, x: 0, y: 0, count: 1, index: 0, dimension: 0 }
+    }
+
+    /// Start sampling the pixel at `(x, y)`, which is expected to get
+    /// `count` samples.
+    pub fn start_pixel(&mut self, x: u32, y: u32, count: u32) {
+        self.x = x;
+        self.y = y;
+        self.count = count.max(1);
+        self.start_sample(0);
+    }
+
+    /// Start the sample with the given index in the current pixel.
+    pub fn start_sample(&mut self, index: u32) {
+        self.index = index;
+        self.dimension = 0;
+    }
+
+    /// A seed that is different for each pixel and dimension pair.
+    fn pixel_seed(&self, pair: u32) -> u32 {
+        hash(hash_combine(hash_combine(hash(self.x), self.y), pair))
+    }
+
+    /// Get the next dimension of the current sample.
+    pub fn get_1d(&mut self) -> f64 {
+        let (pair, second) = (self.dimension / 2, self.dimension % 2 == 1);
+        self.dimension += 1;
+        let (a, b) = match self.kind {
+            SamplerKind::Random => return self.rng.next_f64(),
+            SamplerKind::Stratified => {
+                let nx = (self.count as f64).sqrt() as u32;
+                let ny = self.count / nx;
+                if self.index >= nx * ny {
+                    return self.rng.next_f64();
+                }
+                let cell = permute(self.index, nx * ny, self.pixel_seed(pair));
+                let jitter = self.rng.next_f64();
+                if second {
+                    return ((cell / nx) as f64 + jitter) / ny as f64;
+                }
+                return ((cell % nx) as f64 + jitter) / nx as f64;
+            },
+            SamplerKind::Sobol => scrambled_sobol_2d(self.index, self.pixel_seed(pair)),
+            SamplerKind::BlueNoise => {
+                // shift the mask differently for each dimension
+                let (a, b) = scrambled_sobol_2d(self.index, hash(pair ^ 0x5bd1e995));
+                let shift = hash_combine(hash(pair), second as u32);
+                let mx = (self.x as usize + (shift & 0xffff) as usize) % MASK_SIZE;
+                let my = (self.y as usize + (shift >> 16) as usize) % MASK_SIZE;
+                let offset = self.mask[mx + MASK_SIZE * my] as f64;
+                let v = if second { b } else { a } + offset;
+                return if v >= 1.0 { v - 1.0 } else { v };
+            },
+        };
+        if second { b } else { a }
+    }
+
+    /// Save the state of the sampler to a checkpoint. The random
+    /// number generator can't be saved, so it is reseeded from itself
+    /// and the seed is saved instead.
+    pub fn write_state<W: Write>(&mut self, w: &mut W) -> io::Result<()> {
+        let mut seed = [0; 4];
+        // the generator can't be seeded with zeros
+        while seed == [0; 4] {
+            seed = self.rng.gen();
+        }
+        self.rng = XorShiftRng::from_seed(seed);
+        for &v in &seed {
+            try!(checkpoint::write_u32(w, v));
+        }
+        try!(checkpoint::write_u32(w, self.mask.len() as u32));
+        for &v in self.mask.iter() {
+            try!(checkpoint::write_f32(w, v));
+        }
+        Ok(())
+    }
+
+    /// Load a sampler from a checkpoint.
+    pub fn read_state<R: Read>(r: &mut R, kind: SamplerKind) -> io::Result<Sampler> {
+        let mut seed = [0; 4];
+        for v in &mut seed {
+            *v = try!(checkpoint::read_u32(r));
+        }
+        if seed == [0; 4] {
+            return checkpoint::invalid("invalid sampler seed");
+        }
+        let n = try!(checkpoint::read_u32(r)) as usize;
+        if n != if kind == SamplerKind::BlueNoise { MASK_SIZE * MASK_SIZE } else { 0 } {
+            return checkpoint::invalid("invalid sampler mask");
+        }
+        let mut mask = Vec::with_capacity(n);
+        for _ in 0..n {
+            mask.push(try!(checkpoint::read_f32(r)));
+        }
+        Ok(Sampler { kind: kind, rng: XorShiftRng::from_seed(seed), mask: Rc::new(mask), x: 0, y: 0, count: 1, index: 0, dimension: 0 })
+    }
+
+    /// Get the next two dimensions of the current sample, which are
+    /// always a pair.
+    pub fn get_2d(&mut self) -> (f64, f64) {
+        if self.dimension % 2 == 1 {
+            self.dimension += 1;
+        }
+        let a = self.get_1d();
+        (a, self.get_1d())
+    }
+}
+
+impl Rng for Sampler {
+    fn next_u32(&mut self) -> u32 {
+        self.rng.next_u32()
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.rng.next_u64()
+    }
+
+    fn next_f64(&mut self) -> f64 {
+        self.get_1d()
+    }
+}
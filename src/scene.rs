@@ -9,15 +9,23 @@ use types::*;
 use shapes::*;
 use color::*;
 use camera::*;
+use param::*;
+use voxel::VoxelGrid;
+use sampler::SamplerKind;
+use film::Filter;
+use aov::Aov;
+use denoise::Denoiser;
 #[cfg(feature = "skybox")]
 use texture::*;
 
 use types::na::{Norm, FloatPnt};
 
+use std::f64;
 use std::boxed::Box;
 use std::option::Option;
 use std::iter::Iterator;
 use std::cmp::Ordering;
+use std::ops::{Add, Mul, Div};
 
 /// An object's material. A material is used to compute the color
 /// of an object when a ray hits it.
@@ -26,20 +34,92 @@ pub trait Material {
     /// getting the interaction from the object's material. Significance is a float that is decreased
     /// when a ray is generated recursively.
     fn color(&self, scene: &Scene, result: &IntersectionResult, ray: &Ray, significance: f64, depth: u32, rng: &mut RngT) -> Color;
+    /// Get the color split into passes by the kind of light, which add
+    /// up to the color. Materials that do not split their light put
+    /// all of it in the indirect diffuse pass.
+    fn passes(&self, scene: &Scene, result: &IntersectionResult, ray: &Ray, significance: f64, depth: u32, rng: &mut RngT) -> Passes {
+        Passes { diffuse_indirect: self.color(scene, result, ray, significance, depth, rng), ..Passes::default() }
+    }
+    /// Get the color of the surface without lighting.
+    fn albedo(&self, _: &IntersectionResult, _: &Pnt3) -> Color {
+        BLACK
+    }
+    /// Get the normal that the material shades with.
+    fn shading_normal(&self, result: &IntersectionResult, _: &Pnt3) -> Vec3 {
+        result.normal
+    }
+}
+
+/// The color of a material split by the kind of light. Ambient light
+/// counts as indirect diffuse light, and light that is refracted
+/// through a surface counts as indirect specular light.
+#[derive(Clone, Copy, Default)]
+pub struct Passes {
+    /// Light from light sources that is reflected diffusely.
+    pub diffuse_direct: Color,
+    /// Light from other surfaces that is reflected diffusely.
+    pub diffuse_indirect: Color,
+    /// Highlights from light sources.
+    pub specular_direct: Color,
+    /// Reflections and refractions of other surfaces.
+    pub specular_indirect: Color,
+}
+
+impl Add for Passes {
+    type Output = Passes;
+    fn add(self, other: Passes) -> Passes {
+        Passes {
+            diffuse_direct: self.diffuse_direct + other.diffuse_direct,
+            diffuse_indirect: self.diffuse_indirect + other.diffuse_indirect,
+            specular_direct: self.specular_direct + other.specular_direct,
+            specular_indirect: self.specular_indirect + other.specular_indirect,
+        }
+    }
+}
+
+impl Mul<Color> for Passes {
+    type Output = Passes;
+    fn mul(self, other: Color) -> Passes {
+        Passes {
+            diffuse_direct: self.diffuse_direct * other,
+            diffuse_indirect: self.diffuse_indirect * other,
+            specular_direct: self.specular_direct * other,
+            specular_indirect: self.specular_indirect * other,
+        }
+    }
+}
+
+impl Div<f64> for Passes {
+    type Output = Passes;
+    fn div(self, other: f64) -> Passes {
+        Passes {
+            diffuse_direct: self.diffuse_direct / other,
+            diffuse_indirect: self.diffuse_indirect / other,
+            specular_direct: self.specular_direct / other,
+            specular_indirect: self.specular_indirect / other,
+        }
+    }
+}
+
+impl Passes {
+    /// The sum of all the passes.
+    pub fn total(&self) -> Color {
+        self.diffuse_direct + self.diffuse_indirect + self.specular_direct + self.specular_indirect
+    }
 }
 
 /// Material using the Blinn-Phong reflection model.
 pub struct PhongMaterial {
     /// Diffuse color of Lambertian reflectance.
-    pub diffuse: Color,
+    pub diffuse: ColorParam,
     /// Color of specular reflectance. Currently glossy reflection is not implemented, and thus
     /// only highlights will be glossy.
-    pub specular: Color,
+    pub specular: ColorParam,
     /// Shininess (specular exponent) in the Phong reflection model.
-    pub exponent: f64,
-    /// Ambient light (light from scattered light in the environment). Currently ambient
-    /// occlusion is not implemented.
-    pub ambient: Color,
+    pub exponent: ScalarParam,
+    /// Ambient light (light from scattered light in the environment). It is darkened by
+    /// ambient occlusion if `occlude_ambient` is set in the options.
+    pub ambient: ColorParam,
 }
 
 /// Material using Blinn-Phong, but with a Fresnel term.
@@ -47,15 +127,15 @@ pub struct PhongMaterial {
 /// there is a dramatic effect.
 pub struct FresnelMaterial {
     /// Diffuse color of Lambertian reflectance.
-    pub diffuse: Color,
+    pub diffuse: ColorParam,
     /// Color of specular reflectance. Currently glossy reflection is not implemented, and thus
     /// only highlights will be glossy.
-    pub specular: Color,
+    pub specular: ColorParam,
     /// Shininess (specular exponent) in the Phong reflection model.
-    pub exponent: f64,
-    /// Ambient light (light from scattered light in the environment). Currently ambient
-    /// occlusion is not implemented.
-    pub ambient: Color,
+    pub exponent: ScalarParam,
+    /// Ambient light (light from scattered light in the environment). It is darkened by
+    /// ambient occlusion if `occlude_ambient` is set in the options.
+    pub ambient: ColorParam,
     /// Index of refraction. The IOR of air is 1.00. There are tables on the Internet.
     pub ior: f64,
 }
@@ -65,9 +145,9 @@ pub struct FresnelMaterial {
 pub struct TransparentMaterial {
     /// Color of specular reflectance. Currently glossy reflection is not implemented, and thus
     /// only highlights will be glossy.
-    pub specular: Color,
+    pub specular: ColorParam,
     /// Shininess (specular exponent) in the Phong reflection model.
-    pub exponent: f64,
+    pub exponent: ScalarParam,
     /// Index of refraction. The IOR of air is 1.00. There are tables on the Internet.
     pub ior: f64,
 }
@@ -75,26 +155,105 @@ pub struct TransparentMaterial {
 /// Material using the Blinn-Phong reflection model with indirect lighting.
 pub struct IndirectPhongMaterial {
     /// Diffuse color of Lambertian reflectance.
-    pub diffuse: Color,
+    pub diffuse: ColorParam,
     /// Color of specular reflectance. Currently glossy reflection is not implemented, and thus
     /// only highlights will be glossy.
-    pub specular: Color,
+    pub specular: ColorParam,
     /// Shininess (specular exponent) in the Phong reflection model.
-    pub exponent: f64,
-    /// Ambient light (light from scattered light in the environment). Currently ambient
-    /// occlusion is not implemented.
-    pub ambient: Color,
+    pub exponent: ScalarParam,
+    /// Ambient light (light from scattered light in the environment). It is darkened by
+    /// ambient occlusion if `occlude_ambient` is set in the options.
+    pub ambient: ColorParam,
     /// Number of samples to use
     pub samples: u32,
 }
 
+/// A material that perturbs the shading normal using a height map
+/// before shading with another material.
+pub struct BumpMappedMaterial {
+    /// The material used for shading.
+    pub material: Box<Material>,
+    /// The height of the surface along the normal.
+    pub height: ScalarParam,
+    /// Multiplier for the height. Negative values invert the bumps.
+    pub strength: f64,
+}
+
+/// A material that replaces the shading normal with a normal from a
+/// tangent-space normal map before shading with another material.
+/// The red, green, and blue components of the map are the tangent
+/// (along u), bitangent (along v), and normal components, mapped
+/// from [-1, 1] to [0, 1]. The map should be loaded with `color`
+/// set to false so that it is not decoded from sRGB.
+#[cfg(feature = "textures")]
+pub struct NormalMappedMaterial {
+    /// The material used for shading.
+    pub material: Box<Material>,
+    /// The normal map.
+    pub normal_map: ImageTexture,
+    /// Multiplier for the tangent components of the normal. 1 uses
+    /// the normal map as-is, and 0 disables it.
+    pub strength: f64,
+}
+
 /// An object in a scene. The `Object` struct contains everything
 /// necessary to render the object.
 pub struct Object {
+    /// The name of the object, used to refer to it (e.g. for
+    /// autofocus).
+    pub name: Option<String>,
     /// The bounds of the object, which is used for ray intersection.
     pub bounds: Box<Shape>,
     /// The material of the object.
     pub material: Box<Material>,
+    /// A number identifying the material in the material ID output.
+    /// Objects that share an ID are masked together.
+    pub material_id: u32,
+}
+
+/// A homogeneous participating medium, such as fog or smoke. Light
+/// travelling through the medium is absorbed and scattered at a
+/// constant rate.
+pub struct Medium {
+    /// The fraction of light that is absorbed per unit of distance.
+    pub absorption: Color,
+    /// The fraction of light that is scattered per unit of distance.
+    pub scattering: Color,
+    /// The asymmetry of the Henyey-Greenstein phase function, in
+    /// (-1, 1). Positive values scatter light forward, negative
+    /// values scatter it back, and 0 scatters it equally in all
+    /// directions.
+    pub asymmetry: f64,
+}
+
+/// A region of space that is filled with a medium.
+pub struct Volume {
+    /// The medium inside the volume.
+    pub medium: Medium,
+    /// The closed shape bounding the volume, with normals facing
+    /// outwards. If there is no shape, the medium fills the whole
+    /// scene.
+    pub bounds: Option<Box<Shape>>,
+}
+
+/// A heterogeneous medium whose density is given by a voxel grid,
+/// such as a cloud or an explosion. The grid fills an axis-aligned
+/// box.
+pub struct GridVolume {
+    /// The densities of the medium.
+    pub grid: VoxelGrid,
+    /// The corner of the box with the lowest coordinates.
+    pub min: Pnt3,
+    /// The corner of the box with the highest coordinates.
+    pub max: Pnt3,
+    /// Multiplier for the densities in the grid, giving the fraction
+    /// of light that is absorbed or scattered per unit of distance.
+    pub density_scale: f64,
+    /// The fraction of light that is scattered (instead of absorbed)
+    /// when it interacts with the medium.
+    pub albedo: Color,
+    /// The asymmetry of the Henyey-Greenstein phase function.
+    pub asymmetry: f64,
 }
 
 /// A light that can project rays onto an object.
@@ -187,14 +346,141 @@ pub struct SkyboxBackground {
     pub nz: Texture,
 }
 
+/// How the film gate of the camera is fitted to the image, when
+/// their aspect ratios differ.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum GateFit {
+    /// The whole gate is in the image, which shows more than the
+    /// gate along one of the axes.
+    Fit,
+    /// The image is inside the gate, which is cut off along one of
+    /// the axes.
+    Fill,
+    /// The width of the image is the width of the gate.
+    Horizontal,
+    /// The height of the image is the height of the gate.
+    Vertical,
+}
+
+/// What is computed for each camera ray.
+pub enum RenderMode {
+    /// Shade objects with their materials.
+    Shaded,
+    /// Show only the ambient occlusion of the objects, in gray. This
+    /// is useful for clay renders. Rays that hit nothing are white.
+    AmbientOcclusion,
+}
+
+/// Settings for ambient occlusion, which is the fraction of the
+/// hemisphere around a point that is not blocked by nearby objects.
+pub struct AmbientOcclusion {
+    /// The number of rays used for each point.
+    pub samples: u32,
+    /// Objects further away than this do not occlude a point.
+    pub distance: f64,
+}
+
+impl Default for AmbientOcclusion {
+    fn default() -> AmbientOcclusion {
+        AmbientOcclusion { samples: 16, distance: f64::INFINITY }
+    }
+}
+
+/// Settings for adaptive sampling. Each pixel gets at least as many
+/// samples as the `antialias` option, then is sampled until the
+/// estimated error of its luminance is low enough.
+pub struct AdaptiveSampling {
+    /// The largest number of samples for a pixel.
+    pub max_samples: u32,
+    /// The target standard error of the mean of a pixel, relative to
+    /// its luminance.
+    pub error: f64,
+}
+
+/// Settings for progressive rendering, where each pass adds a few
+/// samples to every pixel and the image is saved now and then, so
+/// that a render can be stopped early.
+pub struct Progressive {
+    /// The number of samples that each pass adds to a pixel.
+    pub samples: u32,
+    /// Save the image after this many passes, or never if 0.
+    pub checkpoint_passes: u32,
+    /// Save the image when this many seconds have passed since it was
+    /// last saved, or never if 0.
+    pub checkpoint_interval: f64,
+}
+
+/// A rectangle of the image to render, instead of the whole image.
+/// The camera sees the same as in the whole image.
+pub struct Crop {
+    /// The column of the leftmost pixel.
+    pub x: u32,
+    /// The row of the top pixel, counting from the top of the image.
+    pub y: u32,
+    /// The width of the rectangle in pixels.
+    pub width: u32,
+    /// The height of the rectangle in pixels.
+    pub height: u32,
+    /// Whether the rectangle is pasted into the existing output
+    /// images, instead of written as smaller images.
+    pub merge: bool,
+}
+
 /// Render options
 pub struct Options {
     /// width of the rendered image
     pub width: u32,
     /// height of the rendered image
     pub height: u32,
-    /// number of anti-aliasing samples
+    /// the part of the image that is rendered, if not all of it
+    pub crop: Option<Crop>,
+    /// how the film gate of the camera is fitted to the image
+    pub gate_fit: GateFit,
+    /// the width of a pixel divided by its height
+    pub pixel_aspect: f64,
+    /// number of anti-aliasing samples, or the minimum number if
+    /// sampling is adaptive
     pub antialias: u32,
+    /// settings for adaptive sampling, if enabled
+    pub adaptive: Option<AdaptiveSampling>,
+    /// settings for progressive rendering, if enabled
+    pub progressive: Option<Progressive>,
+    /// how the samples of each pixel are distributed
+    pub sampler: SamplerKind,
+    /// the filter that weights samples for the pixels around them
+    pub filter: Box<Filter>,
+    /// the extra images that are written besides the beauty pass
+    pub aovs: Vec<Aov>,
+    /// settings for denoising the image, if enabled
+    pub denoise: Option<Denoiser>,
+    /// interval during which the shutter is open, for motion blur
+    pub shutter: Shutter,
+    /// whether light scattered by media is scattered again, instead
+    /// of only lighting media directly
+    pub multiple_scattering: bool,
+    /// what is computed for each camera ray
+    pub mode: RenderMode,
+    /// settings for ambient occlusion
+    pub occlusion: AmbientOcclusion,
+    /// whether the ambient light of materials is darkened by ambient
+    /// occlusion
+    pub occlude_ambient: bool,
+}
+
+impl Options {
+    /// The part of the image that is rendered, as the lower left
+    /// pixel and the size, where rows count from the bottom. A crop
+    /// rectangle is clipped to the image.
+    pub fn region(&self) -> (u32, u32, u32, u32) {
+        match self.crop {
+            Some(ref crop) => {
+                let (x, top) = (crop.x.min(self.width), crop.y.min(self.height));
+                let (w, h) = (crop.width.min(self.width - x), crop.height.min(self.height - top));
+                (x, self.height - top - h, w, h)
+            },
+            None => (0, 0, self.width, self.height),
+        }
+    }
 }
 
 /// A scene with objects, lights, a camera, and a background.
@@ -203,6 +489,10 @@ pub struct Scene {
     pub objects: Vec<Object>,
     /// The lights in the scene.
     pub lights: Vec<Light>,
+    /// The volumes of participating media in the scene.
+    pub volumes: Vec<Volume>,
+    /// The volumes of media with densities from voxel grids.
+    pub grid_volumes: Vec<GridVolume>,
     /// The camera of the scene.
     pub camera: Box<Camera>,
     /// The background of the scene.
@@ -215,6 +505,8 @@ pub struct Scene {
 pub struct SceneIntersectionResult<'a> {
     /// The object the ray hit.
     pub object: &'a Object,
+    /// The index of the object in the list it was found in.
+    pub index: usize,
     /// The `IntersectionResult` returned by the object's `intersect`
     /// method.
     pub result: IntersectionResult,
@@ -241,10 +533,53 @@ impl Ord for FloatNotNan {
     }
 }
 
+/// Intersect a ray with a list of objects, returning the closest hit.
+pub fn intersect_objects<'a>(objects: &'a [Object], ray: &Ray) -> Option<SceneIntersectionResult<'a>> {
+    objects.iter().enumerate().filter_map(|(i, o)| o.bounds.intersect(ray).map( |r| SceneIntersectionResult { object: o, index: i, result: r })).min_by_key(|o| FloatNotNan::new(o.result.t))
+}
+
 impl Scene {
     /// Intersect a ray with the scene, returning a result which
     /// contains the `intersect` result and the object it hit.
     pub fn intersect(&self, ray: &Ray) -> Option<SceneIntersectionResult> {
-        self.objects.iter().filter_map(|o| o.bounds.intersect(ray).map( |r| SceneIntersectionResult { object: o, result: r })).min_by_key(|o| FloatNotNan::new(o.result.t))
+        intersect_objects(&self.objects, ray)
+    }
+
+    /// The size of a pixel in normalized image coordinates, which
+    /// depends on how the film gate of the camera is fitted to the
+    /// image and on the aspect ratio of the pixels.
+    pub fn pixel_size(&self) -> Vec2 {
+        let gate = self.camera.gate();
+        let (width, height, aspect) = (self.options.width as f64, self.options.height as f64, self.options.pixel_aspect);
+        // whether the gate is wider than the image, or as wide
+        let wider = gate.x / gate.y >= width * aspect / height;
+        let horizontal = match self.options.gate_fit {
+            GateFit::Fit => wider,
+            GateFit::Fill => !wider,
+            GateFit::Horizontal => true,
+            GateFit::Vertical => false,
+        };
+        if horizontal {
+            let x = 2.0 * gate.x / width;
+            Vec2::new(x, x / aspect)
+        } else {
+            let y = 2.0 * gate.y / height;
+            Vec2::new(y * aspect, y)
+        }
+    }
+
+    /// Convert a position in pixels to normalized image coordinates,
+    /// where the center of the image is (0, 0).
+    pub fn image_position(&self, x: f64, y: f64) -> Pnt2 {
+        let pixel = self.pixel_size();
+        Pnt2::new(
+            (x - self.options.width as f64 / 2.0) * pixel.x,
+            (y - self.options.height as f64 / 2.0) * pixel.y,
+        )
+    }
+
+    /// Whether the scene contains any participating media.
+    pub fn has_media(&self) -> bool {
+        !self.volumes.is_empty() || !self.grid_volumes.is_empty()
     }
 }
@@ -0,0 +1,338 @@
+//! Signed distance fields
+//!
+//! A signed distance field gives the distance from a point to the
+//! closest surface, which is negative inside of the shape. Distance
+//! fields are built from a tree of primitives and operators, and are
+//! rendered by sphere tracing: stepping along a ray by the distance
+//! to the closest surface until it is reached.
+
+use std::f64;
+
+use types::*;
+use types::na::{Norm, Dot};
+use shapes::{Ray, IntersectionResult, Interval, Shape, AxisAlignedBox, BoundingBox};
+
+/// A node in the tree of a distance field.
+pub trait DistanceField {
+    /// Get the signed distance from a point to the surface.
+    fn distance(&self, p: &Pnt3) -> f64;
+}
+
+/// A sphere.
+pub struct SdfSphere {
+    /// The center of the sphere.
+    pub center: Pnt3,
+    /// The radius of the sphere.
+    pub radius: f64,
+}
+
+impl DistanceField for SdfSphere {
+    fn distance(&self, p: &Pnt3) -> f64 {
+        (*p - self.center).norm() - self.radius
+    }
+}
+
+/// A box aligned with the axes, with optionally rounded edges.
+pub struct SdfBox {
+    /// The center of the box.
+    pub center: Pnt3,
+    /// The size of the box along each axis.
+    pub size: Vec3,
+    /// The radius of the rounded edges. The box keeps its size.
+    pub rounding: f64,
+}
+
+impl DistanceField for SdfBox {
+    fn distance(&self, p: &Pnt3) -> f64 {
+        let d = *p - self.center;
+        let r = self.rounding;
+        let q = Vec3::new(d.x.abs() - self.size.x * 0.5 + r, d.y.abs() - self.size.y * 0.5 + r, d.z.abs() - self.size.z * 0.5 + r);
+        let outside = Vec3::new(q.x.max(0.0), q.y.max(0.0), q.z.max(0.0)).norm();
+        outside + q.x.max(q.y).max(q.z).min(0.0) - r
+    }
+}
+
+/// A torus around the y axis.
+pub struct SdfTorus {
+    /// The center of the torus.
+    pub center: Pnt3,
+    /// The distance from the center to the middle of the tube.
+    pub major_radius: f64,
+    /// The radius of the tube.
+    pub minor_radius: f64,
+}
+
+impl DistanceField for SdfTorus {
+    fn distance(&self, p: &Pnt3) -> f64 {
+        let d = *p - self.center;
+        let ring = (d.x * d.x + d.z * d.z).sqrt() - self.major_radius;
+        (ring * ring + d.y * d.y).sqrt() - self.minor_radius
+    }
+}
+
+/// A capsule: a line segment with a radius around it.
+pub struct SdfCapsule {
+    /// One end of the segment.
+    pub start: Pnt3,
+    /// The other end of the segment.
+    pub end: Pnt3,
+    /// The radius around the segment.
+    pub radius: f64,
+}
+
+impl DistanceField for SdfCapsule {
+    fn distance(&self, p: &Pnt3) -> f64 {
+        let pa = *p - self.start;
+        let ba = self.end - self.start;
+        let h = (pa.dot(&ba) / ba.sqnorm()).max(0.0).min(1.0);
+        (pa - ba * h).norm() - self.radius
+    }
+}
+
+/// The union of two fields, with the seam rounded off.
+pub struct SmoothUnion {
+    /// The first field.
+    pub left: Box<DistanceField>,
+    /// The second field.
+    pub right: Box<DistanceField>,
+    /// The distance over which the fields are smoothly joined. 0 is a
+    /// sharp union.
+    pub smoothness: f64,
+}
+
+impl DistanceField for SmoothUnion {
+    fn distance(&self, p: &Pnt3) -> f64 {
+        let (a, b) = (self.left.distance(p), self.right.distance(p));
+        let k = self.smoothness;
+        if k <= 0.0 {
+            return a.min(b);
+        }
+        let h = (0.5 + 0.5 * (b - a) / k).max(0.0).min(1.0);
+        b + (a - b) * h - k * h * (1.0 - h)
+    }
+}
+
+/// A linear blend between two fields, which morphs one shape into
+/// the other.
+pub struct Blend {
+    /// The field at an amount of 0.
+    pub left: Box<DistanceField>,
+    /// The field at an amount of 1.
+    pub right: Box<DistanceField>,
+    /// How much of the second field is used.
+    pub amount: f64,
+}
+
+impl DistanceField for Blend {
+    fn distance(&self, p: &Pnt3) -> f64 {
+        self.left.distance(p) * (1.0 - self.amount) + self.right.distance(p) * self.amount
+    }
+}
+
+/// Infinite repetition of a field. The field should fit inside one
+/// cell around the origin.
+pub struct Repeat {
+    /// The repeated field.
+    pub node: Box<DistanceField>,
+    /// The distance between copies along each axis. Axes with a period
+    /// of 0 are not repeated.
+    pub period: Vec3,
+}
+
+impl DistanceField for Repeat {
+    fn distance(&self, p: &Pnt3) -> f64 {
+        let wrap = |x: f64, period: f64| if period > 0.0 { x - period * (x / period).round() } else { x };
+        self.node.distance(&Pnt3::new(wrap(p.x, self.period.x), wrap(p.y, self.period.y), wrap(p.z, self.period.z)))
+    }
+}
+
+/// A field twisted around the y axis. Twisting distorts distances, so
+/// the `step` of the shape should be reduced for strong twists.
+pub struct Twist {
+    /// The twisted field.
+    pub node: Box<DistanceField>,
+    /// The angle of the twist in radians per unit along the y axis.
+    pub rate: f64,
+}
+
+impl DistanceField for Twist {
+    fn distance(&self, p: &Pnt3) -> f64 {
+        let (s, c) = (self.rate * p.y).sin_cos();
+        self.node.distance(&Pnt3::new(c * p.x - s * p.z, p.y, s * p.x + c * p.z))
+    }
+}
+
+/// A shape defined by a distance field, which is rendered inside of
+/// a box. The texture coordinates are projected along the axis that
+/// the normal is closest to, in world units.
+pub struct Sdf {
+    /// The root of the tree of the field.
+    pub node: Box<DistanceField>,
+    /// The corner of the box with the lowest coordinates.
+    pub min: Pnt3,
+    /// The corner of the box with the highest coordinates.
+    pub max: Pnt3,
+    /// The distance to the surface that counts as a hit.
+    pub epsilon: f64,
+    /// The largest number of steps along a ray. A ray that runs out
+    /// of steps within a few `epsilon` of the surface hits it where it
+    /// came closest to it, and otherwise misses.
+    pub max_steps: u32,
+    /// Multiplier for the size of each step. Values below 1 are
+    /// needed for fields that overestimate distances.
+    pub step: f64,
+}
+
+/// How many times `epsilon` a ray that runs out of steps may be from
+/// the surface to hit it.
+const GRAZE_DISTANCE: f64 = 10.0;
+
+#[inline]
+fn component(v: &Vec3, i: usize) -> f64 {
+    match i { 0 => v.x, 1 => v.y, _ => v.z }
+}
+
+#[inline]
+fn axis_vec(i: usize, length: f64) -> Vec3 {
+    match i {
+        0 => Vec3::new(length, 0.0, 0.0),
+        1 => Vec3::new(0.0, length, 0.0),
+        _ => Vec3::new(0.0, 0.0, length),
+    }
+}
+
+impl Sdf {
+    fn bounding_box(&self) -> AxisAlignedBox {
+        AxisAlignedBox { min: self.min, max: self.max }
+    }
+
+    /// Estimate the normal with central differences.
+    fn normal_at(&self, p: &Pnt3) -> Vec3 {
+        let h = self.epsilon;
+        let d = |v: Vec3| self.node.distance(&(*p + v)) - self.node.distance(&(*p - v));
+        let n = Vec3::new(d(axis_vec(0, h)), d(axis_vec(1, h)), d(axis_vec(2, h)));
+        if n.sqnorm() > 0.0 { n.normalize() } else { Vec3::new(0.0, 1.0, 0.0) }
+    }
+
+    fn result_at(&self, ray: &Ray, t: f64) -> IntersectionResult {
+        let p = ray.cast(t);
+        let n = self.normal_at(&p);
+        // project along the axis closest to the normal
+        let (ax, ay, az) = (n.x.abs(), n.y.abs(), n.z.abs());
+        let i = if ax >= ay && ax >= az { 0 } else if ay >= az { 1 } else { 2 };
+        let (j, k) = ((i + 1) % 3, (i + 2) % 3);
+        let pv = p.to_vec();
+        let ni = component(&n, i);
+        IntersectionResult::new(
+            t,
+            n,
+            Pnt2::new(component(&pv, j), component(&pv, k)),
+            axis_vec(j, 1.0) - axis_vec(i, component(&n, j) / ni),
+            axis_vec(k, 1.0) - axis_vec(i, component(&n, k) / ni),
+        )
+    }
+
+    /// Find where the sign of the field changes between `a` and `b`
+    /// by bisection.
+    fn refine(&self, ray: &Ray, mut a: f64, mut b: f64) -> f64 {
+        let inside_a = self.node.distance(&ray.cast(a)) < 0.0;
+        for _ in 0..16 {
+            let m = 0.5 * (a + b);
+            if (self.node.distance(&ray.cast(m)) < 0.0) == inside_a { a = m } else { b = m }
+        }
+        0.5 * (a + b)
+    }
+
+    /// Sphere trace a ray from `t0` to `t1`, calling `f` with the `t`
+    /// of each surface that is crossed and whether the ray enters the
+    /// shape there. Stops if `f` returns false. Steps are at least
+    /// `epsilon` long, so that the ray steps through the surface once
+    /// it is close enough. If the steps run out before `t1`, returns
+    /// where the ray came closest to the surface since the last
+    /// crossing, if it was within a few `epsilon` of it. That is not a
+    /// crossing, so it is not passed to `f`.
+    fn march<F>(&self, ray: &Ray, t0: f64, t1: f64, mut f: F) -> Option<f64> where F: FnMut(f64, bool) -> bool {
+        let scale = ray.direction.norm();
+        let min_step = self.epsilon / scale;
+        let d0 = self.node.distance(&ray.cast(t0));
+        let mut inside = d0 < 0.0;
+        // rays that start on the surface (e.g. reflections) should not
+        // hit it again right away
+        let ignore_until = if d0.abs() < self.epsilon { t0 + 2.0 * min_step } else { t0 };
+        let (mut prev, mut t) = (t0, t0);
+        // the distance and position of the closest approach to the
+        // surface since the last crossing
+        let mut closest = (f64::INFINITY, t0);
+        for _ in 0..self.max_steps {
+            let d = self.node.distance(&ray.cast(t));
+            if (d < 0.0) != inside {
+                inside = !inside;
+                let hit = self.refine(ray, prev, t);
+                if hit > ignore_until && !f(hit, inside) {
+                    return None;
+                }
+                closest = (f64::INFINITY, t);
+            } else if d.abs() < closest.0 && t > ignore_until {
+                closest = (d.abs(), t);
+            }
+            prev = t;
+            t = t + (d.abs() * self.step / scale).max(min_step);
+            if t > t1 {
+                return None;
+            }
+        }
+        // rays that graze the surface take many small steps; they would
+        // leave holes if they missed when the steps run out
+        if closest.0 <= GRAZE_DISTANCE * self.epsilon {
+            Some(closest.1)
+        } else {
+            None
+        }
+    }
+}
+
+impl Shape for Sdf {
+    fn intersect(&self, ray: &Ray) -> Option<IntersectionResult> {
+        let mut result = None;
+        for i in self.bounding_box().intervals(ray) {
+            if i.exit.t <= 0.0 {
+                continue;
+            }
+            let t0 = i.enter.t.max(0.0);
+            let mut hit = None;
+            let graze = self.march(ray, t0, i.exit.t, |t, _| { hit = Some(t); false });
+            if let Some(t) = hit.or(graze) {
+                result = Some(self.result_at(ray, t));
+                break;
+            }
+        }
+        result
+    }
+
+    fn intervals(&self, ray: &Ray) -> Vec<Interval> {
+        let mut intervals = Vec::new();
+        for i in self.bounding_box().intervals(ray) {
+            // the shape is cut off by the box
+            let mut enter = if self.node.distance(&ray.cast(i.enter.t)) < 0.0 { Some(i.enter.clone()) } else { None };
+            let mut crossings = Vec::new();
+            // a ray that runs out of steps is treated as missing the
+            // rest of the shape, since it did not cross the surface
+            self.march(ray, i.enter.t, i.exit.t, |t, entering| { crossings.push((t, entering)); true });
+            for (t, entering) in crossings {
+                if entering {
+                    enter = Some(self.result_at(ray, t));
+                } else if let Some(e) = enter.take() {
+                    intervals.push(Interval { enter: e, exit: self.result_at(ray, t) });
+                }
+            }
+            if let Some(e) = enter {
+                intervals.push(Interval { enter: e, exit: i.exit });
+            }
+        }
+        intervals
+    }
+
+    fn bounds(&self) -> Option<BoundingBox> {
+        Some(BoundingBox { min: self.min, max: self.max })
+    }
+}
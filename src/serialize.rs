@@ -1,6 +1,20 @@
 //! Functions for serializing a scene. The output format is similar to Rust's syntax.
 //!
 //! Currently only deserializes.
+//!
+//! Values can be bound to names with `let name = value;`, anywhere
+//! in a scene file, and the name can then be used instead of the
+//! value. A binding can hold anything, such as a number, a material
+//! or a shape, and it is replaced by the tokens of its value, so a
+//! binding must be defined before it is used. Field names are never
+//! replaced, and the names of classes, functions, units and other
+//! words of the format can't be bound. Numbers can be arithmetic expressions with `+`, `-`,
+//! `*`, `/` and parentheses:
+//!
+//! ```text
+//! let white = IndirectPhongMaterial { diffuse: rgb(1, 1, 1) ... };
+//! let im_dist = 30 * 96 / 800;
+//! ```
 
 use std;
 use std::str::{Chars, FromStr};
@@ -8,15 +22,27 @@ use std::fmt;
 use std::error::Error;
 use std::iter::{Iterator};
 use std::f64::consts;
+use std::cmp::Ordering;
+use std::collections::HashMap;
 
 use ::camera::*;
 use ::scene::*;
-use ::types::{Vec3, Pnt3};
+use ::types::{Vec2, Vec3, Pnt3};
+use ::animation::{Keyframe, Transform};
 use ::color::*;
 use ::shapes::*;
-#[cfg(feature = "skybox")]
-use ::texture::Texture;
-#[cfg(feature = "skybox")]
+use ::param::*;
+use ::procedural::*;
+use ::voxel::VoxelGrid;
+use ::sdf::*;
+use ::heightfield::Heightfield;
+use ::sampler::SamplerKind;
+use ::film::*;
+use ::aov::Aov;
+use ::denoise::Denoiser;
+#[cfg(feature = "textures")]
+use ::texture::{Texture, TextureOptions, Wrap};
+#[cfg(feature = "textures")]
 use ::texture;
 
 #[derive(Copy, Clone, Debug)]
@@ -197,7 +223,7 @@ impl<I: Processable> Expectable<I::Item> for Acceptor<I> where I::Item: fmt::Deb
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, PartialEq, Debug)]
 enum Token {
     Identifier(String),
     String(String),
@@ -210,6 +236,12 @@ enum Token {
     RParen,
     Colon,
     Comma,
+    Equals,
+    Semicolon,
+    Plus,
+    Minus,
+    Star,
+    Slash,
 }
 
 /// Type and data for a syntax error.
@@ -239,6 +271,14 @@ pub enum SyntaxErrorType {
         path: String,
         /// The description of the error
         err: String },
+    /// Error loading a voxel grid
+    VoxelLoad {
+        /// The path of the grid
+        path: String,
+        /// The description of the error
+        err: String },
+    /// The camera could not focus on what it was told to
+    Autofocus(String),
 }
 
 impl fmt::Display for SyntaxErrorType {
@@ -251,6 +291,8 @@ impl fmt::Display for SyntaxErrorType {
             SyntaxErrorType::Missing => write!(fmt, "missing one or more fields"),
             SyntaxErrorType::NoClass(ref s) => write!(fmt, "no such class: {}", s),
             SyntaxErrorType::TextureLoad { ref path, ref err } => write!(fmt, "error loading \"{}\": {}", path, err),
+            SyntaxErrorType::VoxelLoad { ref path, ref err } => write!(fmt, "error loading \"{}\": {}", path, err),
+            SyntaxErrorType::Autofocus(ref s) => write!(fmt, "autofocus failed: {}", s),
         }
     }
 }
@@ -281,6 +323,8 @@ impl Error for SyntaxError {
             SyntaxErrorType::Missing => "missing fields",
             SyntaxErrorType::NoClass(_) => "no such class",
             SyntaxErrorType::TextureLoad { path: _, err: _ } => "error loading texture",
+            SyntaxErrorType::VoxelLoad { path: _, err: _ } => "error loading voxel grid",
+            SyntaxErrorType::Autofocus(_) => "autofocus failed",
         }
     }
 
@@ -359,15 +403,114 @@ fn parse_string_tok<'a, I: Processable<Item = char>>(a: &'a mut Acceptor<I>) ->
     StringParser { acceptor: a }
 }
 
+/// Words of the format that can't be the names of `let` bindings,
+/// because they would be replaced where the parser expects them:
+/// classes, functions, units and the identifiers of enums. The names
+/// of output variables are checked separately.
+const RESERVED: &'static [&'static str] = &[
+    "let", "true", "false", "deg", "rad", "rgb", "load", "raw",
+    "new", "look_at", "physical", "point", "object",
+    "union", "intersection", "difference", "equidistant", "equisolid", "uv", "world",
+    "clamp", "repeat", "mirror", "border", "fit", "fill", "horizontal", "vertical",
+    "random", "stratified", "sobol", "blue_noise", "shaded", "ambient_occlusion",
+    "AnimatedCamera", "AnimatedShape", "AreaLight", "AxisAlignedBox", "Blend", "BoxFilter",
+    "BumpMappedMaterial", "Checker", "Cone", "Csg", "Cylinder", "DepthOfFieldCamera",
+    "DirectionalLight", "Disk", "EquirectangularCamera", "FisheyeCamera", "FresnelMaterial",
+    "GaussianFilter", "Heightfield", "ImageTexture", "IndirectPhongMaterial", "LanczosFilter",
+    "Marble", "MitchellFilter", "Noise", "NormalMappedMaterial", "OrientedBox", "Pattern",
+    "PhongMaterial", "Plane", "PointLight", "Rectangle", "Repeat", "Sdf", "SdfBox", "SdfCapsule",
+    "SdfSphere", "SdfTorus", "SimplePerspectiveCamera", "SkyboxBackground", "SmoothUnion",
+    "SolidColorBackground", "Sphere", "Torus", "TransparentMaterial", "TriangleFilter",
+    "Turbulence", "Twist", "Wood",
+];
+
 struct Tokenizer<'a> {
     acceptor: Acceptor<Chars<'a>>,
     error: Option<SyntaxError>,
+    // where the last token was read; the tokens of a binding are at
+    // the name of the binding
+    location: Location,
+    // the values of `let` bindings
+    bindings: HashMap<String, Vec<Token>>,
+    // the rest of the value of a binding that is being used, reversed
+    pending: Vec<Token>,
+    // a token read after a name, to tell fields from bindings
+    lookahead: Option<(Token, Location)>,
+    // textures that have been loaded, by path and whether they hold
+    // colors, so that a file is only loaded once
+    #[cfg(feature = "textures")]
+    textures: HashMap<(String, bool), Texture>,
 }
 
-impl<'a> Iterator for Tokenizer<'a> {
-    type Item = Token;
+impl<'a> Tokenizer<'a> {
+    fn new(text: &'a str) -> Tokenizer<'a> {
+        Tokenizer {
+            acceptor: Acceptor { iter: LL1::new(text.chars()) },
+            error: None,
+            location: Location { row: 1, col: 0 },
+            bindings: HashMap::new(),
+            pending: Vec::new(),
+            lookahead: None,
+            #[cfg(feature = "textures")]
+            textures: HashMap::new(),
+        }
+    }
 
-    fn next(&mut self) -> Option<Token> {
+    /// Record an error in a `let` binding, unless there already is
+    /// an error. Returns false.
+    fn binding_error(&mut self, desc: &str) -> bool {
+        if self.error.is_none() {
+            self.error = Some(SyntaxError { etype: SyntaxErrorType::Expect(format!("{} in let binding", desc)), location: self.acceptor.iter.location });
+        }
+        false
+    }
+
+    /// Read a binding after `let`: a name, `=`, and the value up to a
+    /// `;`. Bindings in the value are replaced by their values.
+    /// Returns false on errors.
+    fn define(&mut self) -> bool {
+        let name = match self.lex() {
+            Some(Token::Identifier(name)) => name,
+            _ => return self.binding_error("name"),
+        };
+        if RESERVED.contains(&name.as_ref()) || Aov::from_name(&name).is_some() {
+            return self.binding_error(&format!("a name other than {}", name));
+        }
+        match self.lex() {
+            Some(Token::Equals) => (),
+            _ => return self.binding_error("="),
+        }
+        let mut value = Vec::new();
+        let mut depth = 0;
+        loop {
+            match self.next() {
+                Some(Token::Semicolon) if depth == 0 => break,
+                Some(t) => {
+                    match t {
+                        Token::LBrace | Token::LBracket | Token::LParen => depth += 1,
+                        Token::RBrace | Token::RBracket | Token::RParen => depth -= 1,
+                        _ => (),
+                    }
+                    value.push(t);
+                },
+                None => return self.binding_error(";"),
+            }
+        }
+        if value.is_empty() {
+            return self.binding_error("value");
+        }
+        // arithmetic is grouped, so that it is evaluated before the
+        // expression that the binding is used in
+        if value.iter().all(|t| match *t { Token::Identifier(_) | Token::String(_) | Token::LBrace | Token::RBrace | Token::LBracket | Token::RBracket | Token::Colon | Token::Comma => false, _ => true }) {
+            value.insert(0, Token::LParen);
+            value.push(Token::RParen);
+        }
+        self.bindings.insert(name, value);
+        true
+    }
+
+    /// Read the next token from the text, without bindings.
+    fn lex(&mut self) -> Option<Token> {
         if self.error.is_some() { return None }
         self.acceptor.skip_while(|c: &char| {c.is_whitespace()});
         let c = {
@@ -385,27 +528,45 @@ impl<'a> Iterator for Tokenizer<'a> {
             ')' => {self.acceptor.skip(); Some(Token::RParen)},
             ':' => {self.acceptor.skip(); Some(Token::Colon)},
             ',' => {self.acceptor.skip(); Some(Token::Comma)},
-            '#' => {self.acceptor.skip_while(|c| {(*c) != '\n'}); self.next()},
+            '=' => {self.acceptor.skip(); Some(Token::Equals)},
+            ';' => {self.acceptor.skip(); Some(Token::Semicolon)},
+            '+' => {self.acceptor.skip(); Some(Token::Plus)},
+            '-' => {self.acceptor.skip(); Some(Token::Minus)},
+            '*' => {self.acceptor.skip(); Some(Token::Star)},
+            '#' => {self.acceptor.skip_while(|c| {(*c) != '\n'}); self.lex()},
             '/' => {
                 self.acceptor.skip(); // discard /
-                match self.acceptor.take() {
-                    Some('/') => {self.acceptor.skip_while(|c| {(*c) != '\n'});},
-                    Some('*') => {loop {
+                match self.acceptor.peek() {
+                    Some(&'/') => {self.acceptor.skip_while(|c| {(*c) != '\n'});},
+                    Some(&'*') => {loop {
+                        self.acceptor.skip(); // discard *
                         self.acceptor.skip_while(|c| {(*c) != '*'});
                         self.acceptor.skip(); // discard *
-                        if let Some('/') = self.acceptor.take() {break}
+                        match self.acceptor.peek() {
+                            Some(&'/') => {self.acceptor.skip(); break},
+                            None => break,
+                            _ => (),
+                        }
                     }},
-                    _ => {
-                        self.error = Some(SyntaxError { etype: SyntaxErrorType::InvalidToken, location: self.acceptor.iter.location });
-                        return None
-                    }
+                    _ => return Some(Token::Slash),
                 }
-                self.next()
+                self.lex()
             },
             '"' => {self.acceptor.skip(); Some(Token::String(parse_string_tok(&mut self.acceptor).collect()))},
             'A' ... 'Z' | 'a' ... 'z' | '_' => Some(Token::Identifier(self.acceptor.take_while(|c| {match *c {'A' ... 'Z' | 'a' ... 'z' | '0' ... '9' | '_' => true, _ => false}}).collect())),
-            '0' ... '9' | '.' | '-' | '+' => {
-                let num = self.acceptor.take_while(|c| {match *c {'A' ... 'Z' | 'a' ... 'z' | '0' ... '9' | '_' | '.' | '-' | '+' => true, _ => false}}).collect::<String>();
+            '0' ... '9' | '.' => {
+                // signs are only part of a number in an exponent
+                let mut num = String::new();
+                while let Some(&c) = self.acceptor.peek() {
+                    let exponent = num.ends_with('e') || num.ends_with('E');
+                    match c {
+                        'A' ... 'Z' | 'a' ... 'z' | '0' ... '9' | '_' | '.' => (),
+                        '-' | '+' if exponent => (),
+                        _ => break,
+                    }
+                    num.push(c);
+                    self.acceptor.skip();
+                }
                 match f64::from_str(&num) {
                     Ok(e) => Some(Token::Number(e)),
                     Err(e) => { self.error = Some(SyntaxError { etype: SyntaxErrorType::InvalidNumber { num: num, err: e }, location: self.acceptor.iter.location }); None }
@@ -416,35 +577,134 @@ impl<'a> Iterator for Tokenizer<'a> {
     }
 }
 
+impl<'a> Iterator for Tokenizer<'a> {
+    type Item = Token;
+
+    fn next(&mut self) -> Option<Token> {
+        loop {
+            if let Some(t) = self.pending.pop() {
+                return Some(t);
+            }
+            let token = match self.lookahead.take() {
+                Some((t, location)) => {
+                    self.location = location;
+                    Some(t)
+                },
+                None => {
+                    let t = self.lex();
+                    self.location = self.acceptor.iter.location;
+                    t
+                },
+            };
+            let name = match token {
+                Some(Token::Identifier(name)) => name,
+                t => return t,
+            };
+            if name == "let" {
+                if !self.define() {
+                    return None;
+                }
+                continue;
+            }
+            if !self.bindings.contains_key(&name) {
+                return Some(Token::Identifier(name));
+            }
+            // a field with the same name as a binding is not replaced
+            self.lookahead = self.lex().map(|t| (t, self.acceptor.iter.location));
+            if let Some((Token::Colon, _)) = self.lookahead {
+                return Some(Token::Identifier(name));
+            }
+            self.pending.extend(self.bindings[&name].iter().rev().cloned());
+        }
+    }
+}
+
 impl<'a> Processable for Tokenizer<'a> {
     fn process(&self, it: Option<Token>, loc: &mut Location) -> Option<Token> {
-        loc.set(&self.acceptor.iter.location);
+        loc.set(&self.location);
         it
     }
 }
 
 /// Deserialize the given text as a `Scene` object.
 pub fn deserialize(text: &String) -> Result<Scene, SyntaxError> {
-    let mut tokenizer = Acceptor {
-        iter: LL1::new(Tokenizer {
-            acceptor: Acceptor { iter: LL1::new(text.chars()) },
-            error: None
-        })
-    };
+    let mut tokenizer = Acceptor { iter: LL1::new(Tokenizer::new(text)) };
     // I would love to have a tail call here, but we need to
     // process a possible lex error.
     let result = parse_scene(&mut tokenizer);
-    match tokenizer.iter.iter.error {
-        Some(e) => Err(e),
-        None => result,
+    if let Some(e) = tokenizer.iter.iter.error {
+        return Err(e);
+    }
+    let mut scene = try!(result);
+    // now that the objects are known, the camera can focus on them
+    let location = tokenizer.iter.location;
+    {
+        let Scene { ref mut camera, ref objects, .. } = scene;
+        try!(camera.autofocus(objects).map_err(|e| SyntaxError { etype: SyntaxErrorType::Autofocus(e), location: location }));
     }
+    Ok(scene)
 }
 
-#[inline]
+/// Parse a number, which can be an arithmetic expression.
 fn parse_f64(toks: &mut Acceptor<Tokenizer>) -> Result<f64, SyntaxError> {
+    let mut value = try!(parse_product(toks));
+    loop {
+        if toks.accept(|t| match *t {Token::Plus => true, _ => false}).is_some() {
+            value += try!(parse_product(toks));
+        } else if toks.accept(|t| match *t {Token::Minus => true, _ => false}).is_some() {
+            value -= try!(parse_product(toks));
+        } else {
+            return Ok(value);
+        }
+    }
+}
+
+/// Parse a number that must be greater than 0.
+fn parse_positive(toks: &mut Acceptor<Tokenizer>) -> Result<f64, SyntaxError> {
+    let value = try!(parse_f64(toks));
+    if value > 0.0 {
+        Ok(value)
+    } else {
+        Err(SyntaxError { etype: SyntaxErrorType::Expect(format!("a positive number, not {}", value)), location: toks.iter.location })
+    }
+}
+
+fn parse_product(toks: &mut Acceptor<Tokenizer>) -> Result<f64, SyntaxError> {
+    let mut value = try!(parse_factor(toks));
+    loop {
+        if toks.accept(|t| match *t {Token::Star => true, _ => false}).is_some() {
+            value *= try!(parse_factor(toks));
+        } else if toks.accept(|t| match *t {Token::Slash => true, _ => false}).is_some() {
+            value /= try!(parse_factor(toks));
+        } else {
+            return Ok(value);
+        }
+    }
+}
+
+fn parse_factor(toks: &mut Acceptor<Tokenizer>) -> Result<f64, SyntaxError> {
+    if toks.accept(|t| match *t {Token::Minus => true, _ => false}).is_some() {
+        return parse_factor(toks).map(|x| -x);
+    }
+    if toks.accept(|t| match *t {Token::Plus => true, _ => false}).is_some() {
+        return parse_factor(toks);
+    }
+    if toks.accept(|t| match *t {Token::LParen => true, _ => false}).is_some() {
+        let value = try!(parse_f64(toks));
+        try!(toks.expect(|t| {match *t {Token::RParen => true, _ => false}}, "RParen"));
+        return Ok(value);
+    }
     Ok(match try!(toks.expect(|t| {match *t {Token::Number(_) => true, _ => false}}, "Number")) { Token::Number(x) => x, _ => panic!("at the disco") })
 }
 
+/// Whether a token can start a number.
+fn starts_number(t: &Token) -> bool {
+    match *t {
+        Token::Number(_) | Token::Plus | Token::Minus | Token::LParen => true,
+        _ => false,
+    }
+}
+
 #[inline]
 fn parse_i32(toks: &mut Acceptor<Tokenizer>) -> Result<i32, SyntaxError> {
     let num = try!(parse_f64(toks));
@@ -468,6 +728,18 @@ fn parse_u32(toks: &mut Acceptor<Tokenizer>) -> Result<u32, SyntaxError> {
     }
 }
 
+fn parse_bool(toks: &mut Acceptor<Tokenizer>) -> Result<bool, SyntaxError> {
+    if let Token::Identifier(value) = try!(toks.expect(|t| match *t {Token::Identifier(_) => true, _ => false}, "Identifier")) {
+        match value.as_ref() {
+            "true" => Ok(true),
+            "false" => Ok(false),
+            _ => Err(SyntaxError { etype: SyntaxErrorType::Expect("true or false".to_string()), location: toks.iter.location }),
+        }
+    } else {
+        panic!("at the disco");
+    }
+}
+
 #[inline]
 fn parse_string(toks: &mut Acceptor<Tokenizer>) -> Result<String, SyntaxError> {
     Ok(match try!(toks.expect(|t| {match *t {Token::String(_) => true, _ => false}}, "String")) { Token::String(x) => x, _ => panic!("at the disco") })
@@ -487,6 +759,15 @@ fn parse_ang(toks: &mut Acceptor<Tokenizer>) -> Result<f64, SyntaxError> {
     }
 }
 
+fn parse_vec2(toks: &mut Acceptor<Tokenizer>) -> Result<Vec2, SyntaxError> {
+    try!(toks.expect(|t| {match *t {Token::LParen => true, _ => false}}, "LParen"));
+    let x = try!(parse_f64(toks));
+    try!(toks.expect(|t| {match *t {Token::Comma => true, _ => false}}, "Comma"));
+    let y = try!(parse_f64(toks));
+    try!(toks.expect(|t| {match *t {Token::RParen => true, _ => false}}, "RParen"));
+    Ok(Vec2::new(x, y))
+}
+
 fn parse_vec3(toks: &mut Acceptor<Tokenizer>) -> Result<Vec3, SyntaxError> {
     try!(toks.expect(|t| {match *t {Token::LParen => true, _ => false}}, "LParen"));
     let x = try!(parse_f64(toks));
@@ -522,7 +803,7 @@ fn parse_color(toks: &mut Acceptor<Tokenizer>) -> Result<Color, SyntaxError> {
 }
 
 macro_rules! fn_parse_struct {
-    ( $name:ident ( $toks:ident ) -> $ty:ident { $( $field:ident : $parser:expr ),* $(,)* } ) => {
+    ( $name:ident ( $toks:ident ) -> $ty:ident { $( $field:ident : $parser:expr $(; default $default:expr)* ),* $(,)* } ) => {
         fn $name($toks: &mut Acceptor<Tokenizer>) -> Result<$ty, SyntaxError> {
             try!($toks.expect(|t| {match *t {Token::LBrace => true, _ => false}}, "LBrace"));
             // declare the field holders
@@ -541,6 +822,8 @@ macro_rules! fn_parse_struct {
                 }
             }
             // right brace accepted already
+            // fill in optional fields that were not given
+            $( $( let $field = $field.or_else(|| Some($default)); )* )*
             match ($($field,)*) {
                 ($(Some($field),)*) => Ok($ty { $($field: $field,)* }),
                 _ => Err(SyntaxError { etype: SyntaxErrorType::Missing, location: $toks.iter.location }),
@@ -603,6 +886,17 @@ fn parse_vec<E>(toks: &mut Acceptor<Tokenizer>, parser: fn(&mut Acceptor<Tokeniz
     Ok(result)
 }
 
+/// Parse a list of keyframes, which must not be empty, and sort them
+/// by time.
+fn parse_keyframes<T>(toks: &mut Acceptor<Tokenizer>, parser: fn(&mut Acceptor<Tokenizer>) -> Result<Keyframe<T>, SyntaxError>) -> Result<Vec<Keyframe<T>>, SyntaxError> {
+    let mut keyframes = try!(parse_vec(toks, parser));
+    if keyframes.is_empty() {
+        return Err(SyntaxError { etype: SyntaxErrorType::Expect("at least one keyframe".to_string()), location: toks.iter.location });
+    }
+    keyframes.sort_by(|a, b| a.time.partial_cmp(&b.time).unwrap_or(Ordering::Equal));
+    Ok(keyframes)
+}
+
 fn_parse_struct!(
     parse_sphere(toks) -> Sphere {
         center: parse_pnt3(toks),
@@ -617,10 +911,224 @@ fn_parse_struct!(
     }
 );
 
+fn_parse_struct!(
+    parse_transform(toks) -> Transform {
+        translation: parse_vec3(toks); default Vec3::new(0.0, 0.0, 0.0),
+        rotation: parse_vec3(toks); default Vec3::new(0.0, 0.0, 0.0),
+        scale: parse_f64(toks); default 1.0,
+    }
+);
+
+type TransformKeyframe = Keyframe<Transform>;
+
+fn_parse_struct!(
+    parse_transform_keyframe(toks) -> TransformKeyframe {
+        time: parse_f64(toks),
+        value: parse_transform(toks),
+    }
+);
+
+fn_parse_struct!(
+    parse_animated_shape(toks) -> AnimatedShape {
+        shape: parse_box_shape(toks),
+        keyframes: parse_keyframes(toks, parse_transform_keyframe),
+    }
+);
+
 fn_parse_box!(
     parse_box_shape(toks) -> Shape {
         Sphere => parse_sphere(toks),
         Plane => parse_plane(toks),
+        AnimatedShape => parse_animated_shape(toks),
+        Csg => parse_csg(toks),
+        AxisAlignedBox => parse_axis_aligned_box(toks),
+        OrientedBox => parse_oriented_box(toks),
+        Cylinder => parse_cylinder(toks),
+        Cone => parse_cone(toks),
+        Disk => parse_disk(toks),
+        Rectangle => parse_rectangle(toks),
+        Torus => parse_torus(toks),
+        Sdf => parse_sdf(toks),
+        Heightfield => parse_heightfield(toks),
+    }
+);
+
+#[cfg(feature = "textures")]
+fn_parse_function!(
+    parse_heightfield(toks) -> Heightfield
+    new(
+        texture: parse_load_texture_as(toks, false),
+        min: parse_pnt3(toks),
+        size: parse_vec3(toks),
+    ) => if texture.width() < 2 || texture.height() < 2 {
+        Err(SyntaxError { etype: SyntaxErrorType::Expect("a heightfield image of at least 2x2 pixels".to_string()), location: toks.iter.location })
+    } else {
+        Ok(Heightfield::from_texture(&texture, min, size))
+    }
+);
+
+#[cfg(not(feature = "textures"))]
+fn parse_heightfield(_: &mut Acceptor<Tokenizer>) -> Result<Heightfield, SyntaxError> { panic!("heightfields not implemented") }
+
+fn_parse_struct!(
+    parse_sdf(toks) -> Sdf {
+        node: parse_box_distance_field(toks),
+        min: parse_pnt3(toks),
+        max: parse_pnt3(toks),
+        epsilon: parse_f64(toks); default 0.0001,
+        max_steps: parse_u32(toks); default 256,
+        step: parse_f64(toks); default 1.0,
+    }
+);
+
+fn_parse_box!(
+    parse_box_distance_field(toks) -> DistanceField {
+        SdfSphere => parse_sdf_sphere(toks),
+        SdfBox => parse_sdf_box(toks),
+        SdfTorus => parse_sdf_torus(toks),
+        SdfCapsule => parse_sdf_capsule(toks),
+        SmoothUnion => parse_smooth_union(toks),
+        Blend => parse_blend(toks),
+        Repeat => parse_repeat(toks),
+        Twist => parse_twist(toks),
+    }
+);
+
+fn_parse_struct!(
+    parse_sdf_sphere(toks) -> SdfSphere {
+        center: parse_pnt3(toks),
+        radius: parse_f64(toks),
+    }
+);
+
+fn_parse_struct!(
+    parse_sdf_box(toks) -> SdfBox {
+        center: parse_pnt3(toks),
+        size: parse_vec3(toks),
+        rounding: parse_f64(toks); default 0.0,
+    }
+);
+
+fn_parse_struct!(
+    parse_sdf_torus(toks) -> SdfTorus {
+        center: parse_pnt3(toks),
+        major_radius: parse_f64(toks),
+        minor_radius: parse_f64(toks),
+    }
+);
+
+fn_parse_struct!(
+    parse_sdf_capsule(toks) -> SdfCapsule {
+        start: parse_pnt3(toks),
+        end: parse_pnt3(toks),
+        radius: parse_f64(toks),
+    }
+);
+
+fn_parse_struct!(
+    parse_smooth_union(toks) -> SmoothUnion {
+        left: parse_box_distance_field(toks),
+        right: parse_box_distance_field(toks),
+        smoothness: parse_f64(toks); default 0.0,
+    }
+);
+
+fn_parse_struct!(
+    parse_blend(toks) -> Blend {
+        left: parse_box_distance_field(toks),
+        right: parse_box_distance_field(toks),
+        amount: parse_f64(toks),
+    }
+);
+
+fn_parse_struct!(
+    parse_repeat(toks) -> Repeat {
+        node: parse_box_distance_field(toks),
+        period: parse_vec3(toks),
+    }
+);
+
+fn_parse_struct!(
+    parse_twist(toks) -> Twist {
+        node: parse_box_distance_field(toks),
+        rate: parse_f64(toks),
+    }
+);
+
+fn_parse_struct!(
+    parse_axis_aligned_box(toks) -> AxisAlignedBox {
+        min: parse_pnt3(toks),
+        max: parse_pnt3(toks),
+    }
+);
+
+fn_parse_struct!(
+    parse_oriented_box(toks) -> OrientedBox {
+        center: parse_pnt3(toks),
+        size: parse_vec3(toks),
+        rotation: parse_vec3(toks); default Vec3::new(0.0, 0.0, 0.0),
+    }
+);
+
+fn_parse_struct!(
+    parse_cylinder(toks) -> Cylinder {
+        base: parse_pnt3(toks),
+        axis: parse_vec3(toks),
+        radius: parse_f64(toks),
+    }
+);
+
+fn_parse_struct!(
+    parse_cone(toks) -> Cone {
+        base: parse_pnt3(toks),
+        axis: parse_vec3(toks),
+        radius: parse_f64(toks),
+    }
+);
+
+fn_parse_struct!(
+    parse_disk(toks) -> Disk {
+        center: parse_pnt3(toks),
+        normal: parse_vec3(toks),
+        radius: parse_f64(toks),
+    }
+);
+
+fn_parse_struct!(
+    parse_rectangle(toks) -> Rectangle {
+        corner: parse_pnt3(toks),
+        side1: parse_vec3(toks),
+        side2: parse_vec3(toks),
+    }
+);
+
+fn_parse_struct!(
+    parse_torus(toks) -> Torus {
+        center: parse_pnt3(toks),
+        axis: parse_vec3(toks),
+        major_radius: parse_f64(toks),
+        minor_radius: parse_f64(toks),
+    }
+);
+
+fn parse_csg_operation(toks: &mut Acceptor<Tokenizer>) -> Result<CsgOperation, SyntaxError> {
+    if let Token::Identifier(op) = try!(toks.expect(|t| match *t {Token::Identifier(_) => true, _ => false}, "Identifier")) {
+        match op.as_ref() {
+            "union" => Ok(CsgOperation::Union),
+            "intersection" => Ok(CsgOperation::Intersection),
+            "difference" => Ok(CsgOperation::Difference),
+            _ => Err(SyntaxError { etype: SyntaxErrorType::NoClass(op), location: toks.iter.location }),
+        }
+    } else {
+        panic!("at the disco");
+    }
+}
+
+fn_parse_struct!(
+    parse_csg(toks) -> Csg {
+        operation: parse_csg_operation(toks),
+        left: parse_box_shape(toks),
+        right: parse_box_shape(toks),
     }
 );
 
@@ -645,73 +1153,381 @@ fn_parse_function!(
     ) => Ok(SimplePerspectiveCamera::look_at(&focus, &look, &up, pov, h))
 );
 
+fn_parse_function!(
+    parse_physical_spc(toks) -> SimplePerspectiveCamera
+    physical(
+        position: parse_pnt3(toks),
+        look: parse_vec3(toks),
+        up: parse_vec3(toks),
+        focal_length: parse_f64(toks),
+        sensor: parse_vec2(toks),
+        shift: parse_vec2(toks),
+    ) => Ok(SimplePerspectiveCamera::physical(&position, &look, &up, focal_length, &sensor, &shift))
+);
+
+fn parse_spc(toks: &mut Acceptor<Tokenizer>) -> Result<SimplePerspectiveCamera, SyntaxError> {
+    parse_new_spc(toks).or_else(|_| parse_look_at_spc(toks)).or_else(|_| parse_physical_spc(toks))
+}
+
+/// Aperture as written in a scene file; the size is given either
+/// as a radius or as a focal length and f-number.
+struct ApertureParams {
+    radius: Option<f64>,
+    focal_length: Option<f64>,
+    f_stop: Option<f64>,
+    blades: u32,
+    rotation: f64,
+    cats_eye: f64,
+}
+
+fn_parse_struct!(
+    parse_aperture_params(toks) -> ApertureParams {
+        radius: parse_f64(toks).map(Some); default None,
+        focal_length: parse_f64(toks).map(Some); default None,
+        f_stop: parse_f64(toks).map(Some); default None,
+        blades: parse_u32(toks); default 0,
+        rotation: parse_ang(toks); default 0.0,
+        cats_eye: parse_f64(toks); default 0.0,
+    }
+);
+
+fn parse_aperture(toks: &mut Acceptor<Tokenizer>) -> Result<Aperture, SyntaxError> {
+    if toks.peek().map_or(false, starts_number) {
+        return Ok(Aperture::circle(try!(parse_f64(toks))));
+    }
+    let params = try!(parse_aperture_params(toks));
+    let mut aperture = match (params.radius, params.focal_length, params.f_stop) {
+        (Some(r), None, None) => Aperture::circle(r),
+        (None, Some(f), Some(n)) => Aperture::from_f_stop(f, n),
+        _ => return Err(SyntaxError { etype: SyntaxErrorType::Expect("either radius or focal_length and f_stop".to_string()), location: toks.iter.location }),
+    };
+    aperture.blades = params.blades;
+    aperture.rotation = params.rotation;
+    aperture.cats_eye = params.cats_eye;
+    Ok(aperture)
+}
+
+fn_parse_function!(
+    parse_point_focus(toks) -> Focus
+    point(
+        point: parse_pnt3(toks),
+    ) => Ok(Focus::Point(point))
+);
+
+fn_parse_function!(
+    parse_object_focus(toks) -> Focus
+    object(
+        name: parse_string(toks),
+    ) => Ok(Focus::Object(name))
+);
+
+fn parse_focus(toks: &mut Acceptor<Tokenizer>) -> Result<Focus, SyntaxError> {
+    if toks.peek().map_or(false, starts_number) {
+        return Ok(Focus::Distance(try!(parse_f64(toks))));
+    }
+    parse_point_focus(toks).or_else(|_| parse_object_focus(toks))
+}
+
 fn_parse_function!(
     parse_new_dofc(toks) -> DepthOfFieldCamera
     new(
-        camera: parse_new_spc(toks).or_else(|_| parse_look_at_spc(toks)),
-        focus: parse_f64(toks),
-        aperture: parse_f64(toks),
+        camera: parse_spc(toks),
+        focus: parse_focus(toks),
+        aperture: parse_aperture(toks),
         samples: parse_u32(toks),
-    ) => Ok(DepthOfFieldCamera::new(camera, focus, aperture, samples))
+    ) => Ok(DepthOfFieldCamera::with_aperture(camera, focus, aperture, samples))
+);
+
+fn_parse_function!(
+    parse_new_erc(toks) -> EquirectangularCamera
+    new(
+        position: parse_pnt3(toks),
+        look: parse_vec3(toks),
+        up: parse_vec3(toks),
+    ) => Ok(EquirectangularCamera::new(&position, &look, &up))
+);
+
+fn parse_fisheye_mapping(toks: &mut Acceptor<Tokenizer>) -> Result<FisheyeMapping, SyntaxError> {
+    if let Token::Identifier(mapping) = try!(toks.expect(|t| match *t {Token::Identifier(_) => true, _ => false}, "Identifier")) {
+        match mapping.as_ref() {
+            "equidistant" => Ok(FisheyeMapping::Equidistant),
+            "equisolid" => Ok(FisheyeMapping::Equisolid),
+            _ => Err(SyntaxError { etype: SyntaxErrorType::NoClass(mapping), location: toks.iter.location }),
+        }
+    } else {
+        panic!("at the disco");
+    }
+}
+
+fn_parse_function!(
+    parse_new_fec(toks) -> FisheyeCamera
+    new(
+        position: parse_pnt3(toks),
+        look: parse_vec3(toks),
+        up: parse_vec3(toks),
+        fov: parse_ang(toks),
+        mapping: parse_fisheye_mapping(toks),
+    ) => Ok(FisheyeCamera::new(&position, &look, &up, fov, mapping))
+);
+
+type CameraKeyframe = Keyframe<SimplePerspectiveCamera>;
+
+fn_parse_struct!(
+    parse_camera_keyframe(toks) -> CameraKeyframe {
+        time: parse_f64(toks),
+        value: parse_spc(toks),
+    }
+);
+
+fn_parse_struct!(
+    parse_animated_camera(toks) -> AnimatedCamera {
+        keyframes: parse_keyframes(toks, parse_camera_keyframe),
+    }
 );
 
 fn_parse_box!(
     parse_box_camera(toks) -> Camera {
-        SimplePerspectiveCamera => parse_new_spc(toks).or_else(|_| parse_look_at_spc(toks)),
+        SimplePerspectiveCamera => parse_spc(toks),
         DepthOfFieldCamera => parse_new_dofc(toks),
+        EquirectangularCamera => parse_new_erc(toks),
+        FisheyeCamera => parse_new_fec(toks),
+        AnimatedCamera => parse_animated_camera(toks),
+    }
+);
+
+#[cfg(feature = "textures")]
+fn_parse_struct!(
+    parse_image_texture(toks) -> ImageTexture {
+        texture: parse_load_texture(toks),
+        scale: parse_f64(toks); default 1.0,
+        multiplier: parse_f64(toks); default 1.0,
+        alpha: parse_bool(toks); default false,
+    }
+);
+
+fn parse_pattern_space(toks: &mut Acceptor<Tokenizer>) -> Result<PatternSpace, SyntaxError> {
+    if let Token::Identifier(space) = try!(toks.expect(|t| match *t {Token::Identifier(_) => true, _ => false}, "Identifier")) {
+        match space.as_ref() {
+            "uv" => Ok(PatternSpace::Uv),
+            "world" => Ok(PatternSpace::World),
+            _ => Err(SyntaxError { etype: SyntaxErrorType::NoClass(space), location: toks.iter.location }),
+        }
+    } else {
+        panic!("at the disco");
+    }
+}
+
+/// Parameters shared by all patterns; each pattern only uses the
+/// ones that apply to it.
+struct PatternParams {
+    space: PatternSpace,
+    scale: f64,
+    octaves: u32,
+    lacunarity: f64,
+    gain: f64,
+    frequency: f64,
+    distortion: f64,
+    seed: u32,
+}
+
+impl PatternParams {
+    fn coords(&self) -> PatternCoords {
+        PatternCoords { space: self.space, scale: self.scale }
+    }
+}
+
+fn_parse_struct!(
+    parse_pattern_params(toks) -> PatternParams {
+        space: parse_pattern_space(toks); default PatternSpace::World,
+        scale: parse_f64(toks); default 1.0,
+        octaves: parse_u32(toks); default 1,
+        lacunarity: parse_f64(toks); default 2.0,
+        gain: parse_f64(toks); default 0.5,
+        frequency: parse_f64(toks); default 1.0,
+        distortion: parse_f64(toks); default 0.0,
+        seed: parse_u32(toks); default 0,
+    }
+);
+
+fn_parse_box!(
+    parse_box_pattern(toks) -> Pattern {
+        Checker => parse_pattern_params(toks).map(|p| Checker { coords: p.coords() }),
+        Noise => parse_pattern_params(toks).map(|p| Noise { coords: p.coords(), octaves: p.octaves, lacunarity: p.lacunarity, gain: p.gain, perlin: Perlin::new(p.seed) }),
+        Turbulence => parse_pattern_params(toks).map(|p| Turbulence { coords: p.coords(), octaves: p.octaves, lacunarity: p.lacunarity, gain: p.gain, perlin: Perlin::new(p.seed) }),
+        Marble => parse_pattern_params(toks).map(|p| Marble { coords: p.coords(), frequency: p.frequency, distortion: p.distortion, octaves: p.octaves, perlin: Perlin::new(p.seed) }),
+        Wood => parse_pattern_params(toks).map(|p| Wood { coords: p.coords(), frequency: p.frequency, distortion: p.distortion, octaves: p.octaves, perlin: Perlin::new(p.seed) }),
+    }
+);
+
+fn_parse_struct!(
+    parse_color_stop(toks) -> ColorStop {
+        at: parse_f64(toks),
+        color: parse_color(toks),
+    }
+);
+
+fn parse_color_ramp(toks: &mut Acceptor<Tokenizer>) -> Result<ColorRamp, SyntaxError> {
+    let mut stops = try!(parse_vec(toks, parse_color_stop));
+    if stops.is_empty() {
+        return Err(SyntaxError { etype: SyntaxErrorType::Expect("at least one color stop".to_string()), location: toks.iter.location });
+    }
+    stops.sort_by(|a, b| a.at.partial_cmp(&b.at).unwrap_or(Ordering::Equal));
+    Ok(ColorRamp { stops: stops })
+}
+
+fn_parse_struct!(
+    parse_color_pattern(toks) -> ColorPattern {
+        pattern: parse_box_pattern(toks),
+        ramp: parse_color_ramp(toks),
+    }
+);
+
+fn_parse_struct!(
+    parse_scalar_pattern(toks) -> ScalarPattern {
+        pattern: parse_box_pattern(toks),
+        low: parse_f64(toks); default 0.0,
+        high: parse_f64(toks); default 1.0,
+    }
+);
+
+#[cfg(feature = "textures")]
+fn_parse_box!(
+    parse_color_texture(toks) -> ColorParam {
+        ImageTexture => parse_image_texture(toks).map(ColorParam::Texture),
+        Pattern => parse_color_pattern(toks).map(ColorParam::Pattern),
+    }
+);
+
+#[cfg(feature = "textures")]
+fn_parse_box!(
+    parse_scalar_texture(toks) -> ScalarParam {
+        ImageTexture => parse_image_texture(toks).map(ScalarParam::Texture),
+        Pattern => parse_scalar_pattern(toks).map(ScalarParam::Pattern),
     }
 );
 
+#[cfg(not(feature = "textures"))]
+fn_parse_box!(
+    parse_color_texture(toks) -> ColorParam {
+        Pattern => parse_color_pattern(toks).map(ColorParam::Pattern),
+    }
+);
+
+#[cfg(not(feature = "textures"))]
+fn_parse_box!(
+    parse_scalar_texture(toks) -> ScalarParam {
+        Pattern => parse_scalar_pattern(toks).map(ScalarParam::Pattern),
+    }
+);
+
+fn parse_color_param(toks: &mut Acceptor<Tokenizer>) -> Result<ColorParam, SyntaxError> {
+    let textured = match toks.peek() { Some(&Token::Identifier(ref x)) => x != "rgb", _ => false };
+    if textured {
+        parse_color_texture(toks).map(|p| *p)
+    } else {
+        Ok(ColorParam::Constant(try!(parse_color(toks))))
+    }
+}
+
+fn parse_scalar_param(toks: &mut Acceptor<Tokenizer>) -> Result<ScalarParam, SyntaxError> {
+    let textured = match toks.peek() { Some(&Token::Identifier(_)) => true, _ => false };
+    if textured {
+        parse_scalar_texture(toks).map(|p| *p)
+    } else {
+        Ok(ScalarParam::Constant(try!(parse_f64(toks))))
+    }
+}
+
 fn_parse_box!(
     parse_box_material(toks) -> Material {
         PhongMaterial => parse_phong_material(toks),
         IndirectPhongMaterial => parse_indirect_phong_material(toks),
         FresnelMaterial => parse_fresnel_material(toks),
         TransparentMaterial => parse_transparent_material(toks),
+        BumpMappedMaterial => parse_bump_mapped_material(toks),
+        NormalMappedMaterial => parse_normal_mapped_material(toks),
     }
 );
 
+fn_parse_struct!(
+    parse_bump_mapped_material(toks) -> BumpMappedMaterial {
+        material: parse_box_material(toks),
+        height: parse_scalar_param(toks),
+        strength: parse_f64(toks); default 1.0,
+    }
+);
+
+#[cfg(feature = "textures")]
+fn_parse_struct!(
+    parse_normal_mapped_material(toks) -> NormalMappedMaterial {
+        material: parse_box_material(toks),
+        normal_map: parse_image_texture_class(toks).map(|t| *t),
+        strength: parse_f64(toks); default 1.0,
+    }
+);
+
+#[cfg(feature = "textures")]
+fn_parse_box!(
+    parse_image_texture_class(toks) -> ImageTexture {
+        ImageTexture => parse_image_texture(toks),
+    }
+);
+
+#[cfg(not(feature = "textures"))]
+struct NormalMappedMaterial;
+#[cfg(not(feature = "textures"))]
+impl Material for NormalMappedMaterial {
+    fn color(&self, _: &Scene, _: &IntersectionResult, _: &Ray, _: f64, _: u32, _: &mut ::types::RngT) -> Color {
+        panic!("no textures; how did you even get here")
+    }
+}
+
+#[cfg(not(feature = "textures"))]
+fn parse_normal_mapped_material(_: &mut Acceptor<Tokenizer>) -> Result<NormalMappedMaterial, SyntaxError> { panic!("normal maps not implemented") }
+
 fn_parse_struct!(
     parse_phong_material(toks) -> PhongMaterial {
-        diffuse: parse_color(toks),
-        specular: parse_color(toks),
-        exponent: parse_f64(toks),
-        ambient: parse_color(toks),
+        diffuse: parse_color_param(toks),
+        specular: parse_color_param(toks),
+        exponent: parse_scalar_param(toks),
+        ambient: parse_color_param(toks),
     }
 );
 
 fn_parse_struct!(
     parse_indirect_phong_material(toks) -> IndirectPhongMaterial {
-        diffuse: parse_color(toks),
-        specular: parse_color(toks),
-        exponent: parse_f64(toks),
-        ambient: parse_color(toks),
+        diffuse: parse_color_param(toks),
+        specular: parse_color_param(toks),
+        exponent: parse_scalar_param(toks),
+        ambient: parse_color_param(toks),
         samples: parse_u32(toks),
     }
 );
 
 fn_parse_struct!(
     parse_fresnel_material(toks) -> FresnelMaterial {
-        diffuse: parse_color(toks),
-        specular: parse_color(toks),
-        exponent: parse_f64(toks),
-        ambient: parse_color(toks),
+        diffuse: parse_color_param(toks),
+        specular: parse_color_param(toks),
+        exponent: parse_scalar_param(toks),
+        ambient: parse_color_param(toks),
         ior: parse_f64(toks),
     }
 );
 
 fn_parse_struct!(
     parse_transparent_material(toks) -> TransparentMaterial {
-        specular: parse_color(toks),
-        exponent: parse_f64(toks),
+        specular: parse_color_param(toks),
+        exponent: parse_scalar_param(toks),
         ior: parse_f64(toks),
     }
 );
 
 fn_parse_struct!(
     parse_object(toks) -> Object {
+        name: parse_string(toks).map(Some); default None,
         bounds: parse_box_shape(toks),
         material: parse_box_material(toks),
+        material_id: parse_u32(toks); default 0,
     }
 );
 
@@ -756,14 +1572,72 @@ fn_parse_struct!(
     }
 );
 
-#[cfg(feature = "skybox")]
-fn_parse_function!(
-    parse_load_texture(toks) -> Texture
-    load(
-        path: parse_string(toks),
-    ) => Texture::load(path.clone()).map_err(|err| {SyntaxError { etype: SyntaxErrorType::TextureLoad { path: path, err: texture::error_description(err) }, location: toks.iter.location }})
+#[cfg(feature = "textures")]
+fn parse_wrap(toks: &mut Acceptor<Tokenizer>) -> Result<Wrap, SyntaxError> {
+    if let Token::Identifier(wrap) = try!(toks.expect(|t| match *t {Token::Identifier(_) => true, _ => false}, "Identifier")) {
+        match wrap.as_ref() {
+            "clamp" => Ok(Wrap::Clamp),
+            "repeat" => Ok(Wrap::Repeat),
+            "mirror" => Ok(Wrap::Mirror),
+            "border" => Ok(Wrap::Border),
+            _ => Err(SyntaxError { etype: SyntaxErrorType::NoClass(wrap), location: toks.iter.location }),
+        }
+    } else {
+        panic!("at the disco");
+    }
+}
+
+/// Texture options as written in a scene file; whether the texture
+/// holds colors defaults to what it is used for.
+#[cfg(feature = "textures")]
+struct TextureParams {
+    wrap: Wrap,
+    border: Color,
+    border_alpha: f64,
+    color: Option<bool>,
+}
+
+#[cfg(feature = "textures")]
+fn_parse_struct!(
+    parse_texture_params(toks) -> TextureParams {
+        wrap: parse_wrap(toks); default Wrap::Clamp,
+        border: parse_color(toks); default BLACK,
+        border_alpha: parse_f64(toks); default 0.0,
+        color: parse_bool(toks).map(Some); default None,
+    }
 );
 
+/// Parse `load("path")` or `load("path", { options })` for a texture
+/// that holds colors by default.
+#[cfg(feature = "textures")]
+fn parse_load_texture(toks: &mut Acceptor<Tokenizer>) -> Result<Texture, SyntaxError> {
+    parse_load_texture_as(toks, true)
+}
+
+/// Parse `load("path")` or `load("path", { options })`, where `color`
+/// is whether the texture holds colors unless the options say so. A
+/// file is only loaded once, and every use of it shares the pixels.
+#[cfg(feature = "textures")]
+fn parse_load_texture_as(toks: &mut Acceptor<Tokenizer>, color: bool) -> Result<Texture, SyntaxError> {
+    try!(toks.expect(|t| {match *t {Token::Identifier(ref x) => x == "load", _ => false}}, "Identifier(\"load\")"));
+    try!(toks.expect(|t| {match *t {Token::LParen => true, _ => false}}, "LParen"));
+    let path = try!(parse_string(toks));
+    let options = if toks.accept(|t| {match *t {Token::Comma => true, _ => false}}).is_some() {
+        let params = try!(parse_texture_params(toks));
+        TextureOptions { wrap: params.wrap, border: params.border, border_alpha: params.border_alpha, color: params.color.unwrap_or(color) }
+    } else {
+        TextureOptions { color: color, ..TextureOptions::default() }
+    };
+    try!(toks.expect(|t| {match *t {Token::RParen => true, _ => false}}, "RParen"));
+    let key = (path, options.color);
+    if let Some(texture) = toks.iter.iter.textures.get(&key) {
+        return Ok(texture.with_options(&options));
+    }
+    let texture = try!(Texture::load_with(key.0.clone(), &options).map_err(|err| {SyntaxError { etype: SyntaxErrorType::TextureLoad { path: key.0.clone(), err: texture::error_description(err) }, location: toks.iter.location }}));
+    toks.iter.iter.textures.insert(key, texture.clone());
+    Ok(texture)
+}
+
 #[cfg(feature = "skybox")]
 fn_parse_struct!(
     parse_skybox_background(toks) -> SkyboxBackground {
@@ -796,10 +1670,244 @@ fn_parse_box!(
 );
 
 fn_parse_struct!(
-    parse_options(toks) -> Options {
+    parse_medium(toks) -> Medium {
+        absorption: parse_color(toks),
+        scattering: parse_color(toks),
+        asymmetry: parse_f64(toks); default 0.0,
+    }
+);
+
+fn_parse_struct!(
+    parse_volume(toks) -> Volume {
+        medium: parse_medium(toks),
+        bounds: parse_box_shape(toks).map(Some); default None,
+    }
+);
+
+fn_parse_function!(
+    parse_vol_grid(toks) -> VoxelGrid
+    load(
+        path: parse_string(toks),
+    ) => VoxelGrid::load_vol(path.clone()).map_err(|err| {SyntaxError { etype: SyntaxErrorType::VoxelLoad { path: path, err: format!("{}", err) }, location: toks.iter.location }})
+);
+
+fn_parse_function!(
+    parse_raw_grid(toks) -> VoxelGrid
+    raw(
+        path: parse_string(toks),
+        nx: parse_u32(toks),
+        ny: parse_u32(toks),
+        nz: parse_u32(toks),
+    ) => VoxelGrid::load_raw(path.clone(), nx as usize, ny as usize, nz as usize).map_err(|err| {SyntaxError { etype: SyntaxErrorType::VoxelLoad { path: path, err: format!("{}", err) }, location: toks.iter.location }})
+);
+
+/// Parse `load("file.vol")` or `raw("file.raw", nx, ny, nz)`.
+fn parse_voxel_grid(toks: &mut Acceptor<Tokenizer>) -> Result<VoxelGrid, SyntaxError> {
+    let raw = match toks.peek() {
+        Some(&Token::Identifier(ref x)) => x == "raw",
+        _ => false,
+    };
+    if raw { parse_raw_grid(toks) } else { parse_vol_grid(toks) }
+}
+
+fn_parse_struct!(
+    parse_grid_volume(toks) -> GridVolume {
+        grid: parse_voxel_grid(toks),
+        min: parse_pnt3(toks),
+        max: parse_pnt3(toks),
+        density_scale: parse_f64(toks); default 1.0,
+        albedo: parse_color(toks),
+        asymmetry: parse_f64(toks); default 0.0,
+    }
+);
+
+fn_parse_struct!(
+    parse_shutter(toks) -> Shutter {
+        open: parse_f64(toks),
+        close: parse_f64(toks),
+    }
+);
+
+fn_parse_struct!(
+    parse_adaptive_sampling(toks) -> AdaptiveSampling {
+        max_samples: parse_u32(toks),
+        error: parse_f64(toks); default 0.01,
+    }
+);
+
+fn_parse_struct!(
+    parse_crop_fields(toks) -> Crop {
+        x: parse_u32(toks),
+        y: parse_u32(toks),
+        width: parse_u32(toks),
+        height: parse_u32(toks),
+        merge: parse_bool(toks); default false,
+    }
+);
+
+/// Parse a crop rectangle that is not empty.
+fn parse_crop(toks: &mut Acceptor<Tokenizer>) -> Result<Crop, SyntaxError> {
+    let crop = try!(parse_crop_fields(toks));
+    if crop.width == 0 || crop.height == 0 {
+        return Err(SyntaxError { etype: SyntaxErrorType::Expect(format!("a crop rectangle that is not empty, not {}x{}", crop.width, crop.height)), location: toks.iter.location });
+    }
+    Ok(crop)
+}
+
+fn_parse_struct!(
+    parse_progressive(toks) -> Progressive {
+        samples: parse_u32(toks); default 1,
+        checkpoint_passes: parse_u32(toks); default 0,
+        checkpoint_interval: parse_f64(toks); default 60.0,
+    }
+);
+
+fn_parse_struct!(
+    parse_options_fields(toks) -> Options {
         width: parse_u32(toks),
         height: parse_u32(toks),
+        crop: parse_crop(toks).map(Some); default None,
+        gate_fit: parse_gate_fit(toks); default GateFit::Fit,
+        pixel_aspect: parse_f64(toks); default 1.0,
         antialias: parse_u32(toks),
+        adaptive: parse_adaptive_sampling(toks).map(Some); default None,
+        progressive: parse_progressive(toks).map(Some); default None,
+        sampler: parse_sampler_kind(toks); default SamplerKind::Random,
+        filter: parse_box_filter(toks); default Box::new(BoxFilter { radius: 0.5 }),
+        aovs: parse_vec(toks, parse_aov); default Vec::new(),
+        denoise: parse_denoiser(toks).map(Some); default None,
+        shutter: parse_shutter(toks); default Shutter::instant(),
+        multiple_scattering: parse_bool(toks); default false,
+        mode: parse_render_mode(toks); default RenderMode::Shaded,
+        occlusion: parse_ambient_occlusion(toks); default AmbientOcclusion::default(),
+        occlude_ambient: parse_bool(toks); default false,
+    }
+);
+
+/// Parse the options, checking that the crop rectangle is inside of
+/// the image.
+fn parse_options(toks: &mut Acceptor<Tokenizer>) -> Result<Options, SyntaxError> {
+    let options = try!(parse_options_fields(toks));
+    if let Some(ref crop) = options.crop {
+        if crop.x >= options.width || crop.y >= options.height || crop.width > options.width - crop.x || crop.height > options.height - crop.y {
+            return Err(SyntaxError {
+                etype: SyntaxErrorType::Expect(format!("a crop rectangle inside of the {}x{} image", options.width, options.height)),
+                location: toks.iter.location,
+            });
+        }
+    }
+    Ok(options)
+}
+
+fn_parse_box!(
+    parse_box_filter(toks) -> Filter {
+        BoxFilter => parse_box_filter_class(toks),
+        TriangleFilter => parse_triangle_filter(toks),
+        GaussianFilter => parse_gaussian_filter(toks),
+        MitchellFilter => parse_mitchell_filter(toks),
+        LanczosFilter => parse_lanczos_filter(toks),
+    }
+);
+
+fn_parse_struct!(
+    parse_box_filter_class(toks) -> BoxFilter {
+        radius: parse_f64(toks); default 0.5,
+    }
+);
+
+fn_parse_struct!(
+    parse_triangle_filter(toks) -> TriangleFilter {
+        radius: parse_f64(toks); default 1.0,
+    }
+);
+
+fn_parse_struct!(
+    parse_gaussian_filter(toks) -> GaussianFilter {
+        radius: parse_f64(toks); default 1.5,
+        alpha: parse_f64(toks); default 2.0,
+    }
+);
+
+fn_parse_struct!(
+    parse_mitchell_filter(toks) -> MitchellFilter {
+        radius: parse_f64(toks); default 2.0,
+        b: parse_f64(toks); default 1.0 / 3.0,
+        c: parse_f64(toks); default 1.0 / 3.0,
+    }
+);
+
+fn_parse_struct!(
+    parse_lanczos_filter(toks) -> LanczosFilter {
+        radius: parse_f64(toks); default 3.0,
+        tau: parse_f64(toks); default 3.0,
+    }
+);
+
+fn_parse_struct!(
+    parse_denoiser(toks) -> Denoiser {
+        radius: parse_u32(toks); default 6,
+        spatial: parse_positive(toks); default 3.0,
+        color: parse_positive(toks); default 0.3,
+        albedo: parse_positive(toks); default 0.1,
+        normal: parse_positive(toks); default 0.3,
+    }
+);
+
+fn parse_aov(toks: &mut Acceptor<Tokenizer>) -> Result<Aov, SyntaxError> {
+    if let Token::Identifier(name) = try!(toks.expect(|t| match *t {Token::Identifier(_) => true, _ => false}, "Identifier")) {
+        match Aov::from_name(&name) {
+            Some(aov) => Ok(aov),
+            None => Err(SyntaxError { etype: SyntaxErrorType::NoClass(name), location: toks.iter.location }),
+        }
+    } else {
+        panic!("at the disco");
+    }
+}
+
+fn parse_gate_fit(toks: &mut Acceptor<Tokenizer>) -> Result<GateFit, SyntaxError> {
+    if let Token::Identifier(fit) = try!(toks.expect(|t| match *t {Token::Identifier(_) => true, _ => false}, "Identifier")) {
+        match fit.as_ref() {
+            "fit" => Ok(GateFit::Fit),
+            "fill" => Ok(GateFit::Fill),
+            "horizontal" => Ok(GateFit::Horizontal),
+            "vertical" => Ok(GateFit::Vertical),
+            _ => Err(SyntaxError { etype: SyntaxErrorType::NoClass(fit), location: toks.iter.location }),
+        }
+    } else {
+        panic!("at the disco");
+    }
+}
+
+fn parse_sampler_kind(toks: &mut Acceptor<Tokenizer>) -> Result<SamplerKind, SyntaxError> {
+    if let Token::Identifier(kind) = try!(toks.expect(|t| match *t {Token::Identifier(_) => true, _ => false}, "Identifier")) {
+        match kind.as_ref() {
+            "random" => Ok(SamplerKind::Random),
+            "stratified" => Ok(SamplerKind::Stratified),
+            "sobol" => Ok(SamplerKind::Sobol),
+            "blue_noise" => Ok(SamplerKind::BlueNoise),
+            _ => Err(SyntaxError { etype: SyntaxErrorType::NoClass(kind), location: toks.iter.location }),
+        }
+    } else {
+        panic!("at the disco");
+    }
+}
+
+fn parse_render_mode(toks: &mut Acceptor<Tokenizer>) -> Result<RenderMode, SyntaxError> {
+    if let Token::Identifier(mode) = try!(toks.expect(|t| match *t {Token::Identifier(_) => true, _ => false}, "Identifier")) {
+        match mode.as_ref() {
+            "shaded" => Ok(RenderMode::Shaded),
+            "ambient_occlusion" => Ok(RenderMode::AmbientOcclusion),
+            _ => Err(SyntaxError { etype: SyntaxErrorType::NoClass(mode), location: toks.iter.location }),
+        }
+    } else {
+        panic!("at the disco");
+    }
+}
+
+fn_parse_struct!(
+    parse_ambient_occlusion(toks) -> AmbientOcclusion {
+        samples: parse_u32(toks); default 16,
+        distance: parse_f64(toks); default std::f64::INFINITY,
     }
 );
 
@@ -807,8 +1915,106 @@ fn_parse_struct!(
     parse_scene(toks) -> Scene {
         objects: parse_vec(toks, parse_object),
         lights: parse_vec(toks, parse_light),
+        volumes: parse_vec(toks, parse_volume); default Vec::new(),
+        grid_volumes: parse_vec(toks, parse_grid_volume); default Vec::new(),
         camera: parse_box_camera(toks),
         background: parse_box_background(toks),
         options: parse_options(toks),
     }
 );
+
+#[cfg(test)]
+mod tests {
+    use super::{Acceptor, LL1, Token, Tokenizer, parse_f64, parse_options};
+
+    fn tokens(text: &str) -> Vec<Token> {
+        let mut tokenizer = Tokenizer::new(text);
+        let res = tokenizer.by_ref().collect();
+        assert!(tokenizer.error.is_none(), "{}", tokenizer.error.unwrap());
+        res
+    }
+
+    fn number(text: &str) -> f64 {
+        let mut toks = Acceptor { iter: LL1::new(Tokenizer::new(text)) };
+        let res = parse_f64(&mut toks).unwrap();
+        assert!(toks.take().is_none());
+        res
+    }
+
+    #[test]
+    fn arithmetic() {
+        assert_eq!(number("1 - 2"), -1.0);
+        assert_eq!(number("1-2"), -1.0);
+        assert_eq!(number("-(3) * 2"), -6.0);
+        assert_eq!(number("1e-3"), 0.001);
+        assert_eq!(number("2.5E+2 / 5"), 50.0);
+        assert_eq!(number("1 + 2 * 3 - 4 / 2"), 5.0);
+        assert_eq!(number("30 * 96 / 800"), 3.6);
+    }
+
+    #[test]
+    fn comments() {
+        assert_eq!(tokens("1 // comment"), vec![Token::Number(1.0)]);
+        assert_eq!(tokens("1 /* comment */ / 2"), vec![Token::Number(1.0), Token::Slash, Token::Number(2.0)]);
+        assert_eq!(tokens("1 /* comment ** / */"), vec![Token::Number(1.0)]);
+        assert_eq!(tokens("1 /* unterminated *"), vec![Token::Number(1.0)]);
+        assert_eq!(tokens("1 /* unterminated"), vec![Token::Number(1.0)]);
+    }
+
+    #[test]
+    fn bindings() {
+        // a field with the name of a binding is kept
+        assert_eq!(tokens("let radius = 2; { radius: radius }"), vec![
+            Token::LBrace, Token::Identifier("radius".to_string()), Token::Colon,
+            Token::LParen, Token::Number(2.0), Token::RParen, Token::RBrace,
+        ]);
+        // bindings in a value are replaced when it is defined
+        assert_eq!(number("let a = 1 + 2; let b = a * 3; b - a"), 6.0);
+        assert_eq!(tokens("let c = rgb(1, 0, 0); let m = { color: c }; m"), tokens("{ color: rgb(1, 0, 0) }"));
+    }
+
+    #[test]
+    fn binding_errors() {
+        for text in &["let rgb = 1; 1", "let Sphere = 1; 1", "let depth = 1; 1", "let x 1; 1", "let x = 1"] {
+            let mut tokenizer = Tokenizer::new(text);
+            assert!(tokenizer.by_ref().count() == 0 && tokenizer.error.is_some(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn binding_locations() {
+        // the tokens of a binding are where its name is, and the token
+        // after the name is where it is without the binding
+        let locations = |text| {
+            let mut toks = Acceptor { iter: LL1::new(Tokenizer::new(text)) };
+            let mut res = Vec::new();
+            while toks.take().is_some() {
+                res.push((toks.iter.location.row, toks.iter.location.col));
+            }
+            res
+        };
+        let plain = locations("\nx a\ny");
+        let bound = locations("let a = 1;\nx a\ny");
+        assert_eq!(bound, vec![plain[0], plain[1], plain[1], plain[1], plain[2]]);
+    }
+
+    #[test]
+    fn crop() {
+        let options = |crop: &str| {
+            let text = format!("{{ width: 100 height: 50 antialias: 1 crop: {} }}", crop);
+            parse_options(&mut Acceptor { iter: LL1::new(Tokenizer::new(&text)) })
+        };
+        let region = options("{ x: 10 y: 5 width: 90 height: 45 }").unwrap().region();
+        assert_eq!(region, (10, 0, 90, 45));
+        assert!(options("{ x: 0 y: 0 width: 100 height: 50 }").is_ok());
+        // empty
+        assert!(options("{ x: 0 y: 0 width: 0 height: 10 }").is_err());
+        assert!(options("{ x: 0 y: 0 width: 10 height: 0 }").is_err());
+        // outside of the image
+        assert!(options("{ x: 100 y: 0 width: 1 height: 1 }").is_err());
+        assert!(options("{ x: 0 y: 50 width: 1 height: 1 }").is_err());
+        assert!(options("{ x: 10 y: 0 width: 91 height: 1 }").is_err());
+        assert!(options("{ x: 0 y: 10 width: 1 height: 41 }").is_err());
+        assert!(options("{ x: 1 y: 0 width: 4294967295 height: 1 }").is_err());
+    }
+}
@@ -3,16 +3,39 @@
 //! This module contains various shapes that have a collision
 //! test with the `Ray` struct, which is also in here. These
 //! shapes are used to model how an object looks and reflects light.
+use std::f64;
+
 use types::*;
-use types::na::{Norm, Dot};
+use types::na::{Norm, Dot, cross};
+use animation::{Keyframe, Transform, sample_keyframes};
 
-/// A ray. A ray has an origin and a direction.
+/// A ray. A ray has an origin, a direction, and the time at which
+/// it was cast.
 pub struct Ray {
     /// The origin of the ray.
     pub origin: Pnt3,
     /// The direction of the ray. Should be normalized most of the
     /// time.
     pub direction: Vec3,
+    /// The time at which the ray was cast, somewhere within the
+    /// shutter interval. Used to position animated objects.
+    pub time: f64,
+    /// Rays offset by one pixel in x and y, used to estimate the
+    /// footprint of the ray for texture filtering.
+    pub differential: Option<RayDifferential>,
+}
+
+/// The rays through the neighboring pixels of a ray.
+#[derive(Clone)]
+pub struct RayDifferential {
+    /// The origin of the ray offset in x.
+    pub rx_origin: Pnt3,
+    /// The direction of the ray offset in x.
+    pub rx_direction: Vec3,
+    /// The origin of the ray offset in y.
+    pub ry_origin: Pnt3,
+    /// The direction of the ray offset in y.
+    pub ry_direction: Vec3,
 }
 
 impl Ray {
@@ -25,11 +48,168 @@ impl Ray {
 }
 
 /// The result of an intersection between a shape and a ray.
+#[derive(Clone)]
 pub struct IntersectionResult {
     /// The `t` value at which the ray hits the shape.
     pub t: f64,
     /// The normal of the shape at the point of intersection.
     pub normal: Vec3,
+    /// The texture coordinates of the point of intersection.
+    pub uv: Pnt2,
+    /// The derivative of the point of intersection with respect to
+    /// the u texture coordinate. Together with `dpdv`, this is the
+    /// tangent frame used for normal and bump mapping.
+    pub dpdu: Vec3,
+    /// The derivative of the point of intersection with respect to
+    /// the v texture coordinate.
+    pub dpdv: Vec3,
+    /// The change in texture coordinates to the neighboring pixel in
+    /// x. Zero unless computed by `compute_uv_differentials`.
+    pub duvdx: Vec2,
+    /// The change in texture coordinates to the neighboring pixel in
+    /// y. Zero unless computed by `compute_uv_differentials`.
+    pub duvdy: Vec2,
+}
+
+impl IntersectionResult {
+    /// Create an intersection result without texture coordinate
+    /// differentials.
+    pub fn new(t: f64, normal: Vec3, uv: Pnt2, dpdu: Vec3, dpdv: Vec3) -> IntersectionResult {
+        IntersectionResult {
+            t: t,
+            normal: normal,
+            uv: uv,
+            dpdu: dpdu,
+            dpdv: dpdv,
+            duvdx: Vec2::new(0.0, 0.0),
+            duvdy: Vec2::new(0.0, 0.0),
+        }
+    }
+
+    /// Estimate how much the texture coordinates change between
+    /// neighboring pixels, using the ray's differentials. The offset
+    /// rays are intersected with the tangent plane at the point of
+    /// intersection.
+    pub fn compute_uv_differentials(&mut self, ray: &Ray) {
+        let d = match ray.differential {
+            Some(ref d) => d,
+            None => return,
+        };
+        let p = ray.cast(self.t);
+        let n = self.normal;
+        let tx = n.dot(&(p - d.rx_origin)) / n.dot(&d.rx_direction);
+        let ty = n.dot(&(p - d.ry_origin)) / n.dot(&d.ry_direction);
+        if !tx.is_finite() || !ty.is_finite() { return }
+        let dpdx = (d.rx_origin + d.rx_direction * tx) - p;
+        let dpdy = (d.ry_origin + d.ry_direction * ty) - p;
+        // least squares solution of dpdx = dpdu * dudx + dpdv * dvdx
+        let a = self.dpdu.dot(&self.dpdu);
+        let b = self.dpdu.dot(&self.dpdv);
+        let c = self.dpdv.dot(&self.dpdv);
+        let det = a * c - b * b;
+        if det.abs() < 1e-12 { return }
+        let (dpdu, dpdv) = (self.dpdu, self.dpdv);
+        let solve = |v: &Vec3| {
+            let r1 = dpdu.dot(v);
+            let r2 = dpdv.dot(v);
+            Vec2::new((c * r1 - b * r2) / det, (a * r2 - b * r1) / det)
+        };
+        self.duvdx = solve(&dpdx);
+        self.duvdy = solve(&dpdy);
+    }
+}
+
+/// A span of a ray that is inside a shape, from where the ray enters
+/// the shape to where it exits. The `t` of either end can be
+/// infinite for unbounded shapes, in which case the rest of the
+/// result is meaningless.
+pub struct Interval {
+    /// Where the ray enters the shape.
+    pub enter: IntersectionResult,
+    /// Where the ray exits the shape.
+    pub exit: IntersectionResult,
+}
+
+/// An end of an unbounded interval.
+fn infinite(t: f64) -> IntersectionResult {
+    let zero = Vec3::new(0.0, 0.0, 0.0);
+    IntersectionResult::new(t, zero, Pnt2::new(0.0, 0.0), zero, zero)
+}
+
+/// Get the closest boundary of a list of intervals that is in the
+/// direction of the ray.
+fn first_hit(intervals: Vec<Interval>) -> Option<IntersectionResult> {
+    for i in intervals {
+        if i.enter.t > 0.0 && i.enter.t.is_finite() {
+            return Some(i.enter);
+        }
+        if i.exit.t > 0.0 && i.exit.t.is_finite() {
+            return Some(i.exit);
+        }
+    }
+    None
+}
+
+/// Intersect two intervals of the same ray.
+fn clip_interval(a: Interval, b: Interval) -> Option<Interval> {
+    let enter = if a.enter.t > b.enter.t { a.enter } else { b.enter };
+    let exit = if a.exit.t < b.exit.t { a.exit } else { b.exit };
+    if enter.t < exit.t {
+        Some(Interval { enter: enter, exit: exit })
+    } else {
+        None
+    }
+}
+
+/// An axis-aligned box that contains a shape.
+#[derive(Clone, Copy, Debug)]
+pub struct BoundingBox {
+    /// The corner with the lowest coordinates.
+    pub min: Pnt3,
+    /// The corner with the highest coordinates.
+    pub max: Pnt3,
+}
+
+impl BoundingBox {
+    /// The smallest box around a center point, extending by `extent`
+    /// on each side.
+    pub fn around(center: &Pnt3, extent: &Vec3) -> BoundingBox {
+        BoundingBox { min: *center - *extent, max: *center + *extent }
+    }
+
+    /// The smallest box that contains all the points. Panics if
+    /// there are no points.
+    pub fn from_points(points: &[Pnt3]) -> BoundingBox {
+        let mut b = BoundingBox { min: points[0], max: points[0] };
+        for p in &points[1..] {
+            b = b.union(&BoundingBox { min: *p, max: *p });
+        }
+        b
+    }
+
+    /// The smallest box that contains both boxes.
+    pub fn union(&self, other: &BoundingBox) -> BoundingBox {
+        BoundingBox {
+            min: Pnt3::new(self.min.x.min(other.min.x), self.min.y.min(other.min.y), self.min.z.min(other.min.z)),
+            max: Pnt3::new(self.max.x.max(other.max.x), self.max.y.max(other.max.y), self.max.z.max(other.max.z)),
+        }
+    }
+
+    /// The box that is inside both boxes. If they do not overlap, the
+    /// minimum will be larger than the maximum on some axis.
+    pub fn intersection(&self, other: &BoundingBox) -> BoundingBox {
+        BoundingBox {
+            min: Pnt3::new(self.min.x.max(other.min.x), self.min.y.max(other.min.y), self.min.z.max(other.min.z)),
+            max: Pnt3::new(self.max.x.min(other.max.x), self.max.y.min(other.max.y), self.max.z.min(other.max.z)),
+        }
+    }
+
+    /// The eight corners of the box.
+    pub fn corners(&self) -> [Pnt3; 8] {
+        let (a, b) = (self.min, self.max);
+        [Pnt3::new(a.x, a.y, a.z), Pnt3::new(b.x, a.y, a.z), Pnt3::new(a.x, b.y, a.z), Pnt3::new(b.x, b.y, a.z),
+         Pnt3::new(a.x, a.y, b.z), Pnt3::new(b.x, a.y, b.z), Pnt3::new(a.x, b.y, b.z), Pnt3::new(b.x, b.y, b.z)]
+    }
 }
 
 /// A shape that can be intersected with a ray.
@@ -37,6 +217,17 @@ pub trait Shape {
     /// Intersect the ray with a shape, and return the closest
     /// result that is in the direction of the ray.
     fn intersect(&self, ray: &Ray) -> Option<IntersectionResult>;
+
+    /// Find all the spans of the line through the ray that are inside
+    /// the shape, including those behind the origin of the ray. The
+    /// intervals are sorted and do not overlap. Normals face out of
+    /// the shape. Shapes that do not enclose a volume have no
+    /// intervals.
+    fn intervals(&self, ray: &Ray) -> Vec<Interval>;
+
+    /// Get a box that contains the shape, or `None` if the shape is
+    /// unbounded.
+    fn bounds(&self) -> Option<BoundingBox>;
 }
 
 /// A sphere. A sphere has a center and a radius.
@@ -67,17 +258,11 @@ impl Shape for Sphere {
             let dsqrt = discriminant.sqrt();
             let t = (-b - dsqrt) / (2.0 * a);
             if t > 0.0 {
-                Some(IntersectionResult {
-                    t: t,
-                    normal: (ray.cast(t) - self.center).normalize(),
-                })
+                Some(self.result_at(ray, t))
             } else {
                 let t2 = (-b + dsqrt) / (2.0 * a);
                 if t2 > 0.0 {
-                    Some(IntersectionResult {
-                        t: t2,
-                        normal: (ray.cast(t2) - self.center).normalize(),
-                    })
+                    Some(self.result_at(ray, t2))
                 } else {
                     None
                 }
@@ -86,6 +271,51 @@ impl Shape for Sphere {
             None
         }
     }
+
+    fn intervals(&self, ray: &Ray) -> Vec<Interval> {
+        let ominusc = ray.origin - self.center;
+        let a = ray.direction.sqnorm();
+        let b = 2.0 * ray.direction.dot(&ominusc);
+        let c = ominusc.sqnorm() - self.radius * self.radius;
+        let discriminant = b * b - 4.0 * a * c;
+        if discriminant > 0.0 {
+            let dsqrt = discriminant.sqrt();
+            vec![Interval {
+                enter: self.result_at(ray, (-b - dsqrt) / (2.0 * a)),
+                exit: self.result_at(ray, (-b + dsqrt) / (2.0 * a)),
+            }]
+        } else {
+            Vec::new()
+        }
+    }
+
+    fn bounds(&self) -> Option<BoundingBox> {
+        Some(BoundingBox::around(&self.center, &Vec3::new(self.radius, self.radius, self.radius)))
+    }
+}
+
+impl Sphere {
+    fn result_at(&self, ray: &Ray, t: f64) -> IntersectionResult {
+        let normal = (ray.cast(t) - self.center).normalize();
+        // longitude around the y axis, latitude from the equator
+        let u = 0.5 + normal.x.atan2(normal.z) / (2.0 * f64::consts::PI);
+        let v = 0.5 + normal.y.max(-1.0).min(1.0).asin() / f64::consts::PI;
+        // the point is center + radius * (sin phi cos theta, sin theta, cos phi cos theta)
+        let cos_theta = (normal.x * normal.x + normal.z * normal.z).sqrt();
+        let (sin_phi, cos_phi) = if cos_theta > 1e-9 { (normal.x / cos_theta, normal.z / cos_theta) } else { (0.0, 1.0) };
+        let dpdu = Vec3::new(normal.z, 0.0, -normal.x) * (2.0 * f64::consts::PI * self.radius);
+        let dpdv = Vec3::new(-sin_phi * normal.y, cos_theta, -cos_phi * normal.y) * (f64::consts::PI * self.radius);
+        IntersectionResult::new(t, normal, Pnt2::new(u, v), dpdu, dpdv)
+    }
+}
+
+/// Compute two unit vectors that span a plane with the given normal.
+/// For vertical planes, the second vector points up.
+fn plane_basis(normal: &Vec3) -> (Vec3, Vec3) {
+    let n = normal.normalize();
+    let up = if n.y.abs() > 0.999 { Vec3::new(0.0, 0.0, -1.0) } else { Vec3::new(0.0, 1.0, 0.0) };
+    let t = cross(&up, &n).normalize();
+    (t, cross(&n, &t))
 }
 
 /// A plane. Defined by a point on it and the normal vector.
@@ -103,10 +333,957 @@ impl Shape for Plane {
         if t <= 0.0 {
             None
         } else {
-            Some(IntersectionResult {
-                t: t,
-                normal: self.normal,
-            })
+            Some(self.result_at(ray, t))
+        }
+    }
+
+    /// The inside of a plane is the half-space behind its normal.
+    fn intervals(&self, ray: &Ray) -> Vec<Interval> {
+        let nd = self.normal.dot(&ray.direction);
+        let t = self.normal.dot(&(self.point - ray.origin)) / nd;
+        if nd > 0.0 {
+            vec![Interval { enter: infinite(-f64::INFINITY), exit: self.result_at(ray, t) }]
+        } else if nd < 0.0 {
+            vec![Interval { enter: self.result_at(ray, t), exit: infinite(f64::INFINITY) }]
+        } else if self.normal.dot(&(ray.origin - self.point)) <= 0.0 {
+            vec![Interval { enter: infinite(-f64::INFINITY), exit: infinite(f64::INFINITY) }]
+        } else {
+            Vec::new()
+        }
+    }
+
+    fn bounds(&self) -> Option<BoundingBox> {
+        None
+    }
+}
+
+impl Plane {
+    fn result_at(&self, ray: &Ray, t: f64) -> IntersectionResult {
+        // texture coordinates are in world units along the plane
+        let (tu, tv) = plane_basis(&self.normal);
+        let d = ray.cast(t) - self.point;
+        IntersectionResult::new(t, self.normal, Pnt2::new(d.dot(&tu), d.dot(&tv)), tu, tv)
+    }
+}
+
+/// A shape that moves over time. The shape is defined in object space
+/// and is transformed into world space by interpolating the keyframes
+/// at the time of each ray.
+pub struct AnimatedShape {
+    /// The shape in object space.
+    pub shape: Box<Shape>,
+    /// The transformations of the shape over time, sorted by time.
+    pub keyframes: Vec<Keyframe<Transform>>,
+}
+
+impl Shape for AnimatedShape {
+    fn intersect(&self, ray: &Ray) -> Option<IntersectionResult> {
+        let transform = sample_keyframes(&self.keyframes, ray.time);
+        self.shape.intersect(&local_ray(&transform, ray)).map(|r| world_result(&transform, r))
+    }
+
+    fn intervals(&self, ray: &Ray) -> Vec<Interval> {
+        let transform = sample_keyframes(&self.keyframes, ray.time);
+        self.shape.intervals(&local_ray(&transform, ray)).into_iter().map(|i| Interval {
+            enter: world_result(&transform, i.enter),
+            exit: world_result(&transform, i.exit),
+        }).collect()
+    }
+
+    /// The union of the bounds at each keyframe. The shape is assumed
+    /// not to leave them between keyframes.
+    fn bounds(&self) -> Option<BoundingBox> {
+        let local = match self.shape.bounds() {
+            Some(b) => b,
+            None => return None,
+        };
+        let mut points = Vec::new();
+        for k in &self.keyframes {
+            points.extend(local.corners().iter().map(|p| k.value.apply_pnt(p)));
+        }
+        Some(BoundingBox::from_points(&points))
+    }
+}
+
+/// Transform a ray into object space. The direction is not normalized
+/// so that t is the same in both spaces.
+fn local_ray(transform: &Transform, ray: &Ray) -> Ray {
+    Ray {
+        origin: transform.invert_pnt(&ray.origin),
+        direction: transform.invert_vec(&ray.direction),
+        time: ray.time,
+        differential: None,
+    }
+}
+
+/// Transform an intersection result from object space into world space.
+fn world_result(transform: &Transform, r: IntersectionResult) -> IntersectionResult {
+    IntersectionResult::new(
+        r.t,
+        transform.apply_normal(&r.normal),
+        r.uv,
+        transform.apply_vec(&r.dpdu),
+        transform.apply_vec(&r.dpdv),
+    )
+}
+
+/// How the shapes of a `Csg` node are combined.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CsgOperation {
+    /// Everything inside either shape.
+    Union,
+    /// Everything inside both shapes.
+    Intersection,
+    /// Everything inside the first shape but not the second.
+    Difference,
+}
+
+impl CsgOperation {
+    fn inside(&self, a: bool, b: bool) -> bool {
+        match *self {
+            CsgOperation::Union => a || b,
+            CsgOperation::Intersection => a && b,
+            CsgOperation::Difference => a && !b,
+        }
+    }
+}
+
+/// Constructive solid geometry: two closed shapes combined into one.
+/// The surface of the result has the texture coordinates of the
+/// shape it came from.
+pub struct Csg {
+    /// The operation used to combine the shapes.
+    pub operation: CsgOperation,
+    /// The first shape.
+    pub left: Box<Shape>,
+    /// The second shape.
+    pub right: Box<Shape>,
+}
+
+impl Shape for Csg {
+    fn intersect(&self, ray: &Ray) -> Option<IntersectionResult> {
+        first_hit(self.intervals(ray))
+    }
+
+    fn bounds(&self) -> Option<BoundingBox> {
+        match (self.operation, self.left.bounds(), self.right.bounds()) {
+            (CsgOperation::Union, Some(a), Some(b)) => Some(a.union(&b)),
+            (CsgOperation::Union, _, _) => None,
+            (CsgOperation::Intersection, Some(a), Some(b)) => Some(a.intersection(&b)),
+            (CsgOperation::Intersection, a, b) => a.or(b),
+            (CsgOperation::Difference, a, _) => a,
+        }
+    }
+
+    fn intervals(&self, ray: &Ray) -> Vec<Interval> {
+        // the boundaries of both shapes: (is it the left shape,
+        // is it entering, the result)
+        let mut events = Vec::new();
+        for i in self.left.intervals(ray) {
+            events.push((true, true, i.enter));
+            events.push((true, false, i.exit));
+        }
+        for i in self.right.intervals(ray) {
+            events.push((false, true, i.enter));
+            events.push((false, false, i.exit));
         }
+        events.sort_by(|a, b| a.2.t.partial_cmp(&b.2.t).unwrap_or(::std::cmp::Ordering::Equal));
+        // sweep along the ray, keeping the boundaries where the
+        // combined inside changes. Boundaries at the same t are taken
+        // together, so that shapes that touch or share a surface do not
+        // leave empty intervals or surfaces inside of the result.
+        let (mut in_left, mut in_right) = (false, false);
+        let mut was_inside = false;
+        let mut boundary = None;
+        let mut enter = None;
+        let mut intervals = Vec::new();
+        let mut events = events.into_iter().peekable();
+        while let Some((left, entering, result)) = events.next() {
+            let t = result.t;
+            let before = self.operation.inside(in_left, in_right);
+            if left { in_left = entering } else { in_right = entering }
+            if self.operation.inside(in_left, in_right) != before {
+                boundary = Some((left, result));
+            }
+            if events.peek().map_or(false, |e| e.2.t == t) {
+                continue;
+            }
+            let inside = self.operation.inside(in_left, in_right);
+            let changed = inside != was_inside;
+            was_inside = inside;
+            let (left, mut result) = match boundary.take() {
+                Some(b) if changed => b,
+                _ => continue,
+            };
+            // the second shape is turned inside out when subtracted
+            if !left && self.operation == CsgOperation::Difference {
+                result.normal = -result.normal;
+            }
+            if inside {
+                enter = Some(result);
+            } else if let Some(e) = enter.take() {
+                intervals.push(Interval { enter: e, exit: result });
+            }
+        }
+        intervals
+    }
+}
+
+/// Get a component of a vector by index.
+#[inline]
+fn component(v: &Vec3, i: usize) -> f64 {
+    match i { 0 => v.x, 1 => v.y, _ => v.z }
+}
+
+/// A vector along an axis, given by index.
+#[inline]
+fn axis_vec(i: usize, length: f64) -> Vec3 {
+    match i {
+        0 => Vec3::new(length, 0.0, 0.0),
+        1 => Vec3::new(0.0, length, 0.0),
+        _ => Vec3::new(0.0, 0.0, length),
+    }
+}
+
+/// The extent of a disk with the given normal and radius along each
+/// axis.
+fn disk_extent(normal: &Vec3, radius: f64) -> Vec3 {
+    let n = normal.normalize();
+    let e = |c: f64| radius * (1.0 - c * c).max(0.0).sqrt();
+    Vec3::new(e(n.x), e(n.y), e(n.z))
+}
+
+/// Intersect the line through a ray with an axis-aligned box. Each
+/// face is textured from 0 to 1 along the next two axes.
+fn box_intervals(min: &Pnt3, max: &Pnt3, ray: &Ray) -> Vec<Interval> {
+    let (o, d) = (ray.origin.to_vec(), ray.direction);
+    let (lo, hi) = (min.to_vec(), max.to_vec());
+    // the t of each end, and the axis and direction of its face
+    let (mut enter, mut exit) = ((-f64::INFINITY, None), (f64::INFINITY, None));
+    for i in 0..3 {
+        let (oi, di, loi, hii) = (component(&o, i), component(&d, i), component(&lo, i), component(&hi, i));
+        if di == 0.0 {
+            if oi < loi || oi > hii {
+                return Vec::new();
+            }
+            continue;
+        }
+        let (ta, tb) = ((loi - oi) / di, (hii - oi) / di);
+        let (near, far, sign) = if ta < tb { (ta, tb, -1.0) } else { (tb, ta, 1.0) };
+        if near > enter.0 { enter = (near, Some((i, sign))); }
+        if far < exit.0 { exit = (far, Some((i, -sign))); }
+    }
+    if enter.0 > exit.0 {
+        return Vec::new();
+    }
+    let size = *max - *min;
+    let result = |(t, face): (f64, Option<(usize, f64)>)| match face {
+        Some((i, sign)) => {
+            let (j, k) = ((i + 1) % 3, (i + 2) % 3);
+            let p = ray.cast(t) - *min;
+            let uv = Pnt2::new(component(&p, j) / component(&size, j), component(&p, k) / component(&size, k));
+            IntersectionResult::new(t, axis_vec(i, sign), uv, axis_vec(j, component(&size, j)), axis_vec(k, component(&size, k)))
+        },
+        None => infinite(t),
+    };
+    vec![Interval { enter: result(enter), exit: result(exit) }]
+}
+
+/// A box whose sides are aligned with the axes.
+pub struct AxisAlignedBox {
+    /// The corner with the lowest coordinates.
+    pub min: Pnt3,
+    /// The corner with the highest coordinates.
+    pub max: Pnt3,
+}
+
+impl Shape for AxisAlignedBox {
+    fn intersect(&self, ray: &Ray) -> Option<IntersectionResult> {
+        first_hit(self.intervals(ray))
+    }
+
+    fn intervals(&self, ray: &Ray) -> Vec<Interval> {
+        box_intervals(&self.min, &self.max, ray)
+    }
+
+    fn bounds(&self) -> Option<BoundingBox> {
+        Some(BoundingBox { min: self.min, max: self.max })
+    }
+}
+
+/// A box that is rotated around its center.
+pub struct OrientedBox {
+    /// The center of the box.
+    pub center: Pnt3,
+    /// The size of the box along each of its axes.
+    pub size: Vec3,
+    /// The rotation of the box as an axis multiplied by an angle in
+    /// radians.
+    pub rotation: Vec3,
+}
+
+impl OrientedBox {
+    fn transform(&self) -> Transform {
+        Transform { translation: self.center.to_vec(), rotation: self.rotation, scale: 1.0 }
+    }
+}
+
+impl Shape for OrientedBox {
+    fn intersect(&self, ray: &Ray) -> Option<IntersectionResult> {
+        first_hit(self.intervals(ray))
+    }
+
+    fn intervals(&self, ray: &Ray) -> Vec<Interval> {
+        let transform = self.transform();
+        let half = self.size * 0.5;
+        let origin = Pnt3::new(0.0, 0.0, 0.0);
+        box_intervals(&(origin - half), &(origin + half), &local_ray(&transform, ray)).into_iter().map(|i| Interval {
+            enter: world_result(&transform, i.enter),
+            exit: world_result(&transform, i.exit),
+        }).collect()
+    }
+
+    fn bounds(&self) -> Option<BoundingBox> {
+        let transform = self.transform();
+        let half = self.size * 0.5;
+        let origin = Pnt3::new(0.0, 0.0, 0.0);
+        let local = BoundingBox { min: origin - half, max: origin + half };
+        let points: Vec<Pnt3> = local.corners().iter().map(|p| transform.apply_pnt(p)).collect();
+        Some(BoundingBox::from_points(&points))
+    }
+}
+
+/// An orthonormal frame in which shapes around an axis are
+/// intersected. The axis of the shape is the local z axis.
+struct Frame {
+    origin: Pnt3,
+    u: Vec3,
+    v: Vec3,
+    w: Vec3,
+}
+
+impl Frame {
+    fn new(origin: &Pnt3, axis: &Vec3) -> Frame {
+        let w = axis.normalize();
+        let (u, v) = plane_basis(&w);
+        Frame { origin: *origin, u: u, v: v, w: w }
+    }
+
+    fn local_ray(&self, ray: &Ray) -> Ray {
+        let o = ray.origin - self.origin;
+        let d = ray.direction;
+        Ray {
+            origin: Pnt3::new(o.dot(&self.u), o.dot(&self.v), o.dot(&self.w)),
+            direction: Vec3::new(d.dot(&self.u), d.dot(&self.v), d.dot(&self.w)),
+            time: ray.time,
+            differential: None,
+        }
+    }
+
+    fn world_vec(&self, v: &Vec3) -> Vec3 {
+        self.u * v.x + self.v * v.y + self.w * v.z
+    }
+
+    fn world_result(&self, r: IntersectionResult) -> IntersectionResult {
+        IntersectionResult::new(r.t, self.world_vec(&r.normal), r.uv, self.world_vec(&r.dpdu), self.world_vec(&r.dpdv))
+    }
+
+    fn world_interval(&self, i: Interval) -> Interval {
+        Interval { enter: self.world_result(i.enter), exit: self.world_result(i.exit) }
+    }
+}
+
+/// The result at `t` on a disk of the given radius in the local xy
+/// plane (or parallel to it), facing up or down. The texture
+/// coordinates span the square around the disk.
+fn local_disk_result(ray: &Ray, t: f64, radius: f64, up: bool) -> IntersectionResult {
+    let p = ray.cast(t);
+    IntersectionResult::new(
+        t,
+        Vec3::new(0.0, 0.0, if up { 1.0 } else { -1.0 }),
+        Pnt2::new(p.x / radius * 0.5 + 0.5, p.y / radius * 0.5 + 0.5),
+        Vec3::new(2.0 * radius, 0.0, 0.0),
+        Vec3::new(0.0, 2.0 * radius, 0.0),
+    )
+}
+
+/// The interval where the line through a local ray is between the
+/// planes z = 0 and z = `height`, which are capped with disks.
+fn local_slab(ray: &Ray, height: f64, radius: f64) -> Option<Interval> {
+    let (oz, dz) = (ray.origin.z, ray.direction.z);
+    if dz == 0.0 {
+        if oz < 0.0 || oz > height {
+            None
+        } else {
+            Some(Interval { enter: infinite(-f64::INFINITY), exit: infinite(f64::INFINITY) })
+        }
+    } else {
+        let bottom = local_disk_result(ray, -oz / dz, radius, false);
+        let top = local_disk_result(ray, (height - oz) / dz, radius, true);
+        Some(if dz > 0.0 { Interval { enter: bottom, exit: top } } else { Interval { enter: top, exit: bottom } })
+    }
+}
+
+/// Find the real roots of a x^2 + b x + c, sorted.
+fn solve_quadratic(a: f64, b: f64, c: f64) -> Vec<f64> {
+    if a == 0.0 {
+        return if b == 0.0 { Vec::new() } else { vec![-c / b] };
+    }
+    let discriminant = b * b - 4.0 * a * c;
+    if discriminant < 0.0 {
+        return Vec::new();
+    }
+    // avoid cancellation by computing the larger root first
+    let q = -0.5 * (b + if b < 0.0 { -discriminant.sqrt() } else { discriminant.sqrt() });
+    if q == 0.0 {
+        return vec![0.0, 0.0];
+    }
+    let (r1, r2) = (q / a, c / q);
+    if r1 < r2 { vec![r1, r2] } else { vec![r2, r1] }
+}
+
+/// Find the largest real root of x^3 + a x^2 + b x + c.
+fn largest_cubic_root(a: f64, b: f64, c: f64) -> f64 {
+    // substitute x = y - a/3 to get y^3 + p y + q
+    let p = b - a * a / 3.0;
+    let q = 2.0 * a * a * a / 27.0 - a * b / 3.0 + c;
+    let discriminant = q * q / 4.0 + p * p * p / 27.0;
+    let y = if discriminant > 0.0 || p == 0.0 {
+        let s = discriminant.max(0.0).sqrt();
+        (-q / 2.0 + s).cbrt() + (-q / 2.0 - s).cbrt()
+    } else {
+        // three real roots; the first trigonometric one is the largest
+        let m = 2.0 * (-p / 3.0).sqrt();
+        let angle = (3.0 * q / (p * m)).max(-1.0).min(1.0).acos() / 3.0;
+        m * angle.cos()
+    };
+    y - a / 3.0
+}
+
+/// Find the real roots of a x^4 + b x^3 + c x^2 + d x + e, sorted,
+/// using Ferrari's method.
+fn solve_quartic(a: f64, b: f64, c: f64, d: f64, e: f64) -> Vec<f64> {
+    let (b, c, d, e) = (b / a, c / a, d / a, e / a);
+    // substitute x = y - b/4 to get y^4 + p y^2 + q y + r
+    let b2 = b * b;
+    let p = c - 3.0 * b2 / 8.0;
+    let q = d - b * c / 2.0 + b2 * b / 8.0;
+    let r = e - b * d / 4.0 + b2 * c / 16.0 - 3.0 * b2 * b2 / 256.0;
+    let mut ys = Vec::new();
+    if q.abs() < 1e-12 {
+        // biquadratic
+        for z in solve_quadratic(1.0, p, r) {
+            if z >= 0.0 {
+                ys.push(z.sqrt());
+                ys.push(-z.sqrt());
+            }
+        }
+    } else {
+        // factor into two quadratics using a root of the resolvent cubic
+        let m = largest_cubic_root(2.0 * p, p * p - 4.0 * r, -q * q);
+        if m > 0.0 {
+            let s = m.sqrt();
+            ys.extend(solve_quadratic(1.0, s, (p + m) / 2.0 - q / (2.0 * s)));
+            ys.extend(solve_quadratic(1.0, -s, (p + m) / 2.0 + q / (2.0 * s)));
+        }
+    }
+    // refine the roots with Newton's method
+    let mut xs: Vec<f64> = ys.into_iter().map(|y| {
+        let mut x = y - b / 4.0;
+        for _ in 0..2 {
+            let f = (((x + b) * x + c) * x + d) * x + e;
+            let df = ((4.0 * x + 3.0 * b) * x + 2.0 * c) * x + d;
+            if df != 0.0 {
+                x = x - f / df;
+            }
+        }
+        x
+    }).collect();
+    xs.sort_by(|a, b| a.partial_cmp(b).unwrap_or(::std::cmp::Ordering::Equal));
+    xs
+}
+
+/// A cylinder with flat caps.
+pub struct Cylinder {
+    /// The center of the bottom cap.
+    pub base: Pnt3,
+    /// The vector from the center of the bottom cap to the center of
+    /// the top cap.
+    pub axis: Vec3,
+    /// The radius of the cylinder.
+    pub radius: f64,
+}
+
+impl Shape for Cylinder {
+    fn intersect(&self, ray: &Ray) -> Option<IntersectionResult> {
+        first_hit(self.intervals(ray))
+    }
+
+    fn intervals(&self, ray: &Ray) -> Vec<Interval> {
+        let frame = Frame::new(&self.base, &self.axis);
+        let height = self.axis.norm();
+        let l = frame.local_ray(ray);
+        let (o, d, r) = (l.origin, l.direction, self.radius);
+        // the side, where x^2 + y^2 = r^2
+        let side_result = |t: f64| {
+            let p = l.cast(t);
+            IntersectionResult::new(
+                t,
+                Vec3::new(p.x / r, p.y / r, 0.0),
+                Pnt2::new(0.5 + p.y.atan2(p.x) / (2.0 * f64::consts::PI), p.z / height),
+                Vec3::new(-p.y, p.x, 0.0) * (2.0 * f64::consts::PI),
+                Vec3::new(0.0, 0.0, height),
+            )
+        };
+        let a = d.x * d.x + d.y * d.y;
+        let c = o.x * o.x + o.y * o.y - r * r;
+        let side = if a == 0.0 {
+            if c > 0.0 {
+                return Vec::new();
+            }
+            Interval { enter: infinite(-f64::INFINITY), exit: infinite(f64::INFINITY) }
+        } else {
+            let roots = solve_quadratic(a, 2.0 * (o.x * d.x + o.y * d.y), c);
+            if roots.len() < 2 || roots[0] == roots[1] {
+                return Vec::new();
+            }
+            Interval { enter: side_result(roots[0]), exit: side_result(roots[1]) }
+        };
+        local_slab(&l, height, r).and_then(|slab| clip_interval(side, slab))
+            .map(|i| vec![frame.world_interval(i)]).unwrap_or(Vec::new())
+    }
+
+    fn bounds(&self) -> Option<BoundingBox> {
+        let extent = disk_extent(&self.axis, self.radius);
+        Some(BoundingBox::around(&self.base, &extent).union(&BoundingBox::around(&(self.base + self.axis), &extent)))
+    }
+}
+
+/// A cone with a flat base.
+pub struct Cone {
+    /// The center of the base.
+    pub base: Pnt3,
+    /// The vector from the center of the base to the apex.
+    pub axis: Vec3,
+    /// The radius of the base.
+    pub radius: f64,
+}
+
+impl Shape for Cone {
+    fn intersect(&self, ray: &Ray) -> Option<IntersectionResult> {
+        first_hit(self.intervals(ray))
+    }
+
+    fn intervals(&self, ray: &Ray) -> Vec<Interval> {
+        let frame = Frame::new(&self.base, &self.axis);
+        let height = self.axis.norm();
+        let l = frame.local_ray(ray);
+        let (o, d, r) = (l.origin, l.direction, self.radius);
+        let k2 = (r / height) * (r / height);
+        // the side, where x^2 + y^2 = k^2 (h - z)^2
+        let side_result = |t: f64| {
+            let p = l.cast(t);
+            let phi = p.y.atan2(p.x);
+            IntersectionResult::new(
+                t,
+                Vec3::new(p.x, p.y, k2 * (height - p.z)).normalize(),
+                Pnt2::new(0.5 + phi / (2.0 * f64::consts::PI), p.z / height),
+                Vec3::new(-p.y, p.x, 0.0) * (2.0 * f64::consts::PI),
+                Vec3::new(-r * phi.cos(), -r * phi.sin(), height),
+            )
+        };
+        let a = d.x * d.x + d.y * d.y - k2 * d.z * d.z;
+        let b = 2.0 * (o.x * d.x + o.y * d.y + k2 * (height - o.z) * d.z);
+        let c = o.x * o.x + o.y * o.y - k2 * (height - o.z) * (height - o.z);
+        // the parts of the line inside the double cone; the slab cuts
+        // off the second cone above the apex
+        let whole = || Interval { enter: infinite(-f64::INFINITY), exit: infinite(f64::INFINITY) };
+        let roots = solve_quadratic(a, b, c);
+        let inside = if a > 0.0 {
+            if roots.len() < 2 {
+                return Vec::new();
+            }
+            vec![Interval { enter: side_result(roots[0]), exit: side_result(roots[1]) }]
+        } else if a < 0.0 {
+            if roots.len() < 2 {
+                vec![whole()]
+            } else {
+                vec![Interval { enter: infinite(-f64::INFINITY), exit: side_result(roots[0]) },
+                     Interval { enter: side_result(roots[1]), exit: infinite(f64::INFINITY) }]
+            }
+        } else if b == 0.0 {
+            if c > 0.0 { return Vec::new(); }
+            vec![whole()]
+        } else if b > 0.0 {
+            vec![Interval { enter: infinite(-f64::INFINITY), exit: side_result(-c / b) }]
+        } else {
+            vec![Interval { enter: side_result(-c / b), exit: infinite(f64::INFINITY) }]
+        };
+        match local_slab(&l, height, r) {
+            Some(slab) => inside.into_iter()
+                .filter_map(|i| clip_interval(i, Interval { enter: slab.enter.clone(), exit: slab.exit.clone() }))
+                .map(|i| frame.world_interval(i))
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    fn bounds(&self) -> Option<BoundingBox> {
+        let apex = self.base + self.axis;
+        Some(BoundingBox::around(&self.base, &disk_extent(&self.axis, self.radius)).union(&BoundingBox { min: apex, max: apex }))
+    }
+}
+
+/// A flat disk. It does not enclose a volume.
+pub struct Disk {
+    /// The center of the disk.
+    pub center: Pnt3,
+    /// The normal of the disk.
+    pub normal: Vec3,
+    /// The radius of the disk.
+    pub radius: f64,
+}
+
+impl Shape for Disk {
+    fn intersect(&self, ray: &Ray) -> Option<IntersectionResult> {
+        let frame = Frame::new(&self.center, &self.normal);
+        let l = frame.local_ray(ray);
+        let t = -l.origin.z / l.direction.z;
+        let p = l.cast(t);
+        if t > 0.0 && p.x * p.x + p.y * p.y <= self.radius * self.radius {
+            Some(frame.world_result(local_disk_result(&l, t, self.radius, true)))
+        } else {
+            None
+        }
+    }
+
+    fn intervals(&self, _: &Ray) -> Vec<Interval> {
+        Vec::new()
+    }
+
+    fn bounds(&self) -> Option<BoundingBox> {
+        Some(BoundingBox::around(&self.center, &disk_extent(&self.normal, self.radius)))
+    }
+}
+
+/// A flat rectangle, or any parallelogram, spanned by two sides from
+/// a corner. It does not enclose a volume. The texture coordinates go
+/// from 0 to 1 along each side.
+pub struct Rectangle {
+    /// A corner of the rectangle.
+    pub corner: Pnt3,
+    /// One side of the rectangle. The normal is `side1 x side2`.
+    pub side1: Vec3,
+    /// The other side of the rectangle.
+    pub side2: Vec3,
+}
+
+impl Shape for Rectangle {
+    fn intersect(&self, ray: &Ray) -> Option<IntersectionResult> {
+        let normal = cross(&self.side1, &self.side2).normalize();
+        let t = normal.dot(&(self.corner - ray.origin)) / normal.dot(&ray.direction);
+        if !(t > 0.0) {
+            return None;
+        }
+        // solve p = side1 * u + side2 * v in the plane
+        let p = ray.cast(t) - self.corner;
+        let (a, b, c) = (self.side1.dot(&self.side1), self.side1.dot(&self.side2), self.side2.dot(&self.side2));
+        let (r1, r2) = (self.side1.dot(&p), self.side2.dot(&p));
+        let det = a * c - b * b;
+        let u = (c * r1 - b * r2) / det;
+        let v = (a * r2 - b * r1) / det;
+        if u >= 0.0 && u <= 1.0 && v >= 0.0 && v <= 1.0 {
+            Some(IntersectionResult::new(t, normal, Pnt2::new(u, v), self.side1, self.side2))
+        } else {
+            None
+        }
+    }
+
+    fn intervals(&self, _: &Ray) -> Vec<Interval> {
+        Vec::new()
+    }
+
+    fn bounds(&self) -> Option<BoundingBox> {
+        let c = self.corner;
+        Some(BoundingBox::from_points(&[c, c + self.side1, c + self.side2, c + self.side1 + self.side2]))
+    }
+}
+
+/// A torus (a donut shape), which is a circle of radius
+/// `minor_radius` swept around a circle of radius `major_radius`.
+pub struct Torus {
+    /// The center of the torus.
+    pub center: Pnt3,
+    /// The axis that the torus goes around.
+    pub axis: Vec3,
+    /// The distance from the center to the middle of the tube.
+    pub major_radius: f64,
+    /// The radius of the tube.
+    pub minor_radius: f64,
+}
+
+impl Shape for Torus {
+    fn intersect(&self, ray: &Ray) -> Option<IntersectionResult> {
+        first_hit(self.intervals(ray))
+    }
+
+    fn intervals(&self, ray: &Ray) -> Vec<Interval> {
+        let frame = Frame::new(&self.center, &self.axis);
+        let l = frame.local_ray(ray);
+        let (big, small) = (self.major_radius, self.minor_radius);
+        // start the line near the torus, so that the quartic is well
+        // conditioned for distant rays
+        let shift = -l.origin.to_vec().dot(&l.direction) / l.direction.sqnorm();
+        let (o, d) = (l.cast(shift).to_vec(), l.direction);
+        // (|p|^2 + R^2 - r^2)^2 = 4 R^2 (x^2 + y^2)
+        let a = d.sqnorm();
+        let b = 2.0 * o.dot(&d);
+        let c = o.sqnorm() + big * big - small * small;
+        let r2 = 4.0 * big * big;
+        let roots = solve_quartic(
+            a * a,
+            2.0 * a * b,
+            b * b + 2.0 * a * c - r2 * (d.x * d.x + d.y * d.y),
+            2.0 * b * c - 2.0 * r2 * (o.x * d.x + o.y * d.y),
+            c * c - r2 * (o.x * o.x + o.y * o.y),
+        );
+        let result = |t: f64| {
+            let p = l.cast(t);
+            let rho = (p.x * p.x + p.y * p.y).sqrt();
+            let phi = p.y.atan2(p.x);
+            let theta = p.z.atan2(rho - big);
+            let normal = Vec3::new(phi.cos() * theta.cos(), phi.sin() * theta.cos(), theta.sin());
+            IntersectionResult::new(
+                t,
+                normal,
+                Pnt2::new(0.5 + phi / (2.0 * f64::consts::PI), 0.5 + theta / (2.0 * f64::consts::PI)),
+                Vec3::new(-p.y, p.x, 0.0) * (2.0 * f64::consts::PI),
+                Vec3::new(-phi.cos() * theta.sin(), -phi.sin() * theta.sin(), theta.cos()) * (2.0 * f64::consts::PI * small),
+            )
+        };
+        // the roots alternate between entering and exiting
+        roots.chunks(2).filter(|pair| pair.len() == 2)
+            .map(|pair| frame.world_interval(Interval { enter: result(pair[0] + shift), exit: result(pair[1] + shift) }))
+            .collect()
+    }
+
+    fn bounds(&self) -> Option<BoundingBox> {
+        let ring = disk_extent(&self.axis, self.major_radius);
+        let r = self.minor_radius;
+        Some(BoundingBox::around(&self.center, &(ring + Vec3::new(r, r, r))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Ray, Shape, Torus, Sphere, AxisAlignedBox, Csg, CsgOperation, solve_quartic};
+    use types::*;
+
+    fn assert_roots(roots: Vec<f64>, expected: &[f64], tolerance: f64) {
+        assert_eq!(roots.len(), expected.len(), "{:?}", roots);
+        for (r, e) in roots.iter().zip(expected) {
+            assert!((r - e).abs() < tolerance, "{:?} != {:?}", roots, expected);
+        }
+    }
+
+    fn ray(origin: Pnt3, direction: Vec3) -> Ray {
+        Ray { origin: origin, direction: direction, time: 0.0, differential: None }
+    }
+
+    /// The `t` of the ends of each interval.
+    fn spans(shape: &Shape, ray: &Ray) -> Vec<(f64, f64)> {
+        shape.intervals(ray).iter().map(|i| (i.enter.t, i.exit.t)).collect()
+    }
+
+    fn assert_spans(spans: Vec<(f64, f64)>, expected: &[(f64, f64)]) {
+        assert_eq!(spans.len(), expected.len(), "{:?}", spans);
+        for (&(enter, exit), &(e1, e2)) in spans.iter().zip(expected) {
+            assert!((enter - e1).abs() < 1e-9 && (exit - e2).abs() < 1e-9, "{:?} != {:?}", spans, expected);
+        }
+    }
+
+    /// A ray along the x axis from x = -5, so that `t` is x + 5.
+    fn x_ray() -> Ray {
+        ray(Pnt3::new(-5.0, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.0))
+    }
+
+    fn sphere(x: f64) -> Box<Shape> {
+        Box::new(Sphere { center: Pnt3::new(x, 0.0, 0.0), radius: 1.0 })
+    }
+
+    /// A slab of the given width along the x axis.
+    fn slab(x0: f64, x1: f64) -> Box<Shape> {
+        Box::new(AxisAlignedBox { min: Pnt3::new(x0, -2.0, -2.0), max: Pnt3::new(x1, 2.0, 2.0) })
+    }
+
+    fn csg(operation: CsgOperation, left: Box<Shape>, right: Box<Shape>) -> Csg {
+        Csg { operation: operation, left: left, right: right }
+    }
+
+    /// A torus around the z axis with radii 2 and 0.5.
+    fn torus() -> Torus {
+        Torus {
+            center: Pnt3::new(0.0, 0.0, 0.0),
+            axis: Vec3::new(0.0, 0.0, 1.0),
+            major_radius: 2.0,
+            minor_radius: 0.5,
+        }
+    }
+
+    #[test]
+    fn quartic_four_roots() {
+        // (x - 1)(x - 2)(x - 3)(x - 4)
+        assert_roots(solve_quartic(1.0, -10.0, 35.0, -50.0, 24.0), &[1.0, 2.0, 3.0, 4.0], 1e-9);
+        // the same roots with a leading coefficient
+        assert_roots(solve_quartic(-2.0, 20.0, -70.0, 100.0, -48.0), &[1.0, 2.0, 3.0, 4.0], 1e-9);
+        // biquadratic: (x^2 - 1)(x^2 - 4)
+        assert_roots(solve_quartic(1.0, 0.0, -5.0, 0.0, 4.0), &[-2.0, -1.0, 1.0, 2.0], 1e-9);
+    }
+
+    #[test]
+    fn quartic_fewer_roots() {
+        // (x^2 + 1)(x - 1)(x - 2)
+        assert_roots(solve_quartic(1.0, -3.0, 3.0, -3.0, 2.0), &[1.0, 2.0], 1e-9);
+        // x^4 + 1
+        assert_roots(solve_quartic(1.0, 0.0, 0.0, 0.0, 1.0), &[], 0.0);
+    }
+
+    #[test]
+    fn quartic_repeated_root() {
+        // (x - 1)^2 (x + 2)(x - 3)
+        assert_roots(solve_quartic(1.0, -3.0, -3.0, 11.0, -6.0), &[-2.0, 1.0, 1.0, 3.0], 1e-6);
+        // (x - 1)^2 (x - 2)^2
+        assert_roots(solve_quartic(1.0, -6.0, 13.0, -12.0, 4.0), &[1.0, 1.0, 2.0, 2.0], 1e-6);
+    }
+
+    #[test]
+    fn torus_through_tube() {
+        let torus = torus();
+        let r = ray(Pnt3::new(-5.0, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.0));
+        let intervals = torus.intervals(&r);
+        let ts: Vec<_> = intervals.iter().map(|i| (i.enter.t, i.exit.t)).collect();
+        assert_eq!(ts.len(), 2, "{:?}", ts);
+        let expected = [(2.5, 3.5), (6.5, 7.5)];
+        for (&(enter, exit), &(e1, e2)) in ts.iter().zip(expected.iter()) {
+            assert!((enter - e1).abs() < 1e-9 && (exit - e2).abs() < 1e-9, "{:?}", ts);
+        }
+        // the normals point out of the tube
+        assert!((intervals[0].enter.normal.x + 1.0).abs() < 1e-9);
+        assert!((intervals[0].exit.normal.x - 1.0).abs() < 1e-9);
+        let hit = torus.intersect(&r).unwrap();
+        assert!((hit.t - 2.5).abs() < 1e-9);
+        // a ray that starts inside the tube hits its far side
+        let inside = ray(Pnt3::new(-2.0, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.0));
+        assert!((torus.intersect(&inside).unwrap().t - 0.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn torus_along_axis() {
+        let torus = torus();
+        // straight through the hole
+        let r = ray(Pnt3::new(0.0, 0.0, -5.0), Vec3::new(0.0, 0.0, 1.0));
+        assert!(torus.intervals(&r).is_empty());
+        assert!(torus.intersect(&r).is_none());
+        // parallel to the axis through the tube
+        let r = ray(Pnt3::new(2.0, 0.0, -5.0), Vec3::new(0.0, 0.0, 1.0));
+        let intervals = torus.intervals(&r);
+        assert_eq!(intervals.len(), 1);
+        assert!((intervals[0].enter.t - 4.5).abs() < 1e-9);
+        assert!((intervals[0].exit.t - 5.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn torus_grazing() {
+        let torus = torus();
+        // just below the top of the tube, which passes through both
+        // sides of the ring in short intervals
+        let r = ray(Pnt3::new(-5.0, 0.0, 0.49), Vec3::new(1.0, 0.0, 0.0));
+        let intervals = torus.intervals(&r);
+        assert_eq!(intervals.len(), 2);
+        for (i, center) in intervals.iter().zip(&[3.0, 7.0]) {
+            assert!(i.enter.t < *center && i.exit.t > *center);
+            assert!(i.exit.t - i.enter.t < 0.3);
+        }
+        // just above it
+        let r = ray(Pnt3::new(-5.0, 0.0, 0.51), Vec3::new(1.0, 0.0, 0.0));
+        assert!(torus.intervals(&r).is_empty());
+        // tangent to the top, where any hit has to be at the tangent points
+        let r = ray(Pnt3::new(-5.0, 0.0, 0.5), Vec3::new(1.0, 0.0, 0.0));
+        for i in torus.intervals(&r) {
+            assert!(i.enter.t <= i.exit.t);
+            assert!((i.enter.t - 3.0).abs() < 1e-3 || (i.enter.t - 7.0).abs() < 1e-3, "{}", i.enter.t);
+        }
+    }
+
+    #[test]
+    fn csg_union() {
+        // spheres from 4 to 6 and 5 to 7
+        let shape = csg(CsgOperation::Union, sphere(0.0), sphere(1.0));
+        assert_spans(spans(&shape, &x_ray()), &[(4.0, 7.0)]);
+        // separate shapes stay separate
+        let shape = csg(CsgOperation::Union, sphere(0.0), slab(2.0, 3.0));
+        assert_spans(spans(&shape, &x_ray()), &[(4.0, 6.0), (7.0, 8.0)]);
+        assert!((shape.intersect(&x_ray()).unwrap().t - 4.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn csg_intersection() {
+        let shape = csg(CsgOperation::Intersection, sphere(0.0), sphere(1.0));
+        let r = x_ray();
+        assert_spans(spans(&shape, &r), &[(5.0, 6.0)]);
+        // each end keeps the normal of the shape it came from
+        let i = &shape.intervals(&r)[0];
+        assert!((i.enter.normal.x + 1.0).abs() < 1e-9);
+        assert!((i.exit.normal.x - 1.0).abs() < 1e-9);
+        let shape = csg(CsgOperation::Intersection, sphere(0.0), slab(2.0, 3.0));
+        assert!(shape.intervals(&r).is_empty());
+        assert!(shape.intersect(&r).is_none());
+    }
+
+    #[test]
+    fn csg_difference() {
+        let r = x_ray();
+        let shape = csg(CsgOperation::Difference, sphere(0.0), sphere(1.0));
+        assert_spans(spans(&shape, &r), &[(4.0, 5.0)]);
+        // the subtracted surface faces out of the result
+        assert!((shape.intervals(&r)[0].exit.normal.x - 1.0).abs() < 1e-9);
+        // cutting through the middle leaves two pieces
+        let shape = csg(CsgOperation::Difference, sphere(0.0), slab(-0.5, 0.5));
+        assert_spans(spans(&shape, &r), &[(4.0, 4.5), (5.5, 6.0)]);
+        // a ray that starts in the hole hits the far piece
+        let inside = ray(Pnt3::new(0.0, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.0));
+        assert!((shape.intersect(&inside).unwrap().t - 0.5).abs() < 1e-9);
+        // subtracting a shape that contains the first one leaves nothing
+        let shape = csg(CsgOperation::Difference, sphere(0.0), slab(-2.0, 2.0));
+        assert!(shape.intervals(&r).is_empty());
+    }
+
+    #[test]
+    fn csg_nested() {
+        let r = x_ray();
+        // (spheres from 4 to 7) minus a slab from 4.5 to 5.5
+        let union = csg(CsgOperation::Union, sphere(0.0), sphere(1.0));
+        let shape = csg(CsgOperation::Difference, Box::new(union), slab(-0.5, 0.5));
+        assert_spans(spans(&shape, &r), &[(4.0, 4.5), (5.5, 7.0)]);
+        // ... intersected with a slab from 5 to 8
+        let shape = csg(CsgOperation::Intersection, Box::new(shape), slab(0.0, 3.0));
+        assert_spans(spans(&shape, &r), &[(5.5, 7.0)]);
+        assert!((shape.intersect(&r).unwrap().t - 5.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn csg_touching() {
+        let r = x_ray();
+        // slabs from 4 to 5 and 5 to 6
+        // without a surface where they touch
+        let shape = csg(CsgOperation::Union, slab(-1.0, 0.0), slab(0.0, 1.0));
+        assert_spans(spans(&shape, &r), &[(4.0, 6.0)]);
+        let shape = csg(CsgOperation::Intersection, slab(-1.0, 0.0), slab(0.0, 1.0));
+        assert!(shape.intervals(&r).is_empty());
+        let shape = csg(CsgOperation::Difference, slab(-1.0, 0.0), slab(0.0, 1.0));
+        assert_spans(spans(&shape, &r), &[(4.0, 5.0)]);
+        // a shape minus itself is empty
+        let shape = csg(CsgOperation::Difference, sphere(0.0), sphere(0.0));
+        assert!(shape.intervals(&r).is_empty());
     }
 }
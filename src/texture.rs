@@ -1,59 +1,461 @@
 //! Module to load and process textures.
 //!
 //! Textures are images that are used when rendering. They represent
-//! the colors in an object. Right now, it is only being used
-//! for the skybox.
+//! the colors in an object. They are used for the skybox and for
+//! material parameters.
+//!
+//! Textures in 8-bit formats are stored as bytes, and are decoded
+//! from sRGB unless they hold non-color data. Textures in the linear
+//! HDR formats (Radiance `.hdr` and `.pfm`) are stored as floats.
 
 extern crate image;
 
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::Read;
 use std::path::Path;
-use color::Color;
+use std::rc::Rc;
+use color::{Color, BLACK};
+
+/// An error that occurs when loading a texture.
+#[derive(Debug)]
+pub enum LoadError {
+    /// The image library could not load the image.
+    Image(image::ImageError),
+    /// The file could not be read.
+    Io(io::Error),
+    /// The file is not a valid HDR image.
+    Format(String),
+}
+
+impl From<image::ImageError> for LoadError {
+    fn from(err: image::ImageError) -> LoadError {
+        LoadError::Image(err)
+    }
+}
 
-pub type LoadError = image::ImageError;
+impl From<io::Error> for LoadError {
+    fn from(err: io::Error) -> LoadError {
+        LoadError::Io(err)
+    }
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LoadError::Image(ref err) => write!(fmt, "error #{}", err),
+            LoadError::Io(ref err) => write!(fmt, "{}", err),
+            LoadError::Format(ref msg) => write!(fmt, "{}", msg),
+        }
+    }
+}
 
 pub fn error_description(err: LoadError) -> String {
-    format!("error #{}", err)
+    format!("{}", err)
+}
+
+/// What happens when a texture is sampled outside of [0, 1].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Wrap {
+    /// Use the color of the closest edge pixel.
+    Clamp,
+    /// Tile the texture.
+    Repeat,
+    /// Tile the texture, flipping every other tile.
+    Mirror,
+    /// Use the border color of the texture.
+    Border,
+}
+
+/// Options for loading a texture.
+pub struct TextureOptions {
+    /// How coordinates outside of the texture are handled.
+    pub wrap: Wrap,
+    /// The color outside of the texture when wrapping with `Border`.
+    pub border: Color,
+    /// The alpha outside of the texture when wrapping with `Border`.
+    pub border_alpha: f64,
+    /// Whether the texture holds colors. 8-bit textures that hold
+    /// colors are decoded from sRGB; other data, such as normal maps
+    /// or roughness, is used as is.
+    pub color: bool,
+}
+
+impl Default for TextureOptions {
+    fn default() -> TextureOptions {
+        TextureOptions { wrap: Wrap::Clamp, border: BLACK, border_alpha: 0.0, color: true }
+    }
 }
 
 /// A texture stored in memory. It can be loaded from a file, and
-/// pixels can be sampled.
+/// pixels can be sampled. A pyramid of downsampled copies (mipmaps)
+/// is built when the texture is loaded, which is used to filter the
+/// texture when it is minified. Clones share the pixels of the
+/// original.
+#[derive(Clone)]
 pub struct Texture {
+    levels: Rc<Vec<MipLevel>>,
+    wrap: Wrap,
+    border: Color,
+    border_alpha: f64,
+}
+
+/// The pixels of a mipmap level, stored as RGBA.
+enum Storage {
+    /// Bytes, which are sRGB encoded if the flag is set.
+    Bytes(Box<[u8]>, bool),
+    /// Linear floats.
+    Floats(Box<[f32]>),
+}
+
+/// One level of the mipmap pyramid.
+struct MipLevel {
     width: u32,
     height: u32,
-    data: Box<[u8]>,
+    data: Storage,
 }
 
-#[inline]
-fn clamp(x: f64) -> f64 {
-    if x < 0.0 {0.0} else if x > 1.0 {1.0} else {x}
+/// Wrap a pixel coordinate into [0, size). Returns `None` if the
+/// coordinate is outside and the border should be used instead.
+fn wrap_coord(wrap: Wrap, i: i64, size: u32) -> Option<u32> {
+    let n = size as i64;
+    match wrap {
+        Wrap::Clamp => Some(if i < 0 { 0 } else if i >= n { size - 1 } else { i as u32 }),
+        Wrap::Repeat => Some((((i % n) + n) % n) as u32),
+        Wrap::Mirror => {
+            let m = ((i % (2 * n)) + 2 * n) % (2 * n);
+            Some((if m >= n { 2 * n - 1 - m } else { m }) as u32)
+        },
+        Wrap::Border => if i < 0 || i >= n { None } else { Some(i as u32) },
+    }
+}
+
+impl MipLevel {
+    fn at(&self, x: u32, y: u32) -> (Color, f64) {
+        let idx = 4 * (x + y * self.width) as usize;
+        match self.data {
+            Storage::Bytes(ref data, true) =>
+                (Color::from_srgb(data[idx + 0], data[idx + 1], data[idx + 2]), data[idx + 3] as f64 / 255.0),
+            Storage::Bytes(ref data, false) =>
+                (Color::from_rgb(data[idx + 0] as f64 / 255.0, data[idx + 1] as f64 / 255.0, data[idx + 2] as f64 / 255.0),
+                 data[idx + 3] as f64 / 255.0),
+            Storage::Floats(ref data) =>
+                (Color::from_rgb(data[idx + 0] as f64, data[idx + 1] as f64, data[idx + 2] as f64), data[idx + 3] as f64),
+        }
+    }
+
+    /// Halve the size of the level by averaging 2x2 blocks of pixels
+    /// in linear space.
+    fn downsample(&self) -> MipLevel {
+        let width = (self.width / 2).max(1);
+        let height = (self.height / 2).max(1);
+        let mut pixels = Vec::with_capacity((width * height) as usize);
+        for y in 0..height {
+            for x in 0..width {
+                let x0 = (2 * x).min(self.width - 1);
+                let x1 = (2 * x + 1).min(self.width - 1);
+                let y0 = (2 * y).min(self.height - 1);
+                let y1 = (2 * y + 1).min(self.height - 1);
+                let (c00, a00) = self.at(x0, y0);
+                let (c10, a10) = self.at(x1, y0);
+                let (c01, a01) = self.at(x0, y1);
+                let (c11, a11) = self.at(x1, y1);
+                pixels.push(((c00 + c10 + c01 + c11) * 0.25, (a00 + a10 + a01 + a11) * 0.25));
+            }
+        }
+        let data = match self.data {
+            Storage::Bytes(_, srgb) => {
+                let mut data = Vec::with_capacity(4 * pixels.len());
+                for &(c, a) in pixels.iter() {
+                    data.extend((if srgb { c.srgb() } else { c.rgb() }).iter().cloned());
+                    data.push((a * 255.0 + 0.5) as u8);
+                }
+                Storage::Bytes(data.into_boxed_slice(), srgb)
+            },
+            Storage::Floats(_) => {
+                let mut data = Vec::with_capacity(4 * pixels.len());
+                for &(c, a) in pixels.iter() {
+                    data.extend([c.r as f32, c.g as f32, c.b as f32, a as f32].iter().cloned());
+                }
+                Storage::Floats(data.into_boxed_slice())
+            },
+        };
+        MipLevel { width: width, height: height, data: data }
+    }
 }
 
 impl Texture {
     /// Load a texture from a file.
-    /// Assumes that the texture is in the sRGB colorspace.
+    /// Assumes that the texture is in the sRGB colorspace, unless it
+    /// is an HDR image.
     pub fn load<P: AsRef<Path>>(path: P) -> Result<Texture, LoadError> {
-        let im = try!(image::open(path)).to_rgb();
-        Ok(Texture { width: im.width(), height: im.height(), data: im.into_raw().into_boxed_slice() })
+        Texture::load_with(path, &TextureOptions::default())
     }
-    /// Get the color at a position. The parameters are in pixels.
-    pub fn at(&self, x: u32, y: u32) -> Color {
-        let idx = 3 * (x + y * self.width) as usize;
-        Color::from_srgb(self.data[idx + 0], self.data[idx + 1], self.data[idx + 2])
+
+    /// Load a texture from a file with the given options. Files
+    /// ending in `.hdr` or `.pfm` are loaded as floats, other files
+    /// are loaded with the image library.
+    pub fn load_with<P: AsRef<Path>>(path: P, options: &TextureOptions) -> Result<Texture, LoadError> {
+        let path = path.as_ref();
+        let ext = path.extension().and_then(|e| e.to_str()).map(|e| e.to_lowercase());
+        let base = match ext.as_ref().map(|e| e.as_ref()) {
+            Some("hdr") => try!(load_radiance(try!(read_file(path)))),
+            Some("pfm") => try!(load_pfm(try!(read_file(path)))),
+            _ => {
+                let im = try!(image::open(path)).to_rgba();
+                MipLevel { width: im.width(), height: im.height(), data: Storage::Bytes(im.into_raw().into_boxed_slice(), options.color) }
+            },
+        };
+        let mut levels = vec![base];
+        while { let last = &levels[levels.len() - 1]; last.width > 1 || last.height > 1 } {
+            let next = levels[levels.len() - 1].downsample();
+            levels.push(next);
+        }
+        Ok(Texture { levels: Rc::new(levels), wrap: options.wrap, border: options.border, border_alpha: options.border_alpha })
+    }
+
+    /// A texture with the same pixels and different options for
+    /// sampling outside of it. Whether the pixels are colors can't be
+    /// changed.
+    pub fn with_options(&self, options: &TextureOptions) -> Texture {
+        Texture { levels: self.levels.clone(), wrap: options.wrap, border: options.border, border_alpha: options.border_alpha }
+    }
+    /// The width of the texture in pixels.
+    pub fn width(&self) -> u32 { self.levels[0].width }
+    /// The height of the texture in pixels.
+    pub fn height(&self) -> u32 { self.levels[0].height }
+
+    /// Get the color and alpha of a pixel of a mipmap level. The
+    /// coordinates are wrapped according to the wrap mode.
+    fn texel(&self, level: usize, x: i64, y: i64) -> (Color, f64) {
+        let l = &self.levels[level];
+        match (wrap_coord(self.wrap, x, l.width), wrap_coord(self.wrap, y, l.height)) {
+            (Some(x), Some(y)) => l.at(x, y),
+            _ => (self.border, self.border_alpha),
+        }
+    }
+
+    /// Bilinearly sample a mipmap level.
+    fn sample_level(&self, level: usize, x: f64, y: f64) -> (Color, f64) {
+        let l = &self.levels[level];
+        let x = x * l.width as f64 - 0.5;
+        let y = y * l.height as f64 - 0.5;
+        let (xf, yf) = (x.floor(), y.floor());
+        let (xx, yy) = (x - xf, y - yf);
+        let (x0, y0) = (xf as i64, yf as i64);
+        let (c00, a00) = self.texel(level, x0, y0);
+        let (c10, a10) = self.texel(level, x0 + 1, y0);
+        let (c01, a01) = self.texel(level, x0, y0 + 1);
+        let (c11, a11) = self.texel(level, x0 + 1, y0 + 1);
+        let c = (c00 * (1.0 - xx) + c10 * xx) * (1.0 - yy) + (c01 * (1.0 - xx) + c11 * xx) * yy;
+        let a = (a00 * (1.0 - xx) + a10 * xx) * (1.0 - yy) + (a01 * (1.0 - xx) + a11 * xx) * yy;
+        (c, a)
+    }
+
+    /// Get the color at a position. The parameters are in pixels,
+    /// and are wrapped according to the wrap mode.
+    pub fn at(&self, x: i64, y: i64) -> Color {
+        self.texel(0, x, y).0
+    }
+    /// Get the alpha at a position. The parameters are in pixels,
+    /// and are wrapped according to the wrap mode.
+    pub fn alpha_at(&self, x: i64, y: i64) -> f64 {
+        self.texel(0, x, y).1
     }
     /// Sample the color at a position. The parameters are in the
-    /// range [0, 1], and they will be clamped. The result will be
-    /// blended.
+    /// range [0, 1]; positions outside are wrapped according to the
+    /// wrap mode. The result will be blended.
     pub fn sample(&self, x: f64, y: f64) -> Color {
-        let x = clamp(x) * (self.width - 1) as f64;
-        let y = clamp(y) * (self.height - 1) as f64;
-        let x0 = x as u32;
-        let x1 = if x0 >= self.width - 1 { self.width - 1 } else { x0 + 1 };
-        let xx = x - (x0 as f64);
-        let y0 = y as u32;
-        let y1 = if y0 >= self.height - 1 { self.height - 1 } else { y0 + 1 };
-        let yy = y - (y0 as f64);
-        let cx0 = self.at(x0, y0) * (1.0 - yy) + self.at(x0, y1) * yy;
-        let cx1 = self.at(x1, y0) * (1.0 - yy) + self.at(x1, y1) * yy;
-        cx0 * (1.0 - xx) + cx1 * xx
+        self.sample_level(0, x, y).0
+    }
+    /// Sample the color at a position, filtered over a footprint of
+    /// the given width (in the same units as the position). The two
+    /// mipmap levels closest to the footprint are sampled and blended
+    /// (trilinear filtering).
+    pub fn sample_filtered(&self, x: f64, y: f64, width: f64) -> Color {
+        self.sample_rgba_filtered(x, y, width).0
+    }
+    /// Like `sample_filtered`, but also returns the alpha.
+    pub fn sample_rgba_filtered(&self, x: f64, y: f64, width: f64) -> (Color, f64) {
+        let texels = width * self.width().max(self.height()) as f64;
+        if !(texels > 1.0) {
+            return self.sample_level(0, x, y);
+        }
+        let max_level = (self.levels.len() - 1) as f64;
+        let level = texels.log2().min(max_level);
+        let l0 = level.floor();
+        let f = level - l0;
+        let (c0, a0) = self.sample_level(l0 as usize, x, y);
+        if f <= 0.0 {
+            return (c0, a0);
+        }
+        let (c1, a1) = self.sample_level(l0 as usize + 1, x, y);
+        (c0 * (1.0 - f) + c1 * f, a0 * (1.0 - f) + a1 * f)
+    }
+}
+
+fn read_file(path: &Path) -> Result<Vec<u8>, LoadError> {
+    let mut buf = Vec::new();
+    try!(try!(File::open(path)).read_to_end(&mut buf));
+    Ok(buf)
+}
+
+fn format_error<T>(msg: &str) -> Result<T, LoadError> {
+    Err(LoadError::Format(msg.to_string()))
+}
+
+/// Read a line of an image header, without the newline.
+fn read_line<'a>(data: &'a [u8], pos: &mut usize) -> Result<&'a [u8], LoadError> {
+    let start = *pos;
+    match data[start..].iter().position(|&c| c == b'\n') {
+        Some(len) => {
+            *pos = start + len + 1;
+            Ok(&data[start..start + len])
+        },
+        None => format_error("unexpected end of header"),
+    }
+}
+
+/// Load a Radiance RGBE image (`.hdr`). Only the standard `-Y h +X w`
+/// orientation is supported.
+fn load_radiance(data: Vec<u8>) -> Result<MipLevel, LoadError> {
+    let mut pos = 0;
+    if !try!(read_line(&data, &mut pos)).starts_with(b"#?") {
+        return format_error("not a Radiance HDR file");
+    }
+    loop {
+        let line = try!(read_line(&data, &mut pos));
+        if line.is_empty() {
+            break;
+        }
+        if line.starts_with(b"FORMAT=") && line != b"FORMAT=32-bit_rle_rgbe" {
+            return format_error("unsupported Radiance HDR format");
+        }
+    }
+    let res = String::from_utf8_lossy(try!(read_line(&data, &mut pos))).into_owned();
+    let parts: Vec<&str> = res.split_whitespace().collect();
+    let (height, width) = match (parts.len(), parts.get(0), parts.get(2)) {
+        (4, Some(&"-Y"), Some(&"+X")) => match (parts[1].parse::<u32>(), parts[3].parse::<u32>()) {
+            (Ok(h), Ok(w)) if w > 0 && h > 0 => (h, w),
+            _ => return format_error("invalid Radiance HDR resolution"),
+        },
+        _ => return format_error("unsupported Radiance HDR orientation"),
+    };
+    let w = width as usize;
+    let mut out = Vec::with_capacity(4 * w * height as usize);
+    let mut scanline = vec![0u8; 4 * w];
+    for _ in 0..height {
+        if data.len() < pos + 4 {
+            return format_error("unexpected end of Radiance HDR data");
+        }
+        if w >= 8 && w < 32768 && data[pos] == 2 && data[pos + 1] == 2 && data[pos + 2] & 0x80 == 0 {
+            // run length encoded, one channel at a time
+            pos += 4;
+            for ch in 0..4 {
+                let mut x = 0;
+                while x < w {
+                    if pos >= data.len() {
+                        return format_error("unexpected end of Radiance HDR data");
+                    }
+                    let count = data[pos] as usize;
+                    pos += 1;
+                    if count > 128 {
+                        let count = count - 128;
+                        if x + count > w || pos >= data.len() {
+                            return format_error("invalid Radiance HDR run");
+                        }
+                        for i in 0..count {
+                            scanline[4 * (x + i) + ch] = data[pos];
+                        }
+                        pos += 1;
+                        x += count;
+                    } else {
+                        if count == 0 || x + count > w || pos + count > data.len() {
+                            return format_error("invalid Radiance HDR run");
+                        }
+                        for i in 0..count {
+                            scanline[4 * (x + i) + ch] = data[pos + i];
+                        }
+                        pos += count;
+                        x += count;
+                    }
+                }
+            }
+        } else {
+            if data.len() < pos + 4 * w {
+                return format_error("unexpected end of Radiance HDR data");
+            }
+            scanline.copy_from_slice(&data[pos..pos + 4 * w]);
+            pos += 4 * w;
+        }
+        for x in 0..w {
+            let e = scanline[4 * x + 3];
+            let f = if e == 0 { 0.0 } else { 2f32.powi(e as i32 - 136) };
+            out.push(scanline[4 * x] as f32 * f);
+            out.push(scanline[4 * x + 1] as f32 * f);
+            out.push(scanline[4 * x + 2] as f32 * f);
+            out.push(1.0);
+        }
+    }
+    Ok(MipLevel { width: width, height: height, data: Storage::Floats(out.into_boxed_slice()) })
+}
+
+/// Load a portable float map (`.pfm`), in color (`PF`) or grayscale
+/// (`Pf`). The rows are stored from bottom to top.
+fn load_pfm(data: Vec<u8>) -> Result<MipLevel, LoadError> {
+    // the header is three whitespace separated tokens after the magic
+    let mut pos = 0;
+    let mut tokens = Vec::new();
+    while tokens.len() < 4 {
+        while pos < data.len() && (data[pos] as char).is_whitespace() {
+            pos += 1;
+        }
+        let start = pos;
+        while pos < data.len() && !(data[pos] as char).is_whitespace() {
+            pos += 1;
+        }
+        if start == pos {
+            return format_error("unexpected end of PFM header");
+        }
+        tokens.push(String::from_utf8_lossy(&data[start..pos]).into_owned());
+    }
+    // a single whitespace character separates the header from the data
+    pos += 1;
+    let channels = match tokens[0].as_ref() {
+        "PF" => 3,
+        "Pf" => 1,
+        _ => return format_error("not a PFM file"),
+    };
+    let (width, height, scale) = match (tokens[1].parse::<u32>(), tokens[2].parse::<u32>(), tokens[3].parse::<f32>()) {
+        (Ok(w), Ok(h), Ok(s)) if w > 0 && h > 0 && s != 0.0 => (w, h, s),
+        _ => return format_error("invalid PFM header"),
+    };
+    let (w, h) = (width as usize, height as usize);
+    if data.len() < pos + 4 * channels * w * h {
+        return format_error("unexpected end of PFM data");
+    }
+    let read = |i: usize| {
+        let b = &data[pos + 4 * i..pos + 4 * i + 4];
+        let bits = if scale < 0.0 {
+            (b[0] as u32) | (b[1] as u32) << 8 | (b[2] as u32) << 16 | (b[3] as u32) << 24
+        } else {
+            (b[3] as u32) | (b[2] as u32) << 8 | (b[1] as u32) << 16 | (b[0] as u32) << 24
+        };
+        f32::from_bits(bits)
+    };
+    let mut out = Vec::with_capacity(4 * w * h);
+    for y in 0..h {
+        let row = h - 1 - y;
+        for x in 0..w {
+            let i = channels * (row * w + x);
+            if channels == 3 {
+                out.extend([read(i), read(i + 1), read(i + 2), 1.0].iter().cloned());
+            } else {
+                let v = read(i);
+                out.extend([v, v, v, 1.0].iter().cloned());
+            }
+        }
     }
+    Ok(MipLevel { width: width, height: height, data: Storage::Floats(out.into_boxed_slice()) })
 }
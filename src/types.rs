@@ -23,7 +23,8 @@ pub type Vec4 = self::na::Vec4<f64>;
 pub type Mat3 = self::na::Mat3<f64>;
 /// 4x4 matrix
 pub type Mat4 = self::na::Mat4<f64>;
-/// Type of RNG used
-pub type RngT = rand::XorShiftRng;
+/// Type of RNG used. It is a sampler, which draws well distributed
+/// samples for each pixel.
+pub type RngT = ::sampler::Sampler;
 /// Rng trait so you don't have to import it yourself
 pub use self::rand::Rng;
@@ -0,0 +1,129 @@
+//! Voxel grids
+//!
+//! A voxel grid stores a density at each point of a regular 3D grid.
+//! They are used for heterogeneous volumes such as clouds and smoke.
+//!
+//! Grids can be loaded from two formats:
+//!
+//! * raw files, which contain only little-endian 32-bit floats with
+//!   x varying fastest, then y, then z; the resolution must be given
+//!   separately;
+//! * dense `.vol` files, which start with the bytes `VOL` and the
+//!   version 3, followed by little-endian 32-bit integers for the
+//!   encoding (1 for floats), the x, y and z resolution and the
+//!   number of channels, six floats for a bounding box (which is
+//!   ignored), and the data in the same order as raw files. Only the
+//!   first channel is used.
+
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::path::Path;
+
+use types::*;
+
+/// A grid of densities.
+pub struct VoxelGrid {
+    nx: usize,
+    ny: usize,
+    nz: usize,
+    data: Vec<f32>,
+    max: f64,
+}
+
+fn invalid<T>(msg: &str) -> io::Result<T> {
+    Err(io::Error::new(io::ErrorKind::InvalidData, msg))
+}
+
+#[inline]
+fn read_u32(data: &[u8], i: usize) -> u32 {
+    (data[i] as u32) | (data[i + 1] as u32) << 8 | (data[i + 2] as u32) << 16 | (data[i + 3] as u32) << 24
+}
+
+#[inline]
+fn read_f32(data: &[u8], i: usize) -> f32 {
+    f32::from_bits(read_u32(data, i))
+}
+
+impl VoxelGrid {
+    /// Create a grid from densities, with x varying fastest.
+    pub fn new(nx: usize, ny: usize, nz: usize, data: Vec<f32>) -> VoxelGrid {
+        assert!(nx > 0 && ny > 0 && nz > 0 && data.len() == nx * ny * nz);
+        let max = data.iter().fold(0.0f32, |m, &d| if d > m { d } else { m }) as f64;
+        VoxelGrid { nx: nx, ny: ny, nz: nz, data: data, max: max }
+    }
+
+    /// Load a raw grid with the given resolution.
+    pub fn load_raw<P: AsRef<Path>>(path: P, nx: usize, ny: usize, nz: usize) -> io::Result<VoxelGrid> {
+        let mut bytes = Vec::new();
+        try!(try!(File::open(path)).read_to_end(&mut bytes));
+        let count = nx * ny * nz;
+        if count == 0 {
+            return invalid("empty grid");
+        }
+        if bytes.len() != 4 * count {
+            return invalid("file size does not match the resolution");
+        }
+        Ok(VoxelGrid::new(nx, ny, nz, (0..count).map(|i| read_f32(&bytes, 4 * i)).collect()))
+    }
+
+    /// Load a dense `.vol` grid.
+    pub fn load_vol<P: AsRef<Path>>(path: P) -> io::Result<VoxelGrid> {
+        let mut bytes = Vec::new();
+        try!(try!(File::open(path)).read_to_end(&mut bytes));
+        const HEADER: usize = 48;
+        if bytes.len() < HEADER || &bytes[0..3] != b"VOL" || bytes[3] != 3 {
+            return invalid("not a VOL file");
+        }
+        if read_u32(&bytes, 4) != 1 {
+            return invalid("only float VOL files are supported");
+        }
+        let (nx, ny, nz) = (read_u32(&bytes, 8) as usize, read_u32(&bytes, 12) as usize, read_u32(&bytes, 16) as usize);
+        let channels = read_u32(&bytes, 20) as usize;
+        let count = nx * ny * nz;
+        if count == 0 || channels == 0 {
+            return invalid("empty grid");
+        }
+        if bytes.len() < HEADER + 4 * count * channels {
+            return invalid("unexpected end of VOL data");
+        }
+        Ok(VoxelGrid::new(nx, ny, nz, (0..count).map(|i| read_f32(&bytes, HEADER + 4 * i * channels)).collect()))
+    }
+
+    /// The largest density in the grid.
+    pub fn max(&self) -> f64 {
+        self.max
+    }
+
+    #[inline]
+    fn at(&self, x: usize, y: usize, z: usize) -> f64 {
+        self.data[x + self.nx * (y + self.ny * z)] as f64
+    }
+
+    /// Get the density at a position in the grid, where the grid
+    /// spans [0, 1] on each axis. Densities are interpolated between
+    /// the centers of voxels, and are 0 outside of the grid.
+    pub fn density(&self, p: &Pnt3) -> f64 {
+        if p.x < 0.0 || p.x > 1.0 || p.y < 0.0 || p.y > 1.0 || p.z < 0.0 || p.z > 1.0 {
+            return 0.0;
+        }
+        // split a coordinate into the lower voxel index, the upper
+        // voxel index and the fraction between them
+        let split = |v: f64, n: usize| {
+            let v = v * n as f64 - 0.5;
+            let f = v.floor();
+            let i0 = if f < 0.0 { 0 } else if f as usize >= n { n - 1 } else { f as usize };
+            let i1 = if i0 + 1 >= n { n - 1 } else { i0 + 1 };
+            let frac = if v < 0.0 { 0.0 } else { v - f };
+            (i0, i1, frac)
+        };
+        let (x0, x1, fx) = split(p.x, self.nx);
+        let (y0, y1, fy) = split(p.y, self.ny);
+        let (z0, z1, fz) = split(p.z, self.nz);
+        let lerp = |a: f64, b: f64, f: f64| a + (b - a) * f;
+        lerp(lerp(lerp(self.at(x0, y0, z0), self.at(x1, y0, z0), fx),
+                  lerp(self.at(x0, y1, z0), self.at(x1, y1, z0), fx), fy),
+             lerp(lerp(self.at(x0, y0, z1), self.at(x1, y0, z1), fx),
+                  lerp(self.at(x0, y1, z1), self.at(x1, y1, z1), fx), fy), fz)
+    }
+}